@@ -112,16 +112,19 @@ export class KiachaCoreBrain {
 
       this.logger.info(`✓ Brain connected to kernel: ${this.brainModuleId}`);
 
-      // Subscribe to kernel events
-      await this.eventBus.subscribeToKernelEvents('module_spawned');
-      await this.eventBus.subscribeToKernelEvents('module_failed');
+      // Subscribe to kernel module lifecycle events (module.spawned, module.exited, ...)
+      await this.eventBus.subscribeToKernelEvents('module.*');
 
       // Listen to events
-      this.eventBus.on('module_spawned', (data: any) => {
+      this.eventBus.on('module.spawned', (data: any) => {
         this.logger.info(`Module spawned event: ${JSON.stringify(data)}`);
       });
 
-      this.eventBus.on('module_failed', (data: any) => {
+      this.eventBus.on('module.exited', (data: any) => {
+        this.logger.warn(`Module exited event: ${JSON.stringify(data)}`);
+      });
+
+      this.eventBus.on('module.gave_up', (data: any) => {
         this.logger.error(`Module failed event: ${JSON.stringify(data)}`);
       });
     } catch (error) {
@@ -13,6 +13,40 @@ const __dirname = path.dirname(__filename);
 
 const PROTO_PATH = path.join(__dirname, "../../../shared/proto/kiacha.proto");
 
+export type AuditSeverity = "AUDIT_SEVERITY_INFO" | "AUDIT_SEVERITY_WARNING" | "AUDIT_SEVERITY_CRITICAL";
+
+/**
+ * One record of the kernel's hash-chained audit log. 64-bit numbers arrive
+ * as strings.
+ */
+export interface AuditLog {
+  event_type: string;
+  actor: string;
+  action: string;
+  target: string;
+  success: boolean;
+  details: string;
+  timestamp: string;
+  sequence: string;
+  prev_hash: string;
+  hash: string;
+  severity: AuditSeverity;
+}
+
+export interface AuditTailOptions {
+  /** Keep streaming new records after the history */
+  follow?: boolean;
+  /** e.g. "ipc_" or "login" */
+  actionPrefix?: string;
+  /** Records with the module as actor or target */
+  moduleId?: string;
+  minSeverity?: AuditSeverity;
+  /** Unix millis; 0 for all history */
+  since?: number;
+  /** Most recent history records only; 0 for all */
+  backlog?: number;
+}
+
 export class KiachaKernelClient {
   private client: any;
   private address: string;
@@ -182,10 +216,18 @@ export class KiachaKernelClient {
   }
 
   /**
-   * Get audit logs from kernel
+   * Stream audit logs from the kernel: matching history, then with `follow`
+   * new records as they are written
    */
-  getAuditLogs(): grpc.ClientReadableStream<any> {
-    return this.client.getAuditLogs({});
+  getAuditLogs(options: AuditTailOptions = {}): grpc.ClientReadableStream<AuditLog> {
+    return this.client.getAuditLogs({
+      follow: options.follow ?? false,
+      action_prefix: options.actionPrefix ?? "",
+      module_id: options.moduleId ?? "",
+      min_severity: options.minSeverity ?? "AUDIT_SEVERITY_INFO",
+      since: options.since ?? 0,
+      backlog: options.backlog ?? 0,
+    });
   }
 
   /**
@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use parking_lot::Mutex;
+
+/// Compares biometric samples of one kind.
+///
+/// Samples are the feature data a sensor or client-side model extracted, never
+/// raw images; the kernel only stores and compares them.
+pub trait Matcher: Send + Sync {
+    /// Reject data that is not a well-formed sample, before it is enrolled
+    fn validate(&self, sample: &[u8]) -> anyhow::Result<()>;
+
+    /// Similarity of a probe to an enrolled template, from 0.0 (unrelated) to
+    /// 1.0 (identical)
+    fn score(&self, template: &[u8], probe: &[u8]) -> anyhow::Result<f32>;
+}
+
+/// Face embeddings: little-endian `f32` vectors compared by cosine similarity
+pub struct EmbeddingMatcher;
+
+impl Matcher for EmbeddingMatcher {
+    fn validate(&self, sample: &[u8]) -> anyhow::Result<()> {
+        let vector = floats(sample)?;
+        if vector.is_empty() || norm(&vector) == 0.0 {
+            return Err(anyhow::anyhow!("Embedding is empty"));
+        }
+        Ok(())
+    }
+
+    fn score(&self, template: &[u8], probe: &[u8]) -> anyhow::Result<f32> {
+        let (a, b) = (floats(template)?, floats(probe)?);
+        if a.len() != b.len() {
+            return Err(anyhow::anyhow!("Embedding has {} dimensions, expected {}", b.len(), a.len()));
+        }
+        let (na, nb) = (norm(&a), norm(&b));
+        if na == 0.0 || nb == 0.0 {
+            return Ok(0.0);
+        }
+        let dot: f32 = a.iter().zip(&b).map(|(x, y)| x * y).sum();
+        Ok((dot / (na * nb)).clamp(0.0, 1.0))
+    }
+}
+
+/// Fingerprint minutiae: little-endian `f32` triples of x, y and ridge angle in
+/// radians, from an aligned capture. The score is the share of minutiae that
+/// pair up within the tolerances.
+pub struct MinutiaeMatcher {
+    /// Furthest apart, in sensor units, two minutiae may be and still pair
+    pub max_distance: f32,
+    /// Largest angle difference, in radians, for two minutiae to pair
+    pub max_angle: f32,
+}
+
+impl Default for MinutiaeMatcher {
+    fn default() -> Self {
+        MinutiaeMatcher {
+            max_distance: 12.0,
+            max_angle: std::f32::consts::PI / 12.0,
+        }
+    }
+}
+
+impl MinutiaeMatcher {
+    fn minutiae(sample: &[u8]) -> anyhow::Result<Vec<[f32; 3]>> {
+        let values = floats(sample)?;
+        if values.len() % 3 != 0 {
+            return Err(anyhow::anyhow!("Minutiae must be (x, y, angle) triples"));
+        }
+        Ok(values.chunks(3).map(|m| [m[0], m[1], m[2]]).collect())
+    }
+}
+
+impl Matcher for MinutiaeMatcher {
+    fn validate(&self, sample: &[u8]) -> anyhow::Result<()> {
+        if Self::minutiae(sample)?.is_empty() {
+            return Err(anyhow::anyhow!("No minutiae"));
+        }
+        Ok(())
+    }
+
+    fn score(&self, template: &[u8], probe: &[u8]) -> anyhow::Result<f32> {
+        let (template, probe) = (Self::minutiae(template)?, Self::minutiae(probe)?);
+        if template.is_empty() || probe.is_empty() {
+            return Ok(0.0);
+        }
+
+        let mut used = vec![false; template.len()];
+        let mut paired = 0;
+        for p in &probe {
+            let nearest = template
+                .iter()
+                .enumerate()
+                .filter(|(i, t)| {
+                    !used[*i]
+                        && angle_between(t[2], p[2]) <= self.max_angle
+                        && (t[0] - p[0]).hypot(t[1] - p[1]) <= self.max_distance
+                })
+                .min_by(|(_, a), (_, b)| {
+                    let da = (a[0] - p[0]).hypot(a[1] - p[1]);
+                    let db = (b[0] - p[0]).hypot(b[1] - p[1]);
+                    da.total_cmp(&db)
+                });
+            if let Some((i, _)) = nearest {
+                used[i] = true;
+                paired += 1;
+            }
+        }
+        Ok(2.0 * paired as f32 / (template.len() + probe.len()) as f32)
+    }
+}
+
+/// Iris codes: packed bits compared by fractional Hamming distance
+pub struct IrisCodeMatcher;
+
+impl Matcher for IrisCodeMatcher {
+    fn validate(&self, sample: &[u8]) -> anyhow::Result<()> {
+        if sample.is_empty() {
+            return Err(anyhow::anyhow!("Iris code is empty"));
+        }
+        Ok(())
+    }
+
+    fn score(&self, template: &[u8], probe: &[u8]) -> anyhow::Result<f32> {
+        if template.len() != probe.len() {
+            return Err(anyhow::anyhow!("Iris code has {} bytes, expected {}", probe.len(), template.len()));
+        }
+        let differing: u32 = template.iter().zip(probe).map(|(a, b)| (a ^ b).count_ones()).sum();
+        Ok(1.0 - differing as f32 / (template.len() * 8) as f32)
+    }
+}
+
+struct Registered {
+    matcher: Box<dyn Matcher>,
+    /// Lowest score accepted as a match
+    threshold: f32,
+}
+
+/// Enrolled biometric templates and the matchers that check samples against
+/// them.
+///
+/// Templates are encrypted with AES-256-GCM, bound to their user and kind so
+/// they cannot be moved to another account, and only decrypted to compare.
+pub struct Biometrics {
+    matchers: HashMap<String, Registered>,
+    cipher: Aes256Gcm,
+    /// User id -> kind -> hex of nonce and ciphertext
+    templates: Mutex<BTreeMap<String, BTreeMap<String, String>>>,
+    path: PathBuf,
+}
+
+impl Biometrics {
+    /// Load the templates at `path` with the built-in `face`, `fingerprint` and
+    /// `iris` matchers. `thresholds` overrides their default thresholds.
+    pub fn load(path: &Path, key: &[u8], thresholds: &HashMap<String, f32>) -> anyhow::Result<Self> {
+        let templates = if path.exists() {
+            serde_json::from_slice(&std::fs::read(path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut biometrics = Biometrics {
+            matchers: HashMap::new(),
+            cipher: Aes256Gcm::new_from_slice(key)
+                .map_err(|_| anyhow::anyhow!("Biometric key must be 32 bytes"))?,
+            templates: Mutex::new(templates),
+            path: path.to_path_buf(),
+        };
+        biometrics.register("face", Box::new(EmbeddingMatcher), 0.9);
+        biometrics.register("fingerprint", Box::new(MinutiaeMatcher::default()), 0.6);
+        biometrics.register("iris", Box::new(IrisCodeMatcher), 0.68);
+
+        for (kind, threshold) in thresholds {
+            let registered = biometrics
+                .matchers
+                .get_mut(kind)
+                .ok_or_else(|| anyhow::anyhow!("No matcher for biometric type {}", kind))?;
+            registered.threshold = *threshold;
+        }
+        Ok(biometrics)
+    }
+
+    /// Add or replace the matcher for a kind of sample
+    pub fn register(&mut self, kind: &str, matcher: Box<dyn Matcher>, threshold: f32) {
+        self.matchers
+            .insert(kind.to_string(), Registered { matcher, threshold });
+    }
+
+    pub fn supports(&self, kind: &str) -> bool {
+        self.matchers.contains_key(kind)
+    }
+
+    /// Kinds of template enrolled for a user
+    pub fn enrolled(&self, user_id: &str) -> Vec<String> {
+        self.templates
+            .lock()
+            .get(user_id)
+            .map(|kinds| kinds.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Store a user's template for a kind, replacing any earlier one
+    pub fn enroll(&self, user_id: &str, kind: &str, sample: &[u8]) -> anyhow::Result<()> {
+        self.matcher(kind)?.matcher.validate(sample)?;
+
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: sample,
+                    aad: &associated_data(user_id, kind),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt template"))?;
+
+        let mut templates = self.templates.lock();
+        templates
+            .entry(user_id.to_string())
+            .or_default()
+            .insert(kind.to_string(), hex::encode([nonce.as_slice(), &ciphertext].concat()));
+        self.persist(&templates)
+    }
+
+    /// Drop every template of a user
+    pub fn remove_user(&self, user_id: &str) -> anyhow::Result<()> {
+        let mut templates = self.templates.lock();
+        if templates.remove(user_id).is_some() {
+            self.persist(&templates)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `probe` matches the user's enrolled template of its kind. A
+    /// user with no template of that kind never matches.
+    pub fn verify(&self, user_id: &str, kind: &str, probe: &[u8]) -> anyhow::Result<bool> {
+        let registered = self.matcher(kind)?;
+        let stored = match self.templates.lock().get(user_id).and_then(|kinds| kinds.get(kind)) {
+            Some(stored) => hex::decode(stored)?,
+            None => return Ok(false),
+        };
+        if stored.len() < 12 {
+            return Err(anyhow::anyhow!("Stored template is truncated"));
+        }
+
+        let (nonce, ciphertext) = stored.split_at(12);
+        let template = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(user_id, kind),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Stored template failed to decrypt"))?;
+
+        // A malformed probe is a failed match, not a kernel error
+        let score = registered.matcher.score(&template, probe).unwrap_or(0.0);
+        Ok(score >= registered.threshold)
+    }
+
+    fn matcher(&self, kind: &str) -> anyhow::Result<&Registered> {
+        self.matchers
+            .get(kind)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported biometric type: {}", kind))
+    }
+
+    /// Write the templates atomically
+    fn persist(&self, templates: &BTreeMap<String, BTreeMap<String, String>>) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(templates)?)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        }
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn associated_data(user_id: &str, kind: &str) -> Vec<u8> {
+    format!("{}\0{}", user_id, kind).into_bytes()
+}
+
+fn floats(sample: &[u8]) -> anyhow::Result<Vec<f32>> {
+    if sample.len() % 4 != 0 {
+        return Err(anyhow::anyhow!("Sample is not a list of f32 values"));
+    }
+    Ok(sample
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+fn norm(vector: &[f32]) -> f32 {
+    vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+fn angle_between(a: f32, b: f32) -> f32 {
+    let d = (a - b).rem_euclid(std::f32::consts::TAU);
+    d.min(std::f32::consts::TAU - d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn bytes(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn embeddings_match_by_cosine_similarity() {
+        let template = bytes(&[0.2, 0.9, -0.4, 0.1]);
+        let close = bytes(&[0.21, 0.88, -0.41, 0.12]);
+        let other = bytes(&[0.9, -0.2, 0.1, 0.4]);
+
+        assert!(EmbeddingMatcher.score(&template, &close).unwrap() > 0.99);
+        assert!(EmbeddingMatcher.score(&template, &other).unwrap() < 0.5);
+        assert!(EmbeddingMatcher.score(&template, &bytes(&[1.0])).is_err());
+    }
+
+    #[test]
+    fn minutiae_pair_within_tolerance() {
+        let matcher = MinutiaeMatcher::default();
+        let template = bytes(&[10.0, 10.0, 0.1, 50.0, 80.0, 1.2, 120.0, 40.0, 3.1, 90.0, 150.0, 6.2]);
+        // Same finger, jittered, one minutia missed and angles wrapping past 2π
+        let same = bytes(&[12.0, 9.0, 0.15, 48.0, 83.0, 1.25, 91.0, 148.0, 0.02]);
+        let other = bytes(&[200.0, 10.0, 0.1, 50.0, 180.0, 2.0, 10.0, 140.0, 4.0]);
+
+        assert!(matcher.score(&template, &same).unwrap() >= 0.8);
+        assert_eq!(matcher.score(&template, &other).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn templates_are_encrypted_and_bound_to_their_user() {
+        let dir = TempDir::new("biometrics");
+        let path = dir.join("biometrics.json");
+        let key = [7u8; 32];
+        let face = bytes(&[0.2, 0.9, -0.4, 0.1]);
+
+        let biometrics = Biometrics::load(&path, &key, &HashMap::new()).unwrap();
+        biometrics.enroll("u1", "face", &face).unwrap();
+        assert!(biometrics.verify("u1", "face", &face).unwrap());
+        assert!(!biometrics.verify("u2", "face", &face).unwrap());
+        assert!(!biometrics.verify("u1", "face", &bytes(&[0.9, -0.2, 0.1, 0.4])).unwrap());
+
+        let on_disk = std::fs::read(&path).unwrap();
+        assert!(!on_disk.windows(face.len()).any(|w| w == face.as_slice()));
+
+        // Moving the template to another user breaks its authentication tag
+        let mut raw: BTreeMap<String, BTreeMap<String, String>> = serde_json::from_slice(&on_disk).unwrap();
+        let moved = raw.remove("u1").unwrap();
+        raw.insert("u2".to_string(), moved);
+        std::fs::write(&path, serde_json::to_vec(&raw).unwrap()).unwrap();
+        let reloaded = Biometrics::load(&path, &key, &HashMap::new()).unwrap();
+        assert!(reloaded.verify("u2", "face", &face).is_err());
+    }
+
+    #[test]
+    fn matchers_are_pluggable() {
+        struct Exact;
+        impl Matcher for Exact {
+            fn validate(&self, _: &[u8]) -> anyhow::Result<()> {
+                Ok(())
+            }
+            fn score(&self, template: &[u8], probe: &[u8]) -> anyhow::Result<f32> {
+                Ok(if template == probe { 1.0 } else { 0.0 })
+            }
+        }
+
+        let dir = TempDir::new("biometrics");
+        let path = dir.join("biometrics.json");
+        let mut biometrics = Biometrics::load(&path, &[1u8; 32], &HashMap::new()).unwrap();
+        assert!(biometrics.enroll("u1", "voice", b"abc").is_err());
+
+        biometrics.register("voice", Box::new(Exact), 1.0);
+        biometrics.enroll("u1", "voice", b"abc").unwrap();
+        assert!(biometrics.verify("u1", "voice", b"abc").unwrap());
+        assert!(!biometrics.verify("u1", "voice", b"abd").unwrap());
+    }
+}
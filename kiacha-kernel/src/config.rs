@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::user_manager::AuthPolicy;
+use crate::wasm_runtime::SandboxPolicy;
+
+/// Kernel settings, read from `KIACHA_*` environment variables at startup.
+#[derive(Clone, Debug)]
+pub struct KernelConfig {
+    /// Root directory for everything the kernel persists
+    pub data_dir: PathBuf,
+    /// Size at which the event journal starts a new segment
+    pub event_segment_bytes: u64,
+    /// Journal segments older than this are deleted
+    pub event_retention_age: Option<Duration>,
+    /// Oldest journal segments are deleted while the journal is larger than this
+    pub event_retention_bytes: Option<u64>,
+    /// Subscription channel capacity when no per-topic size applies
+    pub event_buffer_default: usize,
+    /// Subscription channel capacity per topic pattern
+    pub event_buffer_sizes: HashMap<String, usize>,
+    /// Signed permission policy; defaults to `<data_dir>/permissions.json`
+    pub policy_file: Option<PathBuf>,
+    /// Hex policy signing key; when unset a key file in the data dir is used
+    pub policy_key: Option<String>,
+    /// Login lockout and session idle expiry
+    pub auth: AuthPolicy,
+    /// Executables non-admin users may spawn as process modules
+    pub module_executables: Vec<PathBuf>,
+    /// Password for the `admin` user created when there are no users yet;
+    /// when unset a random one is written to the data dir
+    pub admin_password: Option<String>,
+    /// Hex key encrypting biometric templates
+    pub biometric_key: Option<String>,
+    /// File holding the biometric key, created on first start. It must live
+    /// outside the data dir so a copy of that dir does not unlock the
+    /// templates. Without this or `biometric_key` biometric login is disabled.
+    pub biometric_key_file: Option<PathBuf>,
+    /// Match threshold per biometric type, overriding the matcher defaults
+    pub biometric_thresholds: HashMap<String, f32>,
+    /// Size at which the audit log starts a new segment
+    pub audit_segment_bytes: u64,
+    /// Audit segments kept; the oldest are deleted beyond this
+    pub audit_max_segments: usize,
+    /// Hex key the audit chain is signed with
+    pub audit_key: Option<String>,
+    /// File holding the audit key, created on first start. Like the biometric
+    /// key file it must live outside the data dir: anyone who holds the key
+    /// can rewrite the chain. Without this or `audit_key` a key file in the
+    /// data dir is used, which only guards against accidental damage.
+    pub audit_key_file: Option<PathBuf>,
+    /// Memory, CPU time and host imports allowed to WASM guests
+    pub sandbox: SandboxPolicy,
+    /// Compiled WASM modules kept in memory
+    pub wasm_cache_entries: usize,
+    /// Keep compiled WASM modules on disk across restarts
+    pub wasm_cache_on_disk: bool,
+    /// Least recently used compiled modules are deleted from disk while they
+    /// take up more than this
+    pub wasm_cache_disk_bytes: Option<u64>,
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        KernelConfig {
+            data_dir: PathBuf::from("kiacha-data"),
+            event_segment_bytes: 16 * 1024 * 1024,
+            event_retention_age: Some(Duration::from_secs(7 * 24 * 3600)),
+            event_retention_bytes: Some(512 * 1024 * 1024),
+            event_buffer_default: crate::event_bus::DEFAULT_BUFFER_SIZE,
+            event_buffer_sizes: HashMap::new(),
+            policy_file: None,
+            policy_key: None,
+            auth: AuthPolicy::default(),
+            module_executables: Vec::new(),
+            admin_password: None,
+            biometric_key: None,
+            biometric_key_file: None,
+            biometric_thresholds: HashMap::new(),
+            audit_segment_bytes: 8 * 1024 * 1024,
+            audit_max_segments: 64,
+            audit_key: None,
+            audit_key_file: None,
+            sandbox: SandboxPolicy::default(),
+            wasm_cache_entries: 64,
+            wasm_cache_on_disk: true,
+            wasm_cache_disk_bytes: Some(1024 * 1024 * 1024),
+        }
+    }
+}
+
+impl KernelConfig {
+    /// Defaults overridden by `KIACHA_DATA_DIR`, `KIACHA_EVENT_SEGMENT_MB`,
+    /// `KIACHA_EVENT_RETENTION_HOURS` and `KIACHA_EVENT_RETENTION_MB`
+    /// (`0` disables a retention limit), `KIACHA_EVENT_BUFFER_DEFAULT` and
+    /// `KIACHA_EVENT_BUFFERS` (e.g. `module.*=256,security.#=1024`),
+    /// `KIACHA_POLICY_FILE` and `KIACHA_POLICY_KEY`, `KIACHA_MODULE_EXECUTABLES`
+    /// (absolute paths, comma separated), `KIACHA_LOGIN_MAX_FAILURES`,
+    /// `KIACHA_LOGIN_LOCKOUT_SECS`, `KIACHA_SESSION_IDLE_SECS`,
+    /// `KIACHA_ADMIN_PASSWORD`, `KIACHA_BIOMETRIC_KEY` or `KIACHA_BIOMETRIC_KEY_FILE`,
+    /// `KIACHA_BIOMETRIC_THRESHOLDS` (e.g. `face=0.92,fingerprint=0.5`),
+    /// `KIACHA_AUDIT_SEGMENT_MB`, `KIACHA_AUDIT_SEGMENTS`, `KIACHA_AUDIT_KEY` or
+    /// `KIACHA_AUDIT_KEY_FILE`,
+    /// `KIACHA_WASM_MAX_MEMORY_MB`, `KIACHA_WASM_MAX_CPU_MS`, `KIACHA_WASM_ALLOWED_IMPORTS` and
+    /// `KIACHA_WASM_FORBIDDEN` (e.g. `network,filesystem,env::*`),
+    /// `KIACHA_WASM_CACHE_ENTRIES`, `KIACHA_WASM_CACHE_DISK` (`0` keeps
+    /// compiled modules in memory only) and `KIACHA_WASM_CACHE_DISK_MB` (`0`
+    /// for no limit).
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = KernelConfig::default();
+
+        if let Ok(dir) = std::env::var("KIACHA_DATA_DIR") {
+            config.data_dir = PathBuf::from(dir);
+        }
+        if let Some(mb) = env_number("KIACHA_EVENT_SEGMENT_MB")? {
+            config.event_segment_bytes = mb.max(1) * 1024 * 1024;
+        }
+        if let Some(hours) = env_number("KIACHA_EVENT_RETENTION_HOURS")? {
+            config.event_retention_age = (hours > 0).then(|| Duration::from_secs(hours * 3600));
+        }
+        if let Some(mb) = env_number("KIACHA_EVENT_RETENTION_MB")? {
+            config.event_retention_bytes = (mb > 0).then(|| mb * 1024 * 1024);
+        }
+        if let Some(size) = env_number("KIACHA_EVENT_BUFFER_DEFAULT")? {
+            config.event_buffer_default = size as usize;
+        }
+        if let Ok(raw) = std::env::var("KIACHA_EVENT_BUFFERS") {
+            for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (pattern, size) = entry
+                    .split_once('=')
+                    .and_then(|(p, s)| Some((p.trim(), s.trim().parse::<usize>().ok()?)))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid KIACHA_EVENT_BUFFERS entry: {}", entry))?;
+                config.event_buffer_sizes.insert(pattern.to_string(), size);
+            }
+        }
+
+        config.policy_file = std::env::var("KIACHA_POLICY_FILE").ok().map(PathBuf::from);
+        config.policy_key = std::env::var("KIACHA_POLICY_KEY").ok();
+        if let Some(executables) = env_list("KIACHA_MODULE_EXECUTABLES") {
+            config.module_executables = executables.into_iter().map(PathBuf::from).collect();
+        }
+
+        if let Some(failures) = env_number("KIACHA_LOGIN_MAX_FAILURES")? {
+            config.auth.max_failures = failures.max(1) as u32;
+        }
+        if let Some(secs) = env_number("KIACHA_LOGIN_LOCKOUT_SECS")? {
+            config.auth.lockout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_number("KIACHA_SESSION_IDLE_SECS")? {
+            config.auth.session_idle_timeout = Duration::from_secs(secs.max(1));
+        }
+        config.admin_password = std::env::var("KIACHA_ADMIN_PASSWORD").ok();
+
+        config.biometric_key = std::env::var("KIACHA_BIOMETRIC_KEY").ok();
+        config.biometric_key_file = std::env::var("KIACHA_BIOMETRIC_KEY_FILE").ok().map(PathBuf::from);
+        if let Ok(raw) = std::env::var("KIACHA_BIOMETRIC_THRESHOLDS") {
+            for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+                let (kind, threshold) = entry
+                    .split_once('=')
+                    .and_then(|(k, t)| Some((k.trim(), t.trim().parse::<f32>().ok()?)))
+                    .filter(|(_, t)| (0.0..=1.0).contains(t))
+                    .ok_or_else(|| anyhow::anyhow!("Invalid KIACHA_BIOMETRIC_THRESHOLDS entry: {}", entry))?;
+                config.biometric_thresholds.insert(kind.to_string(), threshold);
+            }
+        }
+
+        if let Some(mb) = env_number("KIACHA_AUDIT_SEGMENT_MB")? {
+            config.audit_segment_bytes = mb.max(1) * 1024 * 1024;
+        }
+        if let Some(segments) = env_number("KIACHA_AUDIT_SEGMENTS")? {
+            config.audit_max_segments = segments.max(1) as usize;
+        }
+        config.audit_key = std::env::var("KIACHA_AUDIT_KEY").ok();
+        config.audit_key_file = std::env::var("KIACHA_AUDIT_KEY_FILE").ok().map(PathBuf::from);
+
+        if let Some(mb) = env_number("KIACHA_WASM_MAX_MEMORY_MB")? {
+            config.sandbox.max_memory = mb.max(1) * 1024 * 1024;
+        }
+        if let Some(ms) = env_number("KIACHA_WASM_MAX_CPU_MS")? {
+            config.sandbox.max_cpu_time = Duration::from_millis(ms.max(1));
+        }
+        if let Some(imports) = env_list("KIACHA_WASM_ALLOWED_IMPORTS") {
+            config.sandbox.allowed_imports = imports;
+        }
+        if let Some(forbidden) = env_list("KIACHA_WASM_FORBIDDEN") {
+            config.sandbox.forbidden = forbidden;
+        }
+        if let Some(entries) = env_number("KIACHA_WASM_CACHE_ENTRIES")? {
+            config.wasm_cache_entries = entries.max(1) as usize;
+        }
+        if let Some(on_disk) = env_number("KIACHA_WASM_CACHE_DISK")? {
+            config.wasm_cache_on_disk = on_disk != 0;
+        }
+        if let Some(mb) = env_number("KIACHA_WASM_CACHE_DISK_MB")? {
+            config.wasm_cache_disk_bytes = (mb > 0).then(|| mb * 1024 * 1024);
+        }
+
+        Ok(config)
+    }
+
+    pub fn event_journal_dir(&self) -> PathBuf {
+        self.data_dir.join("events")
+    }
+
+    pub fn policy_file(&self) -> PathBuf {
+        self.policy_file
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join("permissions.json"))
+    }
+
+    pub fn policy_key_file(&self) -> PathBuf {
+        self.data_dir.join("policy.key")
+    }
+
+    /// Snapshot and journal of users, credentials and sessions
+    pub fn users_dir(&self) -> PathBuf {
+        self.data_dir.join("users")
+    }
+
+    /// Generated password of the initial `admin` user
+    pub fn admin_password_file(&self) -> PathBuf {
+        self.data_dir.join("initial-admin-password")
+    }
+
+    /// Role definitions and per-user access overrides
+    pub fn access_policy_file(&self) -> PathBuf {
+        self.data_dir.join("access.json")
+    }
+
+    /// Segments of the hash-chained audit log
+    pub fn audit_dir(&self) -> PathBuf {
+        self.data_dir.join("audit")
+    }
+
+    /// Audit key used when neither `audit_key` nor `audit_key_file` is set
+    pub fn default_audit_key_file(&self) -> PathBuf {
+        self.data_dir.join("audit.key")
+    }
+
+    /// Serialized compiled WASM modules
+    pub fn wasm_cache_dir(&self) -> PathBuf {
+        self.data_dir.join("wasm-cache")
+    }
+
+    /// Encrypted biometric templates
+    pub fn biometrics_file(&self) -> PathBuf {
+        self.data_dir.join("biometrics.json")
+    }
+}
+
+fn env_number(name: &str) -> anyhow::Result<Option<u64>> {
+    match std::env::var(name) {
+        Ok(raw) => raw
+            .parse()
+            .map(Some)
+            .map_err(|_| anyhow::anyhow!("Invalid value for {}: {}", name, raw)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Comma-separated list; an empty value gives an empty list
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let raw = std::env::var(name).ok()?;
+    Some(
+        raw.split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(String::from)
+            .collect(),
+    )
+}
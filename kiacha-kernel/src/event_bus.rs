@@ -1,7 +1,10 @@
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
 use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use serde::{Deserialize, Serialize};
+use crate::event_journal::{EventJournal, JournalCursor};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Event {
@@ -9,33 +12,297 @@ pub struct Event {
     pub source: String,
     pub payload: Vec<u8>,
     pub timestamp: i64,
+    /// Position in the event journal, assigned on publish
+    #[serde(default)]
+    pub offset: u64,
+}
+
+impl Event {
+    /// Build a kernel event with a JSON payload stamped with the current time
+    pub fn json(event_type: &str, source: &str, payload: serde_json::Value) -> Self {
+        Event {
+            event_type: event_type.to_string(),
+            source: source.to_string(),
+            payload: serde_json::to_vec(&payload).unwrap_or_default(),
+            timestamp: chrono::Local::now().timestamp_millis(),
+            offset: 0,
+        }
+    }
+}
+
+/// Whether a hierarchical topic such as `security.threat.high` matches a
+/// subscription pattern. Segments are separated by `.`; `*` matches exactly one
+/// segment and `#` matches zero or more.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    fn matches(pattern: &[&str], topic: &[&str]) -> bool {
+        match (pattern.first(), topic.first()) {
+            (None, None) => true,
+            (Some(&"#"), _) => {
+                matches(&pattern[1..], topic) || (!topic.is_empty() && matches(pattern, &topic[1..]))
+            }
+            (Some(&"*"), Some(_)) => matches(&pattern[1..], &topic[1..]),
+            (Some(p), Some(t)) if p == t => matches(&pattern[1..], &topic[1..]),
+            _ => false,
+        }
+    }
+
+    let pattern: Vec<&str> = pattern.split('.').collect();
+    let topic: Vec<&str> = topic.split('.').collect();
+    matches(&pattern, &topic)
+}
+
+/// Capacity of a subscription channel when no per-topic size is configured
+pub const DEFAULT_BUFFER_SIZE: usize = 100;
+
+/// Broadcast channel for one subscription pattern
+struct TopicChannel {
+    sender: broadcast::Sender<Event>,
+    /// Events sent on the channel since it was created
+    sent: Arc<AtomicU64>,
+}
+
+/// Live counters for one subscriber, kept while its `Subscription` is alive.
+pub struct SubscriberMetrics {
+    pub id: String,
+    pub pattern: String,
+    pub buffer_size: usize,
+    pub connected_at: i64,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    /// Channel send count when the subscriber joined
+    sent_at_subscribe: u64,
+    channel_sent: Arc<AtomicU64>,
+}
+
+impl SubscriberMetrics {
+    /// Events sent to the subscriber that it has neither received nor lost yet,
+    /// capped at what its buffer can hold
+    fn pending(&self) -> u64 {
+        let sent = self.channel_sent.load(Ordering::Relaxed) - self.sent_at_subscribe;
+        let consumed = self.delivered.load(Ordering::Relaxed) + self.dropped.load(Ordering::Relaxed);
+        sent.saturating_sub(consumed).min(self.buffer_size as u64)
+    }
+}
+
+/// Point-in-time copy of a subscriber's counters.
+#[derive(Clone, Debug, Serialize)]
+pub struct SubscriberStats {
+    pub id: String,
+    pub pattern: String,
+    pub buffer_size: usize,
+    pub connected_at: i64,
+    pub delivered: u64,
+    pub dropped: u64,
+    pub pending: u64,
+}
+
+/// What a subscriber receives next: an event, or notice that it fell behind.
+pub enum Delivery {
+    Event(Event),
+    Dropped(u64),
+}
+
+/// A live subscription. Lagging does not end it; the number of events lost is
+/// reported as `Delivery::Dropped` and counted in the subscriber's metrics.
+pub struct Subscription {
+    receiver: broadcast::Receiver<Event>,
+    metrics: Arc<SubscriberMetrics>,
+    registry: Arc<DashMap<String, Arc<SubscriberMetrics>>>,
+    start_offset: u64,
+}
+
+impl Subscription {
+    pub fn pattern(&self) -> &str {
+        &self.metrics.pattern
+    }
+
+    /// Journal offset of the first event the subscription can receive live
+    pub fn start_offset(&self) -> u64 {
+        self.start_offset
+    }
+
+    /// Next delivery, or `None` once the bus is gone
+    pub async fn recv(&mut self) -> Option<Delivery> {
+        let delivery = match self.receiver.recv().await {
+            Ok(event) => {
+                self.metrics.delivered.fetch_add(1, Ordering::Relaxed);
+                Delivery::Event(event)
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                self.metrics.dropped.fetch_add(missed, Ordering::Relaxed);
+                Delivery::Dropped(missed)
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some(delivery)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.registry.remove(&self.metrics.id);
+    }
 }
 
 pub struct EventBus {
-    channels: Arc<DashMap<String, broadcast::Sender<Event>>>,
+    channels: Arc<DashMap<String, TopicChannel>>,
+    journal: Option<Arc<EventJournal>>,
+    /// Keeps journal order and broadcast order identical across publishers
+    publish_lock: tokio::sync::Mutex<()>,
+    default_buffer_size: usize,
+    /// Channel capacity per topic pattern
+    buffer_sizes: DashMap<String, usize>,
+    subscribers: Arc<DashMap<String, Arc<SubscriberMetrics>>>,
 }
 
 impl EventBus {
     pub fn new() -> Self {
         EventBus {
             channels: Arc::new(DashMap::new()),
+            journal: None,
+            publish_lock: tokio::sync::Mutex::new(()),
+            default_buffer_size: DEFAULT_BUFFER_SIZE,
+            buffer_sizes: DashMap::new(),
+            subscribers: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// An event bus that persists every published event before broadcasting it
+    pub fn with_journal(journal: Arc<EventJournal>) -> Self {
+        EventBus {
+            journal: Some(journal),
+            ..EventBus::new()
         }
     }
 
-    /// Subscribe to events of a specific type
-    pub fn subscribe(&self, event_type: String) -> broadcast::Receiver<Event> {
-        let entry = self.channels.entry(event_type.clone());
-        let sender = entry.or_insert_with(|| {
-            let (sender, _) = broadcast::channel(100);
-            sender
+    pub fn journal(&self) -> Option<Arc<EventJournal>> {
+        self.journal.clone()
+    }
+
+    /// Set the default and per-topic channel capacities
+    pub fn with_buffer_sizes(mut self, default: usize, per_topic: HashMap<String, usize>) -> Self {
+        self.default_buffer_size = default.max(1);
+        for (pattern, size) in per_topic {
+            self.buffer_sizes.insert(pattern, size.max(1));
+        }
+        self
+    }
+
+    /// Channel capacity for a subscription pattern: an exact per-topic entry wins,
+    /// then the most specific configured pattern covering it, then the default
+    ///
+    /// Specificity prefers fewer `#`, then fewer `*`, then more segments; ties
+    /// are broken by name so the choice does not depend on map order.
+    pub fn buffer_size_for(&self, pattern: &str) -> usize {
+        if let Some(size) = self.buffer_sizes.get(pattern) {
+            return *size;
+        }
+        self.buffer_sizes
+            .iter()
+            .filter(|entry| topic_matches(entry.key(), pattern))
+            .map(|entry| (specificity(entry.key()), entry.key().clone(), *entry.value()))
+            .min()
+            .map(|(_, _, size)| size)
+            .unwrap_or(self.default_buffer_size)
+    }
+
+    /// Subscribe to events whose type matches a topic pattern (see `topic_matches`)
+    pub fn subscribe(&self, pattern: String) -> Subscription {
+        let buffer_size = self.buffer_size_for(&pattern);
+        // Read before joining so a resume from here never skips an event
+        let start_offset = self.journal.as_ref().map_or(0, |journal| journal.next_offset());
+        let (receiver, sent_at_subscribe, channel_sent) = {
+            // Holding the entry keeps publishers from sending on this channel
+            // until the receiver and its starting count agree
+            let channel = self.channels.entry(pattern.clone()).or_insert_with(|| TopicChannel {
+                sender: broadcast::channel(buffer_size).0,
+                sent: Arc::new(AtomicU64::new(0)),
+            });
+            (
+                channel.sender.subscribe(),
+                channel.sent.load(Ordering::Relaxed),
+                channel.sent.clone(),
+            )
+        };
+
+        let metrics = Arc::new(SubscriberMetrics {
+            id: uuid::Uuid::new_v4().to_string(),
+            pattern,
+            buffer_size,
+            connected_at: chrono::Local::now().timestamp_millis(),
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            sent_at_subscribe,
+            channel_sent,
         });
-        sender.subscribe()
+        self.subscribers.insert(metrics.id.clone(), metrics.clone());
+
+        Subscription {
+            receiver,
+            metrics,
+            registry: self.subscribers.clone(),
+            start_offset,
+        }
     }
 
-    /// Publish an event
-    pub async fn publish(&self, event: Event) -> anyhow::Result<()> {
-        if let Some(sender) = self.channels.get(&event.event_type) {
-            sender.send(event)?;
+    /// Replay journaled events matching `pattern` from `cursor`, then follow live ones
+    ///
+    /// The live subscription is registered before the journal is read, so callers
+    /// must skip live events whose offset is not past the last replayed one. The
+    /// backlog is read on a blocking thread and closes once it is exhausted.
+    pub fn subscribe_from(
+        &self,
+        pattern: String,
+        cursor: JournalCursor,
+    ) -> (mpsc::Receiver<anyhow::Result<Event>>, Subscription) {
+        let subscription = self.subscribe(pattern.clone());
+        let backlog = match &self.journal {
+            Some(journal) => journal.replay(&pattern, cursor),
+            None => mpsc::channel(1).1,
+        };
+        (backlog, subscription)
+    }
+
+    /// Counters for every live subscriber
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.subscribers
+            .iter()
+            .map(|m| SubscriberStats {
+                id: m.id.clone(),
+                pattern: m.pattern.clone(),
+                buffer_size: m.buffer_size,
+                connected_at: m.connected_at,
+                delivered: m.delivered.load(Ordering::Relaxed),
+                dropped: m.dropped.load(Ordering::Relaxed),
+                pending: m.pending(),
+            })
+            .collect()
+    }
+
+    /// Publish an event to every subscription whose pattern matches its type
+    pub async fn publish(&self, mut event: Event) -> anyhow::Result<()> {
+        let _order = self.publish_lock.lock().await;
+        if let Some(journal) = &self.journal {
+            // File I/O stays off the async workers
+            let journal = journal.clone();
+            event = tokio::task::spawn_blocking(move || {
+                journal.append(&mut event)?;
+                Ok::<_, anyhow::Error>(event)
+            })
+            .await??;
+        }
+
+        let mut abandoned = false;
+        for channel in self.channels.iter() {
+            if topic_matches(channel.key(), &event.event_type) {
+                abandoned |= channel.sender.send(event.clone()).is_err();
+                channel.sent.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // Drop patterns nobody listens to anymore
+        if abandoned {
+            self.channels.retain(|_, channel| channel.sender.receiver_count() > 0);
         }
         Ok(())
     }
@@ -45,3 +312,74 @@ impl EventBus {
         self.channels.iter().map(|r| r.key().clone()).collect()
     }
 }
+
+/// Sort key for `buffer_size_for`; smaller is more specific
+fn specificity(pattern: &str) -> (usize, usize, std::cmp::Reverse<usize>) {
+    let segments: Vec<&str> = pattern.split('.').collect();
+    let multi = segments.iter().filter(|s| **s == "#").count();
+    let single = segments.iter().filter(|s| **s == "*").count();
+    (multi, single, std::cmp::Reverse(segments.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topic_matches_exact_and_wildcards() {
+        assert!(topic_matches("module.spawned", "module.spawned"));
+        assert!(!topic_matches("module.spawned", "module.exited"));
+        assert!(topic_matches("module.*", "module.exited"));
+        assert!(!topic_matches("module.*", "module"));
+        assert!(!topic_matches("module.*", "module.restart.failed"));
+    }
+
+    #[test]
+    fn test_topic_matches_multi_segment() {
+        assert!(topic_matches("security.#", "security"));
+        assert!(topic_matches("security.#", "security.threat.high"));
+        assert!(topic_matches("#", "module.spawned"));
+        assert!(topic_matches("security.#.high", "security.threat.high"));
+        assert!(!topic_matches("security.#", "module.spawned"));
+    }
+
+    #[test]
+    fn test_buffer_size_prefers_most_specific_pattern() {
+        let sizes = [("#", 5), ("module.#", 7), ("module.*", 9), ("module.spawned", 11)]
+            .into_iter()
+            .map(|(pattern, size)| (pattern.to_string(), size))
+            .collect();
+        let bus = EventBus::new().with_buffer_sizes(10, sizes);
+
+        assert_eq!(bus.buffer_size_for("module.spawned"), 11);
+        assert_eq!(bus.buffer_size_for("module.exited"), 9);
+        assert_eq!(bus.buffer_size_for("module.restart.failed"), 7);
+        assert_eq!(bus.buffer_size_for("ipc.sent"), 5);
+        assert_eq!(EventBus::new().buffer_size_for("ipc.sent"), DEFAULT_BUFFER_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_pending_is_current_when_stats_are_read() {
+        let sizes = [("module.*".to_string(), 2)].into_iter().collect();
+        let bus = EventBus::new().with_buffer_sizes(10, sizes);
+        let mut subscription = bus.subscribe("module.*".to_string());
+        let pending = |bus: &EventBus| bus.subscriber_stats()[0].pending;
+
+        bus.publish(Event::json("module.spawned", "test", serde_json::json!({}))).await.unwrap();
+        assert_eq!(pending(&bus), 1);
+        bus.publish(Event::json("ipc.sent", "test", serde_json::json!({}))).await.unwrap();
+        assert_eq!(pending(&bus), 1);
+
+        for _ in 0..3 {
+            bus.publish(Event::json("module.exited", "test", serde_json::json!({}))).await.unwrap();
+        }
+        assert_eq!(pending(&bus), 2);
+
+        assert!(matches!(subscription.recv().await, Some(Delivery::Dropped(2))));
+        assert_eq!(pending(&bus), 2);
+        assert!(matches!(subscription.recv().await, Some(Delivery::Event(_))));
+        assert_eq!(pending(&bus), 1);
+        let stats = &bus.subscriber_stats()[0];
+        assert_eq!((stats.delivered, stats.dropped), (1, 2));
+    }
+}
@@ -0,0 +1,463 @@
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Lines, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tracing::warn;
+use crate::event_bus::{topic_matches, Event};
+
+/// Where a replaying subscriber wants to start reading the journal.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JournalCursor {
+    pub from_offset: Option<u64>,
+    pub from_timestamp: Option<i64>,
+}
+
+impl JournalCursor {
+    fn includes(&self, event: &Event) -> bool {
+        self.from_offset.map_or(true, |offset| event.offset >= offset)
+            && self.from_timestamp.map_or(true, |ts| event.timestamp >= ts)
+    }
+}
+
+/// How much history the journal keeps. Whole segments are deleted, oldest first.
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+struct Segment {
+    base_offset: u64,
+    path: PathBuf,
+    size: u64,
+}
+
+struct JournalState {
+    next_offset: u64,
+    active: File,
+    segments: Vec<Segment>,
+}
+
+/// Append-only on-disk event log.
+///
+/// Events are stored as JSON lines in segment files named after the offset of
+/// their first event; offsets increase monotonically across restarts. Each
+/// append is synced to disk before it returns.
+pub struct EventJournal {
+    dir: PathBuf,
+    segment_bytes: u64,
+    retention: RetentionPolicy,
+    state: Mutex<JournalState>,
+}
+
+impl EventJournal {
+    pub fn open(dir: &Path, segment_bytes: u64, retention: RetentionPolicy) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let base_offset = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".jsonl"))
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(base_offset) = base_offset {
+                let size = fs::metadata(&path)?.len();
+                segments.push(Segment { base_offset, path, size });
+            }
+        }
+        segments.sort_by_key(|s| s.base_offset);
+
+        // A crash mid-append leaves a partial line; cut it off so the next
+        // append starts on a fresh line instead of corrupting another event
+        if let Some(last) = segments.last_mut() {
+            last.size = trim_torn_tail(&last.path)?;
+        }
+
+        // Resume numbering after the last event that made it to disk
+        let next_offset = match segments.last() {
+            Some(last) => read_segment(&last.path)?
+                .last()
+                .map(|e| e.offset + 1)
+                .unwrap_or(last.base_offset),
+            None => 0,
+        };
+
+        if segments.last().map_or(true, |s| s.size >= segment_bytes) {
+            segments.push(Segment {
+                base_offset: next_offset,
+                path: segment_path(dir, next_offset),
+                size: 0,
+            });
+        }
+        let active = open_append(&segments.last().expect("active segment").path)?;
+
+        let journal = EventJournal {
+            dir: dir.to_path_buf(),
+            segment_bytes,
+            retention,
+            state: Mutex::new(JournalState {
+                next_offset,
+                active,
+                segments,
+            }),
+        };
+        journal.enforce_retention(&mut journal.state.lock());
+        Ok(journal)
+    }
+
+    /// Persist an event, assigning it the next offset
+    pub fn append(&self, event: &mut Event) -> anyhow::Result<u64> {
+        let mut state = self.state.lock();
+        event.offset = state.next_offset;
+
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        state.active.write_all(&line)?;
+        state.active.sync_data()?;
+        state.next_offset += 1;
+
+        let segment = state.segments.last_mut().expect("active segment");
+        segment.size += line.len() as u64;
+
+        if segment.size >= self.segment_bytes {
+            self.roll(&mut state)?;
+            self.enforce_retention(&mut state);
+        }
+
+        Ok(event.offset)
+    }
+
+    /// Apply the retention policy without waiting for a new segment.
+    ///
+    /// An active segment last written to longer ago than `max_age` is closed
+    /// first, so a quiet journal does not keep old events forever.
+    pub fn apply_retention(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock();
+        let active = state.segments.last().expect("active segment");
+        let stale = self.retention.max_age.map_or(false, |max_age| age(&active.path) > Some(max_age));
+        if active.size > 0 && stale {
+            self.roll(&mut state)?;
+        }
+        self.enforce_retention(&mut state);
+        Ok(())
+    }
+
+    /// Start a new active segment at the next offset
+    fn roll(&self, state: &mut JournalState) -> anyhow::Result<()> {
+        let base_offset = state.next_offset;
+        let path = segment_path(&self.dir, base_offset);
+        state.active = open_append(&path)?;
+        state.segments.push(Segment {
+            base_offset,
+            path,
+            size: 0,
+        });
+        Ok(())
+    }
+
+    /// Offset the next appended event will get
+    pub fn next_offset(&self) -> u64 {
+        self.state.lock().next_offset
+    }
+
+    /// Lazily read stored events matching `pattern` from the cursor onwards, in
+    /// offset order. Segments are opened one at a time as the reader advances.
+    pub fn read(&self, pattern: &str, cursor: JournalCursor) -> JournalReader {
+        let paths = {
+            let state = self.state.lock();
+            let segments = &state.segments;
+            segments
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| match (cursor.from_offset, segments.get(i + 1)) {
+                    // Skip segments that end before the requested offset
+                    (Some(offset), Some(next)) => next.base_offset > offset,
+                    _ => true,
+                })
+                .map(|(_, s)| s.path.clone())
+                .collect()
+        };
+
+        JournalReader {
+            paths,
+            current: None,
+            pattern: pattern.to_string(),
+            cursor,
+        }
+    }
+
+    /// Stream matching events from a blocking thread so large backlogs neither
+    /// stall the async workers nor have to fit in memory
+    pub fn replay(
+        self: &Arc<Self>,
+        pattern: &str,
+        cursor: JournalCursor,
+    ) -> mpsc::Receiver<anyhow::Result<Event>> {
+        let (sender, receiver) = mpsc::channel(REPLAY_BUFFER);
+        let reader = self.read(pattern, cursor);
+        tokio::task::spawn_blocking(move || {
+            for event in reader {
+                // The subscriber went away; stop reading
+                if sender.blocking_send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    /// Delete the oldest segments that fall outside the retention policy.
+    /// The active segment is never removed.
+    fn enforce_retention(&self, state: &mut JournalState) {
+        let mut total: u64 = state.segments.iter().map(|s| s.size).sum();
+
+        while state.segments.len() > 1 {
+            let oldest = &state.segments[0];
+            let too_big = self.retention.max_bytes.map_or(false, |max| total > max);
+            let too_old = self
+                .retention
+                .max_age
+                .map_or(false, |max_age| age(&oldest.path) > Some(max_age));
+            if !too_big && !too_old {
+                break;
+            }
+
+            let oldest = state.segments.remove(0);
+            total -= oldest.size;
+            if let Err(e) = fs::remove_file(&oldest.path) {
+                warn!("Failed to remove event journal segment {:?}: {}", oldest.path, e);
+            }
+        }
+    }
+}
+
+/// Time since a segment was last written to
+fn age(path: &Path) -> Option<Duration> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    SystemTime::now().duration_since(modified).ok()
+}
+
+fn segment_path(dir: &Path, base_offset: u64) -> PathBuf {
+    dir.join(format!("{:020}.jsonl", base_offset))
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// Parse a segment, skipping unreadable lines
+fn read_segment(path: &Path) -> anyhow::Result<Vec<Event>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<Event>(&line) {
+            Ok(event) => events.push(event),
+            Err(e) => warn!("Skipping unreadable event in {:?}: {}", path, e),
+        }
+    }
+    Ok(events)
+}
+
+/// Truncate a segment after its last complete line, returning the new size
+fn trim_torn_tail(path: &Path) -> anyhow::Result<u64> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let size = file.metadata()?.len();
+
+    // Scan backwards for the last newline
+    let mut end = size;
+    let mut chunk = [0u8; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(chunk.len() as u64);
+        let buf = &mut chunk[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(buf)?;
+        if let Some(pos) = buf.iter().rposition(|b| *b == b'\n') {
+            end = start + pos as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+
+    if end < size {
+        warn!("Discarding {} bytes of a torn event in {:?}", size - end, path);
+        file.set_len(end)?;
+        file.sync_data()?;
+    }
+    Ok(end)
+}
+
+/// Events buffered between a replaying blocking reader and its subscriber
+const REPLAY_BUFFER: usize = 256;
+
+/// Iterator over journaled events, returned by `EventJournal::read`.
+///
+/// Segments removed by retention while the reader is behind are skipped.
+pub struct JournalReader {
+    paths: VecDeque<PathBuf>,
+    current: Option<(PathBuf, Lines<BufReader<File>>)>,
+    pattern: String,
+    cursor: JournalCursor,
+}
+
+impl Iterator for JournalReader {
+    type Item = anyhow::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let path = self.paths.pop_front()?;
+                match File::open(&path) {
+                    Ok(file) => self.current = Some((path, BufReader::new(file).lines())),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
+
+            let (path, lines) = self.current.as_mut().expect("open segment");
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => {
+                    self.current = None;
+                    continue;
+                }
+            };
+            match serde_json::from_str::<Event>(&line) {
+                Ok(event) if self.cursor.includes(&event) && topic_matches(&self.pattern, &event.event_type) => {
+                    return Some(Ok(event))
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skipping unreadable event in {:?}: {}", path, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+
+    fn unbounded() -> RetentionPolicy {
+        RetentionPolicy {
+            max_age: None,
+            max_bytes: None,
+        }
+    }
+
+    fn append(journal: &EventJournal, event_type: &str) -> u64 {
+        let mut event = Event::json(event_type, "test", serde_json::json!({}));
+        journal.append(&mut event).unwrap()
+    }
+
+    fn offsets(reader: JournalReader) -> Vec<u64> {
+        reader.map(|event| event.unwrap().offset).collect()
+    }
+
+    #[test]
+    fn resumes_from_offset_across_segments_and_restarts() {
+        let dir = TempDir::new("journal-test");
+        {
+            let journal = EventJournal::open(&dir, 200, unbounded()).unwrap();
+            for _ in 0..10 {
+                append(&journal, "module.spawned");
+            }
+            append(&journal, "security.alert");
+        }
+
+        let journal = EventJournal::open(&dir, 200, unbounded()).unwrap();
+        assert_eq!(journal.next_offset(), 11);
+        assert_eq!(append(&journal, "module.exited"), 11);
+
+        let cursor = JournalCursor {
+            from_offset: Some(7),
+            from_timestamp: None,
+        };
+        assert_eq!(offsets(journal.read("module.*", cursor)), vec![7, 8, 9, 11]);
+        assert_eq!(offsets(journal.read("#", JournalCursor::default())).len(), 12);
+    }
+
+    #[test]
+    fn torn_final_line_is_trimmed_on_open() {
+        let dir = TempDir::new("journal-test");
+        {
+            let journal = EventJournal::open(&dir, 1 << 20, unbounded()).unwrap();
+            append(&journal, "module.spawned");
+            append(&journal, "module.spawned");
+        }
+        let mut segment = OpenOptions::new().append(true).open(segment_path(&dir, 0)).unwrap();
+        segment.write_all(br#"{"event_type":"module.exi"#).unwrap();
+        drop(segment);
+
+        let journal = EventJournal::open(&dir, 1 << 20, unbounded()).unwrap();
+        assert_eq!(journal.next_offset(), 2);
+        assert_eq!(append(&journal, "module.exited"), 2);
+        assert_eq!(offsets(journal.read("#", JournalCursor::default())), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn retention_drops_oldest_segments_but_keeps_offsets() {
+        let dir = TempDir::new("journal-test");
+        let retention = RetentionPolicy {
+            max_age: None,
+            max_bytes: Some(400),
+        };
+        let journal = EventJournal::open(&dir, 150, retention).unwrap();
+        for _ in 0..20 {
+            append(&journal, "module.spawned");
+        }
+
+        assert!(!segment_path(&dir, 0).exists());
+        let kept = offsets(journal.read("#", JournalCursor::default()));
+        assert!(kept.len() < 20);
+        assert_eq!(kept.last(), Some(&19));
+        assert!(kept.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    }
+
+    #[test]
+    fn quiet_journals_still_age_out() {
+        let dir = TempDir::new("journal-test");
+        let retention = RetentionPolicy {
+            max_age: Some(Duration::from_millis(50)),
+            max_bytes: None,
+        };
+        let journal = EventJournal::open(&dir, 1 << 20, retention).unwrap();
+        append(&journal, "module.spawned");
+        append(&journal, "module.spawned");
+
+        std::thread::sleep(Duration::from_millis(100));
+        journal.apply_retention().unwrap();
+        assert!(!segment_path(&dir, 0).exists());
+        assert!(offsets(journal.read("#", JournalCursor::default())).is_empty());
+        assert_eq!(append(&journal, "module.exited"), 2);
+    }
+
+    #[tokio::test]
+    async fn replay_streams_backlog_from_a_blocking_reader() {
+        let dir = TempDir::new("journal-test");
+        let journal = Arc::new(EventJournal::open(&dir, 1 << 20, unbounded()).unwrap());
+        for i in 0..(REPLAY_BUFFER * 2) {
+            append(&journal, if i % 2 == 0 { "module.spawned" } else { "ipc.sent" });
+        }
+
+        let mut backlog = journal.replay("module.*", JournalCursor::default());
+        let mut received = 0;
+        while let Some(event) = backlog.recv().await {
+            assert_eq!(event.unwrap().event_type, "module.spawned");
+            received += 1;
+        }
+        assert_eq!(received, REPLAY_BUFFER);
+    }
+}
@@ -1,9 +1,295 @@
 use tonic::{Request, Response, Status};
 use tracing::info;
 use std::sync::Arc;
+use tokio::sync::mpsc;
 use crate::kernel::KiachaKernel;
+use crate::event_journal::JournalCursor;
+use crate::event_bus::{Delivery, Subscription};
+use crate::rbac::{Access, App, Caller};
 use crate::proto::*;
 
+/// Map IPC failures onto gRPC codes so callers can tell backpressure from bugs
+fn ipc_status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<crate::ipc::IpcError>() {
+        Some(crate::ipc::IpcError::Timeout(..)) => Status::deadline_exceeded(e.to_string()),
+        Some(crate::ipc::IpcError::QueueFull(_)) => Status::resource_exhausted(e.to_string()),
+        None => Status::internal(e.to_string()),
+    }
+}
+
+/// Map login failures onto gRPC codes; a locked account is not the same as bad credentials
+fn auth_status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<crate::user_manager::AuthError>() {
+        Some(crate::user_manager::AuthError::InvalidCredentials) => Status::unauthenticated(e.to_string()),
+        Some(crate::user_manager::AuthError::Locked(_)) => Status::permission_denied(e.to_string()),
+        None => Status::invalid_argument(e.to_string()),
+    }
+}
+
+/// Refusals are permission errors, not server failures
+fn module_status(e: anyhow::Error) -> Status {
+    match e.downcast_ref::<crate::rbac::AccessDenied>() {
+        Some(_) => Status::permission_denied(e.to_string()),
+        None => Status::internal(e.to_string()),
+    }
+}
+
+/// Sandbox limits are resource errors, not server failures
+fn wasm_status(e: anyhow::Error) -> Status {
+    if e.downcast_ref::<crate::rbac::AccessDenied>().is_some() {
+        return Status::permission_denied(e.to_string());
+    }
+    match e.downcast_ref::<crate::wasm_runtime::SandboxError>() {
+        Some(crate::wasm_runtime::SandboxError::ForbiddenImport(_)) => Status::permission_denied(e.to_string()),
+        Some(_) => Status::resource_exhausted(e.to_string()),
+        None => Status::failed_precondition(e.to_string()),
+    }
+}
+
+fn to_proto_wasm_value(value: crate::wasm_runtime::WasmValue) -> WasmValue {
+    use crate::wasm_runtime::WasmValue as Value;
+    let value = match value {
+        Value::I32(v) => wasm_value::Value::I32(v),
+        Value::I64(v) => wasm_value::Value::I64(v),
+        Value::F32(v) => wasm_value::Value::F32(v),
+        Value::F64(v) => wasm_value::Value::F64(v),
+    };
+    WasmValue { value: Some(value) }
+}
+
+fn from_proto_wasm_value(value: WasmValue) -> Result<crate::wasm_runtime::WasmValue, Status> {
+    use crate::wasm_runtime::WasmValue as Value;
+    match value.value {
+        Some(wasm_value::Value::I32(v)) => Ok(Value::I32(v)),
+        Some(wasm_value::Value::I64(v)) => Ok(Value::I64(v)),
+        Some(wasm_value::Value::F32(v)) => Ok(Value::F32(v)),
+        Some(wasm_value::Value::F64(v)) => Ok(Value::F64(v)),
+        None => Err(Status::invalid_argument("WASM argument without a value")),
+    }
+}
+
+fn to_proto_session(session: crate::user_manager::Session, token: String) -> Session {
+    Session {
+        session_id: session.session_id,
+        user_id: session.user_id,
+        started_at: session.started_at,
+        last_activity: session.last_activity,
+        device: session.device,
+        token,
+    }
+}
+
+fn to_proto_user(user: crate::user_manager::User) -> User {
+    User {
+        user_id: user.id,
+        username: user.username,
+        display_name: user.display_name,
+        role: user.role,
+        active: user.active,
+        created_at: user.created_at,
+    }
+}
+
+fn to_proto_audit_log(record: crate::security::AuditRecord) -> AuditLog {
+    AuditLog {
+        event_type: record.event_type().to_string(),
+        actor: record.actor,
+        action: record.action,
+        target: record.target,
+        success: record.success,
+        details: record.details,
+        timestamp: record.timestamp,
+        severity: match record.severity() {
+            crate::security::Severity::Info => AuditSeverity::Info,
+            crate::security::Severity::Warning => AuditSeverity::Warning,
+            crate::security::Severity::Critical => AuditSeverity::Critical,
+        } as i32,
+        sequence: record.sequence,
+        prev_hash: record.prev_hash,
+        hash: record.hash,
+    }
+}
+
+type AuditStream = std::pin::Pin<Box<dyn futures::Stream<Item = Result<AuditLog, Status>> + Send>>;
+
+/// Stream audit history and then, when following, new records as they are
+/// written
+///
+/// A follower that falls behind the live feed catches up from disk, so it
+/// sees every matching record exactly once.
+fn audit_stream(
+    kernel: Arc<KiachaKernel>,
+    filter: crate::security::AuditFilter,
+    backlog: Vec<crate::security::AuditRecord>,
+    live: Option<(tokio::sync::broadcast::Receiver<crate::security::AuditRecord>, u64)>,
+) -> AuditStream {
+    use tokio::sync::broadcast::error::RecvError;
+
+    let stream = async_stream::stream! {
+        // Highest sequence number sent or passed over. Everything before the
+        // subscription was up to the history read.
+        let mut cursor = live.as_ref().and_then(|(_, from)| from.checked_sub(1));
+        for record in backlog {
+            cursor = cursor.max(Some(record.sequence));
+            yield Ok(to_proto_audit_log(record));
+        }
+
+        let mut live = match live {
+            Some((live, _)) => live,
+            None => return,
+        };
+        loop {
+            let records = match live.recv().await {
+                Ok(record) => vec![record],
+                Err(RecvError::Lagged(_)) => {
+                    let catch_up = crate::security::AuditFilter {
+                        from_sequence: Some(cursor.map_or(0, |c: u64| c + 1)),
+                        limit: None,
+                        ..filter.clone()
+                    };
+                    match kernel.audit_records(catch_up).await {
+                        Ok(records) => records,
+                        Err(e) => {
+                            yield Err(Status::internal(e.to_string()));
+                            return;
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for record in records {
+                if cursor.map_or(false, |c| record.sequence <= c) {
+                    continue;
+                }
+                cursor = Some(record.sequence);
+                if filter.matches(&record) {
+                    yield Ok(to_proto_audit_log(record));
+                }
+            }
+        }
+    };
+    Box::pin(stream)
+}
+
+type EventStream = std::pin::Pin<Box<dyn futures::Stream<Item = Result<Event, Status>> + Send>>;
+
+fn to_proto_event(event: crate::event_bus::Event) -> Event {
+    Event {
+        event_type: event.event_type,
+        source: event.source,
+        payload: event.payload,
+        timestamp: event.timestamp,
+        offset: event.offset,
+    }
+}
+
+/// Forward a bus subscription to a gRPC stream, after any replayed backlog
+///
+/// A subscriber that falls behind gets a `kernel.events_dropped` marker with the
+/// number of lost events and keeps streaming. The marker carries the offset of
+/// the last event forwarded, so a client that resumes from `offset + 1` (the
+/// `resume_from` in its payload) recovers the lost events from the journal.
+fn event_stream(
+    mut subscription: Subscription,
+    backlog: Option<mpsc::Receiver<anyhow::Result<crate::event_bus::Event>>>,
+) -> EventStream {
+    let stream = async_stream::stream! {
+        let mut last_offset = None;
+        let mut resume_from = subscription.start_offset();
+        if let Some(mut backlog) = backlog {
+            while let Some(event) = backlog.recv().await {
+                match event {
+                    Ok(event) => {
+                        last_offset = Some(event.offset);
+                        resume_from = event.offset + 1;
+                        yield Ok(to_proto_event(event));
+                    }
+                    Err(e) => {
+                        yield Err(Status::internal(format!("Event journal replay failed: {}", e)));
+                        return;
+                    }
+                }
+            }
+        }
+
+        while let Some(delivery) = subscription.recv().await {
+            match delivery {
+                // Live events published while replaying were already sent from the journal
+                Delivery::Event(event) if last_offset.map_or(false, |last| event.offset <= last) => {}
+                Delivery::Event(event) => {
+                    resume_from = event.offset + 1;
+                    yield Ok(to_proto_event(event));
+                }
+                Delivery::Dropped(dropped) => {
+                    let mut marker = crate::event_bus::Event::json("kernel.events_dropped", "kernel", serde_json::json!({
+                        "dropped": dropped,
+                        "pattern": subscription.pattern(),
+                        "resume_from": resume_from,
+                    }));
+                    marker.offset = resume_from.saturating_sub(1);
+                    yield Ok(to_proto_event(marker));
+                }
+            }
+        }
+    };
+    Box::pin(stream)
+}
+
+/// Token from `authorization: Bearer <token>` metadata
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Authenticates every call and attaches the resolved `Caller` to the request
+///
+/// Tokens that match no module credential or user session are rejected here;
+/// calls without a token proceed as `Caller::Anonymous`.
+#[derive(Clone)]
+pub struct AuthInterceptor {
+    kernel: Arc<KiachaKernel>,
+}
+
+impl AuthInterceptor {
+    pub fn new(kernel: Arc<KiachaKernel>) -> Self {
+        AuthInterceptor { kernel }
+    }
+}
+
+impl tonic::service::Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let caller = self
+            .kernel
+            .resolve_caller(bearer_token(&request))
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        request.extensions_mut().insert(caller);
+        Ok(request)
+    }
+}
+
+/// The caller `AuthInterceptor` resolved for a request
+fn caller<T>(request: &Request<T>) -> Result<Caller, Status> {
+    request
+        .extensions()
+        .get::<Caller>()
+        .cloned()
+        .ok_or_else(|| Status::unauthenticated("Request was not authenticated"))
+}
+
+/// Check the caller of a request against its access to `app`
+fn authorize<T>(kernel: &KiachaKernel, request: &Request<T>, app: App, access: Access) -> Result<Caller, Status> {
+    let caller = caller(request)?;
+    kernel
+        .authorize(&caller, app, access)
+        .map_err(|e| Status::permission_denied(e.to_string()))?;
+    Ok(caller)
+}
+
 pub struct KiachaKernelService {
     kernel: Arc<KiachaKernel>,
 }
@@ -20,27 +306,30 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<ModuleRequest>,
     ) -> Result<Response<ModuleResponse>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Modules, Access::Write)?;
         let req = request.into_inner();
-        info!("Spawning module: {}", req.name);
+        info!("Spawning module: {} (by {})", req.name, caller.describe());
 
         let module_type = req.r#type();
-        let module_id = self
+        let (module_id, credential) = self
             .kernel
-            .spawn(req.name, module_type)
+            .spawn(&caller, req.name, module_type, req.config)
             .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+            .map_err(module_status)?;
 
         Ok(Response::new(ModuleResponse {
             module_id,
             status: "running".to_string(),
             created_at: chrono::Local::now().timestamp_millis(),
+            credential: credential.unwrap_or_default(),
         }))
     }
 
     async fn list_modules(
         &self,
-        _request: Request<::prost::well_known_types::Empty>,
+        request: Request<::prost::well_known_types::Empty>,
     ) -> Result<Response<ModuleList>, Status> {
+        authorize(&self.kernel, &request, App::Modules, Access::Read)?;
         let modules: Vec<ModuleInfo> = self
             .kernel
             .list_modules()
@@ -50,7 +339,7 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
                 name: m.name.clone(),
                 r#type: m.module_type.clone() as i32,
                 status: format!("{:?}", m.status),
-                created_at: chrono::Local::now().timestamp_millis(),
+                created_at: m.created_at,
             })
             .collect();
 
@@ -61,9 +350,10 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<::prost::wrappers::StringValue>,
     ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Modules, Access::Write)?;
         let module_id = request.into_inner().value;
         self.kernel
-            .pause_module(&module_id)
+            .pause_module(&caller, &module_id)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
@@ -74,9 +364,10 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<::prost::wrappers::StringValue>,
     ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Modules, Access::Write)?;
         let module_id = request.into_inner().value;
         self.kernel
-            .resume_module(&module_id)
+            .resume_module(&caller, &module_id)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
@@ -87,11 +378,12 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<IpcMessage>,
     ) -> Result<Response<IpcResponse>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Ipc, Access::Write)?;
         let msg = request.into_inner();
         self.kernel
-            .ipc_send(&msg.from, &msg.to, msg.clone())
+            .ipc_send(&caller, &msg.from, &msg.to, msg.clone().into())
             .await
-            .map_err(|e| Status::internal(e.to_string()))?;
+            .map_err(ipc_status)?;
 
         Ok(Response::new(IpcResponse {
             success: true,
@@ -99,42 +391,138 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         }))
     }
 
-    type SubscribeToEventsStream =
-        std::pin::Pin<Box<dyn futures::Stream<Item = Result<Event, Status>> + Send>>;
+    async fn call_ipc(
+        &self,
+        request: Request<IpcCall>,
+    ) -> Result<Response<IpcMessage>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Ipc, Access::Write)?;
+        let call = request.into_inner();
+        let msg = call
+            .message
+            .ok_or_else(|| Status::invalid_argument("IpcCall.message is required"))?;
+        let timeout = (call.timeout_ms > 0)
+            .then(|| std::time::Duration::from_millis(call.timeout_ms as u64));
 
-    async fn subscribe_to_events(
+        let reply = self
+            .kernel
+            .call_ipc(&caller, &msg.from, &msg.to, msg.clone().into(), timeout)
+            .await
+            .map_err(ipc_status)?;
+
+        Ok(Response::new(reply.into()))
+    }
+
+    async fn list_dead_letters(
+        &self,
+        request: Request<DeadLetterRequest>,
+    ) -> Result<Response<DeadLetterList>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Ipc, Access::Read)?;
+        let letters = self
+            .kernel
+            .list_dead_letters(&caller)
+            .map_err(|e| Status::permission_denied(e.to_string()))?
+            .into_iter()
+            .map(|l| DeadLetter {
+                id: l.id,
+                message: Some(l.message.into()),
+                reason: l.reason,
+                failed_at: l.failed_at,
+            })
+            .collect();
+
+        Ok(Response::new(DeadLetterList { letters }))
+    }
+
+    async fn replay_dead_letter(
+        &self,
+        request: Request<DeadLetterRequest>,
+    ) -> Result<Response<IpcResponse>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Ipc, Access::Write)?;
+        let req = request.into_inner();
+        self.kernel
+            .replay_dead_letter(&caller, &req.letter_id)
+            .await
+            .map_err(ipc_status)?;
+
+        Ok(Response::new(IpcResponse {
+            success: true,
+            message: "Message redelivered".to_string(),
+        }))
+    }
+
+    type ReceiveIpcStream =
+        std::pin::Pin<Box<dyn futures::Stream<Item = Result<IpcMessage, Status>> + Send>>;
+
+    async fn receive_ipc(
         &self,
         request: Request<::prost::wrappers::StringValue>,
-    ) -> Result<Response<Self::SubscribeToEventsStream>, Status> {
-        let event_type = request.into_inner().value;
-        let mut rx = self.kernel.subscribe_to_events(&event_type);
+    ) -> Result<Response<Self::ReceiveIpcStream>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Ipc, Access::Read)?;
+        let module_id = request.into_inner().value;
+        let mut mailbox = self
+            .kernel
+            .open_mailbox(&caller, &module_id)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
 
         let stream = async_stream::stream! {
-            while let Ok(event) = rx.recv().await {
-                yield Ok(Event {
-                    event_type: event.event_type.clone(),
-                    source: event.source.clone(),
-                    payload: event.payload.clone(),
-                    timestamp: event.timestamp,
-                });
+            loop {
+                let msg = mailbox.recv().await;
+                yield Ok(IpcMessage::from(msg));
             }
         };
 
         Ok(Response::new(Box::pin(stream)))
     }
 
+    type SubscribeToEventsStream = EventStream;
+
+    async fn subscribe_to_events(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<Self::SubscribeToEventsStream>, Status> {
+        authorize(&self.kernel, &request, App::Events, Access::Read)?;
+        let event_type = request.into_inner().value;
+        let subscription = self.kernel.subscribe_to_events(&event_type);
+        Ok(Response::new(event_stream(subscription, None)))
+    }
+
+    async fn get_event_bus_metrics(
+        &self,
+        request: Request<::prost::well_known_types::Empty>,
+    ) -> Result<Response<EventBusMetrics>, Status> {
+        authorize(&self.kernel, &request, App::Events, Access::Read)?;
+        let subscribers = self
+            .kernel
+            .event_subscriber_stats()
+            .into_iter()
+            .map(|s| SubscriberMetrics {
+                subscriber_id: s.id,
+                pattern: s.pattern,
+                buffer_size: s.buffer_size as u64,
+                connected_at: s.connected_at,
+                delivered: s.delivered,
+                dropped: s.dropped,
+                pending: s.pending,
+            })
+            .collect();
+
+        Ok(Response::new(EventBusMetrics { subscribers }))
+    }
+
     async fn check_permission(
         &self,
         request: Request<PermissionRequest>,
     ) -> Result<Response<PermissionResponse>, Status> {
+        authorize(&self.kernel, &request, App::Permissions, Access::Read)?;
         let req = request.into_inner();
         let permission = req.permission();
 
+        let target = (!req.target.is_empty()).then(|| req.target.as_str());
         let granted = self
             .kernel
-            .check_permission(&req.module_id, permission)
+            .check_permission(&req.module_id, permission, target)
             .await
-            .is_ok();
+            .unwrap_or(false);
 
         Ok(Response::new(PermissionResponse {
             granted,
@@ -150,9 +538,11 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<PermissionRequest>,
     ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Permissions, Access::Manage)?;
         let req = request.into_inner();
+        let expires_at = (req.expires_at > 0).then(|| req.expires_at);
         self.kernel
-            .grant_permission(&req.module_id, req.permission())
+            .grant_permission(&caller, &req.module_id, req.permission(), req.scope.clone(), expires_at)
             .map_err(|e| Status::internal(e.to_string()))?;
 
         Ok(Response::new(::prost::well_known_types::Empty {}))
@@ -162,9 +552,10 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<PermissionRequest>,
     ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Permissions, Access::Manage)?;
         let req = request.into_inner();
         self.kernel
-            .revoke_permission(&req.module_id, req.permission())
+            .revoke_permission(&caller, &req.module_id, req.permission())
             .map_err(|e| Status::internal(e.to_string()))?;
 
         Ok(Response::new(::prost::well_known_types::Empty {}))
@@ -172,8 +563,9 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
 
     async fn get_resources(
         &self,
-        _request: Request<::prost::well_known_types::Empty>,
+        request: Request<::prost::well_known_types::Empty>,
     ) -> Result<Response<ResourceStats>, Status> {
+        authorize(&self.kernel, &request, App::Resources, Access::Read)?;
         let stats = self
             .kernel
             .get_resources()
@@ -185,6 +577,11 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
             memory_total: stats.get("memory_total").copied().unwrap_or(0.0),
             memory_used: stats.get("memory_used").copied().unwrap_or(0.0),
             memory_percent: stats.get("memory_percent").copied().unwrap_or(0.0),
+            wasm_cache_hits: stats.get("wasm_cache_hits").copied().unwrap_or(0.0) as i64,
+            wasm_cache_disk_hits: stats.get("wasm_cache_disk_hits").copied().unwrap_or(0.0) as i64,
+            wasm_cache_misses: stats.get("wasm_cache_misses").copied().unwrap_or(0.0) as i64,
+            wasm_cache_evictions: stats.get("wasm_cache_evictions").copied().unwrap_or(0.0) as i64,
+            wasm_cache_entries: stats.get("wasm_cache_entries").copied().unwrap_or(0.0) as i64,
             timestamp: chrono::Local::now().timestamp_millis(),
         }))
     }
@@ -193,45 +590,509 @@ impl kiacha_kernel::KiachaKernel for KiachaKernelService {
         &self,
         request: Request<WasmRequest>,
     ) -> Result<Response<WasmResponse>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Wasm, Access::Write)?;
         let req = request.into_inner();
         match self
             .kernel
-            .run_wasm(&req.module_id, &req.wasm_data, req.args)
+            .run_wasm(&caller, &req.module_id, req.wasm_data, req.args, req.env)
             .await
         {
-            Ok(result) => Ok(Response::new(WasmResponse {
-                success: true,
-                result,
-                error: String::new(),
+            Ok(output) => Ok(Response::new(WasmResponse {
+                success: output.exit_code == 0,
+                result: output.result,
+                error: if output.exit_code == 0 {
+                    String::new()
+                } else {
+                    format!("exited with code {}", output.exit_code)
+                },
+                stdout: output.stdout,
+                stderr: output.stderr,
+                exit_code: output.exit_code,
             })),
             Err(e) => Ok(Response::new(WasmResponse {
                 success: false,
-                result: String::new(),
                 error: e.to_string(),
+                ..Default::default()
             })),
         }
     }
 
-    type GetAuditLogsStream = std::pin::Pin<
-        Box<dyn futures::Stream<Item = Result<::prost::wrappers::StringValue, Status>> + Send>,
-    >;
+    async fn register_wasm_module(
+        &self,
+        request: Request<WasmModuleRegistration>,
+    ) -> Result<Response<ModuleResponse>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Modules, Access::Write)?;
+        let req = request.into_inner();
+        info!("Registering WASM module: {} (by {})", req.name, caller.describe());
+
+        let module_type = req.r#type();
+        let module_id = self
+            .kernel
+            .register_wasm_module(&caller, req.name, module_type, req.wasm_data, req.config)
+            .await
+            .map_err(wasm_status)?;
+
+        Ok(Response::new(ModuleResponse {
+            module_id,
+            status: "running".to_string(),
+            created_at: chrono::Local::now().timestamp_millis(),
+            ..Default::default()
+        }))
+    }
+
+    async fn unregister_wasm_module(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Modules, Access::Write)?;
+        let module_id = request.into_inner().value;
+        self.kernel
+            .unregister_wasm_module(&caller, &module_id)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn invoke_wasm_export(
+        &self,
+        request: Request<WasmExportCall>,
+    ) -> Result<Response<WasmExportResult>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Wasm, Access::Write)?;
+        let req = request.into_inner();
+        let args = req
+            .args
+            .into_iter()
+            .map(from_proto_wasm_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        let results = self
+            .kernel
+            .invoke_wasm_export(&caller, &req.module_id, &req.export, args)
+            .await
+            .map_err(wasm_status)?;
+
+        Ok(Response::new(WasmExportResult {
+            results: results.into_iter().map(to_proto_wasm_value).collect(),
+        }))
+    }
+
+    type GetAuditLogsStream = AuditStream;
 
     async fn get_audit_logs(
         &self,
-        _request: Request<::prost::well_known_types::Empty>,
+        request: Request<AuditTailRequest>,
     ) -> Result<Response<Self::GetAuditLogsStream>, Status> {
+        authorize(&self.kernel, &request, App::Audit, Access::Read)?;
+        let req = request.into_inner();
+        let min_severity = match req.min_severity() {
+            AuditSeverity::Info => None,
+            AuditSeverity::Warning => Some(crate::security::Severity::Warning),
+            AuditSeverity::Critical => Some(crate::security::Severity::Critical),
+        };
+        let filter = crate::security::AuditFilter {
+            action: (!req.action_prefix.is_empty()).then(|| format!("{}*", req.action_prefix)),
+            module_id: Some(req.module_id),
+            min_severity,
+            since: (req.since > 0).then_some(req.since),
+            limit: (req.backlog > 0).then_some(req.backlog as usize),
+            ..Default::default()
+        };
+
+        // Subscribe before reading history so nothing written in between is lost
+        let live = req.follow.then(|| self.kernel.subscribe_audit());
+        let backlog = self
+            .kernel
+            .audit_records(filter.clone())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(audit_stream(self.kernel.clone(), filter, backlog, live)))
+    }
+
+    async fn get_detailed_audit_logs(
+        &self,
+        request: Request<AuditLogFilter>,
+    ) -> Result<Response<AuditLogList>, Status> {
+        authorize(&self.kernel, &request, App::Audit, Access::Read)?;
+        let req = request.into_inner();
+        let filter = crate::security::AuditFilter {
+            actor: Some(req.actor),
+            action: Some(req.action),
+            since: (req.since > 0).then_some(req.since),
+            until: (req.until > 0).then_some(req.until),
+            limit: (req.limit > 0).then_some(req.limit as usize),
+            ..Default::default()
+        };
         let logs = self
             .kernel
-            .get_audit_logs()
+            .audit_records(filter)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .into_iter()
+            .map(to_proto_audit_log)
+            .collect();
+
+        Ok(Response::new(AuditLogList { logs }))
+    }
+
+    async fn verify_audit_chain(
+        &self,
+        request: Request<::prost::well_known_types::Empty>,
+    ) -> Result<Response<AuditChainStatus>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Audit, Access::Read)?;
+        let report = self
+            .kernel
+            .verify_audit_chain(&caller)
             .await
             .map_err(|e| Status::internal(e.to_string()))?;
 
-        let stream = async_stream::stream! {
-            for log in logs {
-                yield Ok(::prost::wrappers::StringValue { value: log });
-            }
+        Ok(Response::new(AuditChainStatus {
+            valid: report.valid(),
+            records: report.records,
+            first_sequence: report.first_sequence.unwrap_or_default(),
+            last_sequence: report.last_sequence.unwrap_or_default(),
+            broken_at: report.broken_at.unwrap_or_default(),
+            error: report.error.unwrap_or_default(),
+        }))
+    }
+
+    async fn get_sandbox_status(
+        &self,
+        request: Request<::prost::well_known_types::Empty>,
+    ) -> Result<Response<SandboxPolicy>, Status> {
+        authorize(&self.kernel, &request, App::Security, Access::Read)?;
+        let policy = self.kernel.sandbox_policy();
+
+        Ok(Response::new(SandboxPolicy {
+            max_memory: policy.max_memory as i64,
+            max_cpu_time: policy.max_cpu_time.as_millis() as i64,
+            allowed_syscalls: policy.allowed_imports.clone(),
+            forbidden_operations: policy.forbidden.clone(),
+        }))
+    }
+
+    async fn list_users(
+        &self,
+        request: Request<::prost::well_known_types::Empty>,
+    ) -> Result<Response<UserList>, Status> {
+        authorize(&self.kernel, &request, App::Users, Access::Read)?;
+        let users = self.kernel.list_users().into_iter().map(to_proto_user).collect();
+
+        Ok(Response::new(UserList { users }))
+    }
+
+    async fn create_user(
+        &self,
+        request: Request<User>,
+    ) -> Result<Response<User>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Users, Access::Manage)?;
+        let req = request.into_inner();
+        let user = self
+            .kernel
+            .create_user(&caller, &req.username, &req.display_name, &req.role)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(to_proto_user(user)))
+    }
+
+    async fn delete_user(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Users, Access::Manage)?;
+        let user_id = request.into_inner().value;
+        self.kernel
+            .delete_user(&caller, &user_id)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<SessionList>, Status> {
+        // Users may always list their own sessions; an empty id means the caller
+        let caller = caller(&request)?;
+        let mut user_id = request.into_inner().value;
+        if let (true, Caller::User { user, .. }) = (user_id.is_empty(), &caller) {
+            user_id = user.id.clone();
+        }
+        if !matches!(&caller, Caller::User { user, .. } if user.id == user_id) {
+            self.kernel
+                .authorize(&caller, App::Users, Access::Read)
+                .map_err(|e| Status::permission_denied(e.to_string()))?;
+        }
+
+        let sessions = self
+            .kernel
+            .list_sessions(&user_id)
+            .into_iter()
+            // Tokens are only ever returned by Login
+            .map(|s| to_proto_session(s, String::new()))
+            .collect();
+
+        Ok(Response::new(SessionList { sessions }))
+    }
+
+    async fn terminate_session(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        // Users may always end their own sessions
+        let caller = caller(&request)?;
+        let session_id = request.into_inner().value;
+        let owner = self
+            .kernel
+            .session_owner(&session_id)
+            .ok_or_else(|| Status::not_found(format!("Session {} not found", session_id)))?;
+        if !matches!(&caller, Caller::User { user, .. } if user.id == owner) {
+            self.kernel
+                .authorize(&caller, App::Users, Access::Manage)
+                .map_err(|e| Status::permission_denied(e.to_string()))?;
+        }
+
+        self.kernel
+            .terminate_session(&caller, &session_id)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn get_user_permissions(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<UserPermissionList>, Status> {
+        // Users may always look up their own access
+        let caller = caller(&request)?;
+        let user_id = request.into_inner().value;
+        if !matches!(&caller, Caller::User { user, .. } if user.id == user_id) {
+            self.kernel
+                .authorize(&caller, App::Users, Access::Read)
+                .map_err(|e| Status::permission_denied(e.to_string()))?;
+        }
+
+        let app_permissions = self
+            .kernel
+            .user_permissions(&user_id)
+            .map_err(|e| Status::not_found(e.to_string()))?
+            .into_iter()
+            .map(|(app, levels)| UserPermission {
+                app: app.name(),
+                permissions: levels.iter().map(|l| l.name().to_string()).collect(),
+                user_id: user_id.clone(),
+            })
+            .collect();
+
+        Ok(Response::new(UserPermissionList {
+            user_id,
+            app_permissions,
+        }))
+    }
+
+    async fn grant_user_permission(
+        &self,
+        request: Request<UserPermission>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Users, Access::Manage)?;
+        let req = request.into_inner();
+        let (app, levels) = parse_user_permission(&req)?;
+        self.kernel
+            .grant_user_permission(&caller, &req.user_id, app, &levels)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn revoke_user_permission(
+        &self,
+        request: Request<UserPermission>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Users, Access::Manage)?;
+        let req = request.into_inner();
+        let (app, levels) = parse_user_permission(&req)?;
+        self.kernel
+            .revoke_user_permission(&caller, &req.user_id, app, &levels)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn authenticate_biometric(
+        &self,
+        request: Request<BiometricData>,
+    ) -> Result<Response<Session>, Status> {
+        let req = request.into_inner();
+        let (session, token) = self
+            .kernel
+            .authenticate_biometric(&req.username, &req.r#type, &req.data, &req.device)
+            .map_err(auth_status)?;
+
+        Ok(Response::new(to_proto_session(session, token)))
+    }
+
+    async fn enroll_biometric(
+        &self,
+        request: Request<BiometricEnrollment>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        // Users may enroll themselves; enrolling someone else is user management
+        let caller = caller(&request)?;
+        let mut req = request.into_inner();
+        if let (true, Caller::User { user, .. }) = (req.user_id.is_empty(), &caller) {
+            req.user_id = user.id.clone();
+        }
+        if !matches!(&caller, Caller::User { user, .. } if user.id == req.user_id) {
+            self.kernel
+                .authorize(&caller, App::Users, Access::Manage)
+                .map_err(|e| Status::permission_denied(e.to_string()))?;
+        }
+
+        self.kernel
+            .enroll_biometric(&caller, &req.password, &req.user_id, &req.r#type, &req.data)
+            .await
+            .map_err(auth_status)?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn login(
+        &self,
+        request: Request<LoginRequest>,
+    ) -> Result<Response<Session>, Status> {
+        let req = request.into_inner();
+        let (session, token) = self
+            .kernel
+            .login(&req.username, &req.password, &req.device)
+            .await
+            .map_err(auth_status)?;
+
+        Ok(Response::new(to_proto_session(session, token)))
+    }
+
+    async fn logout(
+        &self,
+        request: Request<::prost::well_known_types::Empty>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = caller(&request)?;
+        self.kernel
+            .logout(&caller)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn change_password(
+        &self,
+        request: Request<PasswordChange>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = caller(&request)?;
+        let req = request.into_inner();
+        self.kernel
+            .change_password(&caller, &req.old_password, &req.new_password)
+            .await
+            .map_err(auth_status)?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    async fn begin_password_reset(
+        &self,
+        request: Request<::prost::wrappers::StringValue>,
+    ) -> Result<Response<PasswordResetTicket>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Users, Access::Manage)?;
+        let user_id = request.into_inner().value;
+        let (code, expires_at) = self
+            .kernel
+            .begin_password_reset(&caller, &user_id)
+            .map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(PasswordResetTicket {
+            user_id,
+            code,
+            expires_at,
+        }))
+    }
+
+    async fn reset_password(
+        &self,
+        request: Request<PasswordReset>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let req = request.into_inner();
+        self.kernel
+            .complete_password_reset(&req.username, &req.code, &req.new_password)
+            .await
+            .map_err(auth_status)?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+}
+
+fn parse_user_permission(req: &UserPermission) -> Result<(App, Vec<Access>), Status> {
+    let app = App::parse(&req.app).map_err(|e| Status::invalid_argument(e.to_string()))?;
+    let levels = req
+        .permissions
+        .iter()
+        .map(|p| Access::parse(p))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    Ok((app, levels))
+}
+
+pub struct KiachaEventBusService {
+    kernel: Arc<KiachaKernel>,
+}
+
+impl KiachaEventBusService {
+    pub fn new(kernel: Arc<KiachaKernel>) -> Self {
+        KiachaEventBusService { kernel }
+    }
+}
+
+#[tonic::async_trait]
+impl kiacha_event_bus_server::KiachaEventBus for KiachaEventBusService {
+    async fn publish(
+        &self,
+        request: Request<Event>,
+    ) -> Result<Response<::prost::well_known_types::Empty>, Status> {
+        let caller = authorize(&self.kernel, &request, App::Events, Access::Write)?;
+        let event = request.into_inner();
+
+        self.kernel
+            .publish_event(
+                &caller,
+                crate::event_bus::Event {
+                    event_type: event.event_type,
+                    source: event.source,
+                    payload: event.payload,
+                    timestamp: event.timestamp,
+                    offset: 0,
+                },
+            )
+            .await
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+
+        Ok(Response::new(::prost::well_known_types::Empty {}))
+    }
+
+    type SubscribeStream = EventStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        authorize(&self.kernel, &request, App::Events, Access::Read)?;
+        let req = request.into_inner();
+        if req.from_offset.is_none() && req.from_timestamp.is_none() {
+            let subscription = self.kernel.subscribe_to_events(&req.pattern);
+            return Ok(Response::new(event_stream(subscription, None)));
+        }
+
+        let cursor = JournalCursor {
+            from_offset: req.from_offset,
+            from_timestamp: req.from_timestamp,
         };
+        let (backlog, subscription) = self.kernel.subscribe_to_events_from(&req.pattern, cursor);
 
-        Ok(Response::new(Box::pin(stream)))
+        Ok(Response::new(event_stream(subscription, Some(backlog))))
     }
 }
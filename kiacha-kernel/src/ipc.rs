@@ -1,4 +1,57 @@
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Encoding of `IpcMessage::data`. `Text` messages only use the legacy `payload` string.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ContentType {
+    Text,
+    Json,
+    ProtobufAny,
+    Raw,
+    MsgPack,
+}
+
+impl ContentType {
+    /// Largest accepted body for each content type
+    pub fn max_size(&self) -> usize {
+        match self {
+            ContentType::Text => 1024 * 1024,
+            ContentType::Json => 1024 * 1024,
+            ContentType::ProtobufAny => 2 * 1024 * 1024,
+            ContentType::MsgPack => 2 * 1024 * 1024,
+            ContentType::Raw => 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl From<crate::proto::ContentType> for ContentType {
+    fn from(content_type: crate::proto::ContentType) -> Self {
+        match content_type {
+            crate::proto::ContentType::Unspecified => ContentType::Text,
+            crate::proto::ContentType::Json => ContentType::Json,
+            crate::proto::ContentType::ProtobufAny => ContentType::ProtobufAny,
+            crate::proto::ContentType::Raw => ContentType::Raw,
+            crate::proto::ContentType::Msgpack => ContentType::MsgPack,
+        }
+    }
+}
+
+impl From<ContentType> for crate::proto::ContentType {
+    fn from(content_type: ContentType) -> Self {
+        match content_type {
+            ContentType::Text => crate::proto::ContentType::Unspecified,
+            ContentType::Json => crate::proto::ContentType::Json,
+            ContentType::ProtobufAny => crate::proto::ContentType::ProtobufAny,
+            ContentType::Raw => crate::proto::ContentType::Raw,
+            ContentType::MsgPack => crate::proto::ContentType::Msgpack,
+        }
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct IpcMessage {
@@ -6,6 +59,13 @@ pub struct IpcMessage {
     pub to: String,
     pub payload: String,
     pub timestamp: u64,
+    /// Binary body, interpreted according to `content_type`
+    pub data: Vec<u8>,
+    pub content_type: ContentType,
+    /// Shared by a request and its reply; empty for fire-and-forget messages
+    pub correlation_id: String,
+    /// Set on requests to the module expecting the reply; empty on replies
+    pub reply_to: String,
 }
 
 impl IpcMessage {
@@ -18,25 +78,386 @@ impl IpcMessage {
                 .duration_since(std::time::UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_millis() as u64,
+            data: Vec::new(),
+            content_type: ContentType::Text,
+            correlation_id: String::new(),
+            reply_to: String::new(),
+        }
+    }
+
+    /// Check the body against its declared content type and size limit
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.payload.len() > ContentType::Text.max_size() {
+            return Err(anyhow::anyhow!(
+                "IPC payload of {} bytes exceeds the {} byte limit",
+                self.payload.len(),
+                ContentType::Text.max_size()
+            ));
+        }
+        if self.data.len() > self.content_type.max_size() {
+            return Err(anyhow::anyhow!(
+                "IPC {:?} data of {} bytes exceeds the {} byte limit",
+                self.content_type,
+                self.data.len(),
+                self.content_type.max_size()
+            ));
+        }
+
+        match self.content_type {
+            ContentType::Text if !self.data.is_empty() => {
+                return Err(anyhow::anyhow!("IPC data requires a content type"));
+            }
+            ContentType::Text | ContentType::Raw => {}
+            ContentType::Json => {
+                serde_json::from_slice::<serde::de::IgnoredAny>(&self.data)
+                    .map_err(|e| anyhow::anyhow!("Invalid JSON IPC data: {}", e))?;
+            }
+            ContentType::ProtobufAny => {
+                let any = <prost_types::Any as prost::Message>::decode(self.data.as_slice())
+                    .map_err(|e| anyhow::anyhow!("Invalid protobuf Any IPC data: {}", e))?;
+                if any.type_url.is_empty() {
+                    return Err(anyhow::anyhow!("Protobuf Any IPC data has no type_url"));
+                }
+            }
+            ContentType::MsgPack => {
+                let mut reader = self.data.as_slice();
+                rmpv::decode::read_value(&mut reader)
+                    .map_err(|e| anyhow::anyhow!("Invalid MessagePack IPC data: {}", e))?;
+                if !reader.is_empty() {
+                    return Err(anyhow::anyhow!("MessagePack IPC data has trailing bytes"));
+                }
+            }
         }
+        Ok(())
+    }
+
+    /// A reply carries the correlation id of the request it answers and no `reply_to`
+    pub fn is_reply(&self) -> bool {
+        !self.correlation_id.is_empty() && self.reply_to.is_empty()
     }
 }
 
+#[derive(Debug, thiserror::Error)]
+pub enum IpcError {
+    #[error("IPC call to {0} timed out after {1:?}")]
+    Timeout(String, Duration),
+    #[error("Mailbox of {0} is full")]
+    QueueFull(String),
+}
+
+/// A `CallIpc` waiting for its reply in the kernel's pending-request table.
+pub struct PendingCall {
+    /// Module expected to answer; replies from anyone else are delivered normally
+    pub responder: String,
+    pub deadline: Instant,
+    pub reply: tokio::sync::oneshot::Sender<IpcMessage>,
+}
+
+impl From<crate::proto::IpcMessage> for IpcMessage {
+    fn from(msg: crate::proto::IpcMessage) -> Self {
+        IpcMessage {
+            from: msg.from,
+            to: msg.to,
+            payload: msg.payload,
+            timestamp: msg.timestamp.max(0) as u64,
+            content_type: msg.content_type().into(),
+            data: msg.data,
+            correlation_id: msg.correlation_id,
+            reply_to: msg.reply_to,
+        }
+    }
+}
+
+impl From<IpcMessage> for crate::proto::IpcMessage {
+    fn from(msg: IpcMessage) -> Self {
+        crate::proto::IpcMessage {
+            from: msg.from,
+            to: msg.to,
+            payload: msg.payload,
+            timestamp: msg.timestamp as i64,
+            data: msg.data,
+            content_type: crate::proto::ContentType::from(msg.content_type) as i32,
+            correlation_id: msg.correlation_id,
+            reply_to: msg.reply_to,
+        }
+    }
+}
+
+/// What a mailbox does with a message once it is full.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait up to `block_timeout` for the reader to make room
+    Block,
+    /// Evict the oldest queued message to make room
+    DropOldest,
+    /// Refuse the new message immediately
+    Reject,
+}
+
+/// Per-module mailbox settings.
+///
+/// Recognised `ModuleRequest.config` keys: `ipc_queue_depth`, `ipc_overflow`
+/// (`block`, `drop-oldest`, `reject`) and `ipc_block_timeout_ms`.
+#[derive(Clone, Debug)]
+pub struct MailboxConfig {
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+    pub block_timeout: Duration,
+}
+
+impl MailboxConfig {
+    pub fn from_config(config: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let capacity = match config.get("ipc_queue_depth") {
+            Some(raw) => raw
+                .parse::<usize>()
+                .ok()
+                .filter(|depth| *depth > 0)
+                .ok_or_else(|| anyhow::anyhow!("Invalid ipc_queue_depth: {}", raw))?,
+            None => 256,
+        };
+        let overflow = match config.get("ipc_overflow").map(|s| s.as_str()) {
+            None | Some("block") => OverflowPolicy::Block,
+            Some("drop-oldest") => OverflowPolicy::DropOldest,
+            Some("reject") => OverflowPolicy::Reject,
+            Some(other) => return Err(anyhow::anyhow!("Unknown ipc_overflow policy: {}", other)),
+        };
+        let block_timeout = match config.get("ipc_block_timeout_ms") {
+            Some(raw) => Duration::from_millis(
+                raw.parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid ipc_block_timeout_ms: {}", raw))?,
+            ),
+            None => Duration::from_secs(1),
+        };
+
+        Ok(MailboxConfig {
+            capacity,
+            overflow,
+            block_timeout,
+        })
+    }
+}
+
+/// A bounded module mailbox. Senders go through `send`, which applies the
+/// mailbox's overflow policy; a single reader drains it through `MailboxReader`.
+/// While paused, messages are accepted but not handed to the reader.
 pub struct IpcChannel {
-    sender: tokio::sync::mpsc::Sender<IpcMessage>,
-    receiver: tokio::sync::mpsc::Receiver<IpcMessage>,
+    config: MailboxConfig,
+    queue: Mutex<VecDeque<IpcMessage>>,
+    paused: AtomicBool,
+    readable: Notify,
+    writable: Notify,
+    reader: Arc<tokio::sync::Mutex<()>>,
 }
 
 impl IpcChannel {
-    pub fn new(capacity: usize) -> (Self, tokio::sync::mpsc::Sender<IpcMessage>, tokio::sync::mpsc::Receiver<IpcMessage>) {
-        let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
-        (
-            IpcChannel {
-                sender: sender.clone(),
-                receiver,
-            },
-            sender,
-            receiver,
-        )
+    pub fn new(config: MailboxConfig) -> Self {
+        IpcChannel {
+            config,
+            queue: Mutex::new(VecDeque::new()),
+            paused: AtomicBool::new(false),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            reader: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    pub fn config(&self) -> &MailboxConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().len()
+    }
+
+    /// Take every queued message out of the mailbox
+    pub fn drain(&self) -> Vec<IpcMessage> {
+        let drained: Vec<IpcMessage> = self.queue.lock().drain(..).collect();
+        self.writable.notify_waiters();
+        drained
+    }
+
+    /// Hold messages back from the reader until unpaused
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        if !paused {
+            self.readable.notify_one();
+        }
+    }
+
+    /// Queue a message. Returns the message evicted under `DropOldest`, if any.
+    pub async fn send(&self, message: IpcMessage) -> Result<Option<IpcMessage>, (IpcError, IpcMessage)> {
+        let deadline = tokio::time::Instant::now() + self.config.block_timeout;
+        loop {
+            {
+                let mut queue = self.queue.lock();
+                if queue.len() < self.config.capacity {
+                    queue.push_back(message);
+                    self.readable.notify_one();
+                    return Ok(None);
+                }
+                match self.config.overflow {
+                    OverflowPolicy::DropOldest => {
+                        let evicted = queue.pop_front();
+                        queue.push_back(message);
+                        self.readable.notify_one();
+                        return Ok(evicted);
+                    }
+                    OverflowPolicy::Reject => {
+                        return Err((IpcError::QueueFull(message.to.clone()), message));
+                    }
+                    OverflowPolicy::Block => {}
+                }
+            }
+
+            if tokio::time::timeout_at(deadline, self.writable.notified()).await.is_err() {
+                return Err((IpcError::QueueFull(message.to.clone()), message));
+            }
+        }
+    }
+
+    /// Claim the read side of the mailbox; fails if another reader holds it
+    pub fn reader(self: &Arc<Self>) -> Option<MailboxReader> {
+        let guard = self.reader.clone().try_lock_owned().ok()?;
+        Some(MailboxReader {
+            channel: self.clone(),
+            _guard: guard,
+        })
+    }
+}
+
+/// Exclusive read access to a mailbox, released on drop.
+pub struct MailboxReader {
+    channel: Arc<IpcChannel>,
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl MailboxReader {
+    /// Wait for the next message in the mailbox
+    pub async fn recv(&mut self) -> IpcMessage {
+        loop {
+            if !self.channel.paused.load(Ordering::SeqCst) {
+                if let Some(message) = self.channel.queue.lock().pop_front() {
+                    self.channel.writable.notify_one();
+                    return message;
+                }
+            }
+            self.channel.readable.notified().await;
+        }
+    }
+}
+
+/// A message the kernel could not deliver.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub id: String,
+    pub message: IpcMessage,
+    pub reason: String,
+    pub failed_at: i64,
+}
+
+/// Bounded store of undeliverable messages, oldest evicted first.
+pub struct DeadLetterStore {
+    capacity: usize,
+    letters: Mutex<VecDeque<DeadLetter>>,
+}
+
+impl DeadLetterStore {
+    pub fn new(capacity: usize) -> Self {
+        DeadLetterStore {
+            capacity,
+            letters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn push(&self, message: IpcMessage, reason: &str) {
+        let mut letters = self.letters.lock();
+        if letters.len() >= self.capacity {
+            letters.pop_front();
+        }
+        letters.push_back(DeadLetter {
+            id: uuid::Uuid::new_v4().to_string(),
+            message,
+            reason: reason.to_string(),
+            failed_at: chrono::Local::now().timestamp_millis(),
+        });
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.letters.lock().iter().cloned().collect()
+    }
+
+    /// Remove a dead letter so it can be redelivered
+    pub fn take(&self, id: &str) -> Option<DeadLetter> {
+        let mut letters = self.letters.lock();
+        let index = letters.iter().position(|l| l.id == id)?;
+        letters.remove(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailbox(capacity: usize, overflow: OverflowPolicy) -> Arc<IpcChannel> {
+        Arc::new(IpcChannel::new(MailboxConfig {
+            capacity,
+            overflow,
+            block_timeout: Duration::from_millis(20),
+        }))
+    }
+
+    fn message(payload: &str) -> IpcMessage {
+        IpcMessage::new("a".to_string(), "b".to_string(), payload.to_string())
+    }
+
+    #[tokio::test]
+    async fn full_mailbox_applies_its_overflow_policy() {
+        let reject = mailbox(1, OverflowPolicy::Reject);
+        reject.send(message("1")).await.unwrap();
+        let (err, refused) = reject.send(message("2")).await.unwrap_err();
+        assert!(matches!(err, IpcError::QueueFull(_)));
+        assert_eq!(refused.payload, "2");
+
+        let drop_oldest = mailbox(1, OverflowPolicy::DropOldest);
+        drop_oldest.send(message("1")).await.unwrap();
+        let evicted = drop_oldest.send(message("2")).await.unwrap();
+        assert_eq!(evicted.map(|m| m.payload).as_deref(), Some("1"));
+        assert_eq!(drop_oldest.reader().unwrap().recv().await.payload, "2");
+
+        let block = mailbox(1, OverflowPolicy::Block);
+        block.send(message("1")).await.unwrap();
+        assert!(block.send(message("2")).await.is_err());
+        let mut reader = block.reader().unwrap();
+        let (sent, received) = tokio::join!(block.send(message("3")), reader.recv());
+        assert!(sent.unwrap().is_none());
+        assert_eq!(received.payload, "1");
+    }
+
+    #[tokio::test]
+    async fn paused_mailbox_holds_messages_back() {
+        let channel = mailbox(4, OverflowPolicy::Reject);
+        let mut reader = channel.reader().unwrap();
+        assert!(channel.reader().is_none());
+
+        channel.set_paused(true);
+        channel.send(message("held")).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(20), reader.recv()).await.is_err());
+        channel.set_paused(false);
+        assert_eq!(reader.recv().await.payload, "held");
+    }
+
+    #[test]
+    fn dead_letters_evict_oldest_and_can_be_taken() {
+        let store = DeadLetterStore::new(2);
+        for payload in ["1", "2", "3"] {
+            store.push(message(payload), "test");
+        }
+        let letters = store.list();
+        assert_eq!(letters.iter().map(|l| l.message.payload.as_str()).collect::<Vec<_>>(), ["2", "3"]);
+
+        let taken = store.take(&letters[0].id).unwrap();
+        assert_eq!(taken.reason, "test");
+        assert!(store.take(&letters[0].id).is_none());
+        assert_eq!(store.list().len(), 1);
     }
 }
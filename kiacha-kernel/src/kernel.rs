@@ -3,15 +3,87 @@ use std::sync::Arc;
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use crate::ipc::IpcMessage;
-use crate::permissions::{PermissionManager, Permission as PermPerm};
+use crate::ipc::{DeadLetter, DeadLetterStore, IpcChannel, IpcError, IpcMessage, MailboxConfig, MailboxReader, PendingCall};
+use crate::permissions::{self, Grant, PermissionManager, Permission as PermPerm};
+use sha2::{Digest, Sha256};
 use crate::resources::ResourceMonitor;
-use crate::wasm_runtime::WasmRuntime;
-use crate::security::SecurityAudit;
-use crate::event_bus::{EventBus, Event};
+use crate::wasm_cache::CacheSettings;
+use crate::wasm_runtime::{
+    self, SandboxPolicy, WasmControl, WasmInstance, WasmInvocation, WasmOutput, WasmRuntime, WasmValue,
+};
+use crate::security::{AuditFilter, AuditRecord, AuditRetention, ChainReport, SecurityAudit, KERNEL_ACTOR};
+use crate::event_bus::{EventBus, Event, SubscriberStats, Subscription};
+use crate::event_journal::{EventJournal, JournalCursor, RetentionPolicy};
+use crate::config::KernelConfig;
+use crate::module_host::{self, ModuleRuntime, ModuleSpec};
+use crate::supervisor::{ModuleSupervisor, RestartConfig};
+use crate::rbac::{Access, AccessControl, AccessDenied, App, AppAccess, Caller, Role};
+use crate::biometric::Biometrics;
+use crate::user_manager::{Session, User, UserManager};
 use crate::proto::ModuleType;
 use std::net::SocketAddr;
-use tracing::info;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use nix::sys::signal::Signal;
+
+/// Key the permission policy is signed with: `KIACHA_POLICY_KEY`, or a key file
+/// generated on first start
+pub fn policy_signing_key(config: &KernelConfig) -> anyhow::Result<Vec<u8>> {
+    match &config.policy_key {
+        Some(key) => Ok(hex::decode(key.trim())?),
+        None => permissions::load_or_create_key(&config.policy_key_file()),
+    }
+}
+
+/// Key the audit chain and its anchors are signed with: `KIACHA_AUDIT_KEY`,
+/// or a key file outside the data dir generated on first start. Without
+/// either the key is kept next to the log it signs.
+fn audit_signing_key(config: &KernelConfig) -> anyhow::Result<Vec<u8>> {
+    if let Some(key) = &config.audit_key {
+        return Ok(hex::decode(key.trim())?);
+    }
+    if let Some(path) = &config.audit_key_file {
+        return load_external_key(config, "KIACHA_AUDIT_KEY_FILE", path);
+    }
+    let path = config.default_audit_key_file();
+    warn!(
+        "Neither KIACHA_AUDIT_KEY nor KIACHA_AUDIT_KEY_FILE is set: the audit key is kept in {:?}, \
+         so anyone who can write the data dir can rewrite the audit log undetected",
+        path
+    );
+    permissions::load_or_create_key(&path)
+}
+
+/// Key biometric templates are encrypted with: `KIACHA_BIOMETRIC_KEY`, or a key
+/// file outside the data dir generated on first start
+fn biometric_key(config: &KernelConfig) -> anyhow::Result<Option<Vec<u8>>> {
+    if let Some(key) = &config.biometric_key {
+        return Ok(Some(hex::decode(key.trim())?));
+    }
+    let Some(path) = &config.biometric_key_file else { return Ok(None) };
+    Ok(Some(load_external_key(config, "KIACHA_BIOMETRIC_KEY_FILE", path)?))
+}
+
+/// Load or create the key file named by `var`, refusing one inside the data
+/// dir so that a copy of that dir does not carry its key along
+fn load_external_key(config: &KernelConfig, var: &str, path: &std::path::Path) -> anyhow::Result<Vec<u8>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    std::fs::create_dir_all(dir)?;
+    std::fs::create_dir_all(&config.data_dir)?;
+    if std::fs::canonicalize(dir)?.starts_with(std::fs::canonicalize(&config.data_dir)?) {
+        return Err(anyhow::anyhow!("{} {:?} must be kept outside the data dir", var, path));
+    }
+    permissions::load_or_create_key(path)
+}
+
+/// Number of undeliverable messages kept for inspection and replay
+const DEAD_LETTER_CAPACITY: usize = 1000;
+/// Bounds for how long `call_ipc` waits for a reply
+const DEFAULT_CALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const MAX_CALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ModuleInfo {
@@ -19,6 +91,11 @@ pub struct ModuleInfo {
     pub name: String,
     pub module_type: ModuleType,
     pub status: ModuleStatus,
+    pub runtime: ModuleRuntime,
+    pub pid: Option<u32>,
+    pub last_exit: Option<String>,
+    pub restarts: u32,
+    pub created_at: i64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -32,6 +109,21 @@ pub enum ModuleType {
     Custom(String),
 }
 
+impl ModuleType {
+    /// Key of this module type in the permission policy
+    pub fn policy_key(&self) -> &str {
+        match self {
+            ModuleType::Brain => "brain",
+            ModuleType::Interface => "interface",
+            ModuleType::Vision => "vision",
+            ModuleType::Audio => "audio",
+            ModuleType::Memory => "memory",
+            ModuleType::Reasoning => "reasoning",
+            ModuleType::Custom(_) => "custom",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ModuleStatus {
     Idle,
@@ -40,183 +132,1475 @@ pub enum ModuleStatus {
     Failed,
 }
 
+/// Cheap to clone: every part is shared
+#[derive(Clone)]
 pub struct KiachaKernel {
     modules: Arc<DashMap<String, ModuleInfo>>,
-    ipc_channels: Arc<DashMap<String, tokio::sync::mpsc::Sender<IpcMessage>>>,
+    ipc_channels: Arc<DashMap<String, Arc<IpcChannel>>>,
+    dead_letters: Arc<DeadLetterStore>,
+    /// Credential token -> module id, issued at spawn
+    module_credentials: Arc<DashMap<String, String>>,
+    pending_calls: Arc<DashMap<String, PendingCall>>,
+    wasm_controls: Arc<DashMap<String, Arc<WasmControl>>>,
+    /// Registered WASM modules that keep their state between calls
+    wasm_instances: Arc<DashMap<String, Arc<parking_lot::Mutex<WasmInstance>>>>,
+    /// Tasks handing mailbox messages to registered modules
+    wasm_pumps: Arc<DashMap<String, tokio::task::JoinHandle<()>>>,
     permissions: Arc<PermissionManager>,
     resources: Arc<ResourceMonitor>,
     wasm_runtime: Arc<WasmRuntime>,
     security_audit: Arc<SecurityAudit>,
     event_bus: Arc<EventBus>,
+    supervisor: Arc<ModuleSupervisor>,
+    users: Arc<UserManager>,
+    access: Arc<AccessControl>,
+    /// `None` when no biometric key is configured
+    biometrics: Option<Arc<Biometrics>>,
+    /// Executables any user with Modules write access may spawn
+    module_executables: Arc<Vec<std::path::PathBuf>>,
 }
 
 impl KiachaKernel {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(config: KernelConfig) -> anyhow::Result<Self> {
+        let modules = Arc::new(DashMap::new());
+        let ipc_channels = Arc::new(DashMap::new());
+        let dead_letters = Arc::new(DeadLetterStore::new(DEAD_LETTER_CAPACITY));
+        let module_credentials = Arc::new(DashMap::new());
+        let wasm_controls = Arc::new(DashMap::new());
+        let wasm_cache = CacheSettings {
+            capacity: config.wasm_cache_entries,
+            dir: config.wasm_cache_on_disk.then(|| config.wasm_cache_dir()),
+            max_disk_bytes: config.wasm_cache_disk_bytes,
+        };
+        let wasm_runtime = Arc::new(WasmRuntime::new(config.sandbox.clone(), wasm_cache)?);
+        let security_audit = Arc::new(SecurityAudit::open(
+            &config.audit_dir(),
+            AuditRetention {
+                segment_bytes: config.audit_segment_bytes,
+                max_segments: config.audit_max_segments,
+            },
+            &audit_signing_key(&config)?,
+        )?);
+        let journal = EventJournal::open(
+            &config.event_journal_dir(),
+            config.event_segment_bytes,
+            RetentionPolicy {
+                max_age: config.event_retention_age,
+                max_bytes: config.event_retention_bytes,
+            },
+        )?;
+        let event_bus = Arc::new(
+            EventBus::with_journal(Arc::new(journal))
+                .with_buffer_sizes(config.event_buffer_default, config.event_buffer_sizes.clone()),
+        );
+        let permissions = Arc::new(PermissionManager::load(
+            &config.policy_file(),
+            &policy_signing_key(&config)?,
+        )?);
+        let supervisor = Arc::new(ModuleSupervisor::new(
+            modules.clone(),
+            ipc_channels.clone(),
+            dead_letters.clone(),
+            module_credentials.clone(),
+            permissions.clone(),
+            wasm_controls.clone(),
+            wasm_runtime.clone(),
+            security_audit.clone(),
+            event_bus.clone(),
+        ));
+
+        let access = Arc::new(AccessControl::load(&config.access_policy_file())?);
+        let biometrics = match biometric_key(&config)? {
+            Some(key) => Some(Arc::new(Biometrics::load(
+                &config.biometrics_file(),
+                &key,
+                &config.biometric_thresholds,
+            )?)),
+            None => {
+                warn!("Biometric login disabled: set KIACHA_BIOMETRIC_KEY or KIACHA_BIOMETRIC_KEY_FILE");
+                None
+            }
+        };
+
         let kernel = KiachaKernel {
-            modules: Arc::new(DashMap::new()),
-            ipc_channels: Arc::new(DashMap::new()),
-            permissions: Arc::new(PermissionManager::new()),
+            modules,
+            ipc_channels,
+            dead_letters,
+            module_credentials,
+            pending_calls: Arc::new(DashMap::new()),
+            wasm_controls,
+            wasm_instances: Arc::new(DashMap::new()),
+            wasm_pumps: Arc::new(DashMap::new()),
+            permissions,
             resources: Arc::new(ResourceMonitor::new()),
-            wasm_runtime: Arc::new(WasmRuntime::new()?),
-            security_audit: Arc::new(SecurityAudit::new()),
-            event_bus: Arc::new(EventBus::new()),
+            wasm_runtime,
+            security_audit,
+            event_bus,
+            supervisor,
+            users: Arc::new(UserManager::open(&config.users_dir(), config.auth.clone())?),
+            access,
+            biometrics,
+            module_executables: Arc::new(config.module_executables.clone()),
         };
 
-        kernel.security_audit.log("kernel_started", "Kiacha Kernel initialized");
+        kernel.bootstrap_admin(&config)?;
+        kernel.expire_grants();
+        kernel.expire_sessions();
+        kernel.expire_events();
+        kernel
+            .security_audit
+            .record(KERNEL_ACTOR, "kernel_started", "", true, "Kiacha Kernel initialized");
         Ok(kernel)
     }
 
     /// Spawn a new module within the kernel
-    pub async fn spawn(&self, name: String, module_type: crate::proto::ModuleType) -> anyhow::Result<String> {
+    ///
+    /// The module is launched as a child process or a WASM instance according to
+    /// `config` (see `ModuleSpec::from_config`) and handed to the supervisor, which
+    /// restarts it per its `RestartConfig`. External modules are only registered;
+    /// their credential is returned so they can authenticate as the module.
+    pub async fn spawn(
+        &self,
+        caller: &Caller,
+        name: String,
+        module_type: crate::proto::ModuleType,
+        config: HashMap<String, String>,
+    ) -> anyhow::Result<(String, Option<String>)> {
         let module_id = Uuid::new_v4().to_string();
         
-        let converted_type = match module_type {
-            crate::proto::ModuleType::ModuleBrain => ModuleType::Brain,
-            crate::proto::ModuleType::ModuleInterface => ModuleType::Interface,
-            crate::proto::ModuleType::ModuleVision => ModuleType::Vision,
-            crate::proto::ModuleType::ModuleAudio => ModuleType::Audio,
-            crate::proto::ModuleType::ModuleMemory => ModuleType::Memory,
-            crate::proto::ModuleType::ModuleReasoning => ModuleType::Reasoning,
-            crate::proto::ModuleType::ModuleCustom => ModuleType::Custom(name.clone()),
-            _ => ModuleType::Custom(name.clone()),
+        let converted_type = to_kernel_module_type(module_type, &name);
+
+        let mut spec = ModuleSpec::from_config(&config)?;
+        // Process modules run as the kernel's own user
+        if spec.runtime == ModuleRuntime::Process && !self.executable_allowed(&spec.command) {
+            self.authorize(caller, App::Modules, Access::Manage).map_err(|_| {
+                AccessDenied(format!(
+                    "{} is not in KIACHA_MODULE_EXECUTABLES; only admins may run other commands",
+                    spec.command
+                ))
+            })?;
+        }
+        let restart = RestartConfig::from_config(&config)?;
+        let mailbox = MailboxConfig::from_config(&config)?;
+        let manifest = match config.get("permissions") {
+            Some(raw) => Grant::parse_manifest(raw)?,
+            None => Vec::new(),
         };
 
+        // Credential the module presents to authenticated kernel services
+        let credential = Uuid::new_v4().simple().to_string();
+        spec.env.insert("KIACHA_MODULE_TOKEN".to_string(), credential.clone());
+
+        // Default deny: only manifest permissions the policy allows for this type
+        self.check_module_name(caller, &name)?;
+        let denied = self
+            .permissions
+            .apply_manifest(&module_id, &name, converted_type.policy_key(), &manifest)?;
+        if !denied.is_empty() {
+            self.security_audit.record(
+                &caller.describe(),
+                "permission_manifest",
+                &module_id,
+                false,
+                &format!("denied for {}: {:?}", converted_type.policy_key(), denied),
+            );
+        }
+
+        // Everything the module may use as soon as it starts is registered up front
+        self.module_credentials.insert(credential.clone(), module_id.clone());
+        self.ipc_channels.insert(module_id.clone(), Arc::new(IpcChannel::new(mailbox)));
+
+        let running = match spec.runtime {
+            ModuleRuntime::External => None,
+            _ => match module_host::launch(&module_id, &spec, self.wasm_runtime.clone()).await {
+                Ok(running) => Some(running),
+                Err(e) => {
+                    self.permissions.clear_module(&module_id);
+                    self.module_credentials.remove(&credential);
+                    self.ipc_channels.remove(&module_id);
+                    return Err(e);
+                }
+            },
+        };
+        let pid = running.as_ref().and_then(|r| r.pid());
+
         let module_info = ModuleInfo {
             id: module_id.clone(),
             name: name.clone(),
             module_type: converted_type.clone(),
             status: ModuleStatus::Running,
+            runtime: spec.runtime.clone(),
+            pid,
+            last_exit: None,
+            restarts: 0,
+            created_at: chrono::Local::now().timestamp_millis(),
         };
 
         self.modules.insert(module_id.clone(), module_info);
+        if let Some(running) = running {
+            self.supervisor.attach(&module_id, &running);
+            self.supervisor.supervise(module_id.clone(), spec.clone(), restart, running);
+        }
         
         // Publish spawn event
-        let event = Event {
-            event_type: "module_spawned".to_string(),
-            source: "kernel".to_string(),
-            payload: serde_json::to_vec(&serde_json::json!({
-                "module_id": &module_id,
-                "name": &name
-            }))?,
-            timestamp: chrono::Local::now().timestamp_millis(),
-        };
-        self.event_bus.publish(event).await?;
+        let event = Event::json("module.spawned", "kernel", serde_json::json!({
+            "module_id": &module_id,
+            "name": &name,
+            "runtime": &spec.runtime,
+            "pid": pid,
+        }));
+        // The module is running either way; a journal failure must not hide that
+        if let Err(e) = self.event_bus.publish(event).await {
+            warn!("Failed to publish module.spawned for {}: {}", module_id, e);
+        }
         
-        self.security_audit.log(&format!("module_spawn:{:?}", &converted_type), &name);
+        self.security_audit.record(
+            &caller.describe(),
+            "module_spawn",
+            &module_id,
+            true,
+            &format!("{} ({:?})", name, converted_type),
+        );
         
         info!("✓ Spawned module: {} ({})", name, module_id);
-        Ok(module_id)
+        let credential = (spec.runtime == ModuleRuntime::External).then_some(credential);
+        Ok((module_id, credential))
+    }
+
+    /// Persisted grants follow a module's name, so only callers who manage
+    /// permissions may start a module under a name that has some
+    fn check_module_name(&self, caller: &Caller, name: &str) -> anyhow::Result<()> {
+        if !self.permissions.has_saved_grants(name) {
+            return Ok(());
+        }
+        self.authorize(caller, App::Permissions, Access::Manage).map_err(|_| {
+            AccessDenied(format!(
+                "Module name {} has saved grants; only callers who manage permissions may use it",
+                name
+            ))
+            .into()
+        })
+    }
+
+        /// Whether `command` is an absolute path to an allow-listed executable
+    fn executable_allowed(&self, command: &str) -> bool {
+        if !std::path::Path::new(command).is_absolute() {
+            return false;
+        }
+        let Ok(command) = std::fs::canonicalize(command) else { return false };
+        self.module_executables
+            .iter()
+            .any(|allowed| std::fs::canonicalize(allowed).map_or(false, |allowed| allowed == command))
+    }
+
+    /// Periodically drop expired grants from the persisted policy
+    fn expire_grants(&self) {
+        let permissions = self.permissions.clone();
+        let security_audit = self.security_audit.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                match permissions.purge_expired() {
+                    Ok(expired) => {
+                        for (module_id, grant) in expired {
+                            security_audit.record(
+                                KERNEL_ACTOR,
+                                "permission_expired",
+                                &module_id,
+                                true,
+                                &format!("{:?}", grant),
+                            );
+                        }
+                    }
+                    Err(e) => warn!("Failed to purge expired grants: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Periodically apply the event journal's retention, which otherwise only
+    /// runs when a segment fills up
+    fn expire_events(&self) {
+        let Some(journal) = self.event_bus.journal() else { return };
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let journal = journal.clone();
+                match tokio::task::spawn_blocking(move || journal.apply_retention()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Failed to apply event journal retention: {}", e),
+                    Err(e) => warn!("Event journal retention task failed: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Create the `admin` user when there are no users yet, so someone can log in
+    fn bootstrap_admin(&self, config: &KernelConfig) -> anyhow::Result<()> {
+        if !self.users.list_users().is_empty() {
+            return Ok(());
+        }
+
+        let password = match &config.admin_password {
+            Some(password) => password.clone(),
+            None => {
+                let password = hex::encode(rand::random::<[u8; 12]>());
+                let path = config.admin_password_file();
+                std::fs::create_dir_all(&config.data_dir)?;
+                let mut file = crate::user_store::open_private(
+                    std::fs::OpenOptions::new().write(true).create(true).truncate(true),
+                    &path,
+                )?;
+                std::io::Write::write_all(&mut file, password.as_bytes())?;
+                file.sync_all()?;
+                warn!("Created user admin; its password is in {:?}", path);
+                password
+            }
+        };
+
+        let admin = self.users.create_user(User {
+            id: Uuid::new_v4().to_string(),
+            username: "admin".to_string(),
+            display_name: "Administrator".to_string(),
+            role: "admin".to_string(),
+            active: true,
+            created_at: chrono::Local::now().timestamp_millis(),
+        })?;
+        self.users.set_password(&admin.id, &password)?;
+        self.security_audit
+            .record(KERNEL_ACTOR, "user_bootstrap", &admin.id, true, &admin.username);
+        Ok(())
+    }
+
+    /// Periodically end user sessions that have been idle too long
+    fn expire_sessions(&self) {
+        let users = self.users.clone();
+        let security_audit = self.security_audit.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                for session in users.expire_idle_sessions() {
+                    security_audit.record(
+                        KERNEL_ACTOR,
+                        "session_expired",
+                        &session.session_id,
+                        true,
+                        &format!("user {}", session.user_id),
+                    );
+                }
+            }
+        });
+    }
+
+    /// Check a user's password and open a session
+    ///
+    /// Returns the session and the bearer token to authenticate later calls with.
+    pub async fn login(&self, username: &str, password: &str, device: &str) -> anyhow::Result<(Session, String)> {
+        let users = self.users.clone();
+        let (name, password, device) = (username.to_string(), password.to_string(), device.to_string());
+        // Password hashing is deliberately slow; keep it off the async workers
+        let result = tokio::task::spawn_blocking(move || users.login(&name, &password, &device)).await?;
+
+        match result {
+            Ok((session, token)) => {
+                self.security_audit
+                    .record(&format!("user:{}", username), "login", username, true, &session.device);
+                Ok((session, token))
+            }
+            Err(e) => {
+                self.security_audit
+                    .record(&format!("user:{}", username), "login", username, false, &e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// End the caller's own session
+    pub fn logout(&self, caller: &Caller) -> anyhow::Result<()> {
+        match caller {
+            Caller::User { session_id, .. } => {
+                self.users.terminate_session(session_id).map_err(|e| anyhow::anyhow!(e))?;
+                self.security_audit
+                    .record(&caller.describe(), "logout", session_id, true, "");
+                Ok(())
+            }
+            _ => Err(anyhow::anyhow!("{} has no session to end", caller.describe())),
+        }
+    }
+
+    /// Change the caller's password; their other sessions are ended
+    pub async fn change_password(&self, caller: &Caller, old_password: &str, new_password: &str) -> anyhow::Result<()> {
+        let (user_id, session_id) = match caller {
+            Caller::User { user, session_id } => (user.id.clone(), session_id.clone()),
+            _ => return Err(anyhow::anyhow!("{} has no password", caller.describe())),
+        };
+
+        let users = self.users.clone();
+        let (old_password, new_password) = (old_password.to_string(), new_password.to_string());
+        let result = tokio::task::spawn_blocking(move || {
+            users.change_password(&user_id, &old_password, &new_password, Some(&session_id))
+        })
+        .await?;
+
+        let details = result.as_ref().err().map(|e| e.to_string()).unwrap_or_default();
+        self.security_audit
+            .record(&caller.describe(), "password_change", "", result.is_ok(), &details);
+        result
+    }
+
+    /// Issue a one-time password reset code for a user
+    pub fn begin_password_reset(&self, caller: &Caller, user_id: &str) -> anyhow::Result<(String, i64)> {
+        let ticket = self.users.begin_password_reset(user_id)?;
+        self.security_audit
+            .record(&caller.describe(), "password_reset_issued", user_id, true, "");
+        Ok(ticket)
+    }
+
+    /// Set a new password with a reset code; all of the user's sessions end
+    pub async fn complete_password_reset(&self, username: &str, code: &str, new_password: &str) -> anyhow::Result<()> {
+        let users = self.users.clone();
+        let (name, code, new_password) = (username.to_string(), code.to_string(), new_password.to_string());
+        let result =
+            tokio::task::spawn_blocking(move || users.complete_password_reset(&name, &code, &new_password)).await?;
+
+        let details = result.as_ref().err().map(|e| e.to_string()).unwrap_or_default();
+        self.security_audit
+            .record(&format!("user:{}", username), "password_reset", username, result.is_ok(), &details);
+        result
+    }
+
+    fn biometrics(&self) -> anyhow::Result<&Biometrics> {
+        self.biometrics
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Biometric login is not configured"))
+    }
+
+    /// Store a biometric template for a user, replacing one of the same type
+    ///
+    /// The calling user re-enters their own password, as for `change_password`;
+    /// wrong passwords count towards their lockout.
+    pub async fn enroll_biometric(
+        &self,
+        caller: &Caller,
+        password: &str,
+        user_id: &str,
+        kind: &str,
+        sample: &[u8],
+    ) -> anyhow::Result<()> {
+        let caller_id = match caller {
+            Caller::User { user, .. } => user.id.clone(),
+            _ => return Err(anyhow::anyhow!("{} has no password", caller.describe())),
+        };
+        if self.users.get_user(user_id).is_none() {
+            return Err(anyhow::anyhow!("User {} not found", user_id));
+        }
+        let biometrics = self.biometrics()?;
+
+        let users = self.users.clone();
+        let password = password.to_string();
+        let verified = tokio::task::spawn_blocking(move || users.verify_password(&caller_id, &password)).await?;
+        if let Err(e) = verified {
+            self.security_audit
+                .record(&caller.describe(), "biometric_enroll", user_id, false, &e.to_string());
+            return Err(e);
+        }
+
+        biometrics.enroll(user_id, kind, sample)?;
+        self.security_audit
+            .record(&caller.describe(), "biometric_enroll", user_id, true, kind);
+        Ok(())
+    }
+
+    /// Match a biometric sample against the named user's template and open a
+    /// session on success. Failed matches count towards the login lockout.
+    pub fn authenticate_biometric(
+        &self,
+        username: &str,
+        kind: &str,
+        sample: &[u8],
+        device: &str,
+    ) -> anyhow::Result<(Session, String)> {
+        let biometrics = self.biometrics()?;
+        if !biometrics.supports(kind) {
+            return Err(anyhow::anyhow!("Unsupported biometric type: {}", kind));
+        }
+        let result = self.users.login_verified(username, device, |user| {
+            biometrics.verify(&user.id, kind, sample).unwrap_or_else(|e| {
+                warn!("Biometric check for {} failed: {}", user.id, e);
+                false
+            })
+        });
+
+        match result {
+            Ok((session, token)) => {
+                self.security_audit.record(
+                    &format!("user:{}", username),
+                    "login",
+                    username,
+                    true,
+                    &format!("{} via {}", session.device, kind),
+                );
+                Ok((session, token))
+            }
+            Err(e) => {
+                self.security_audit.record(
+                    &format!("user:{}", username),
+                    "login",
+                    username,
+                    false,
+                    &format!("via {}: {}", kind, e),
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolve a module credential issued at spawn to its module id
+    pub fn authenticate_module(&self, credential: &str) -> Option<String> {
+        self.module_credentials.get(credential).map(|m| m.clone())
+    }
+
+    /// Resolve the bearer token of an API call to its caller
+    ///
+    /// Module credentials are tried first, then user sessions. No token means an
+    /// anonymous caller; a token that matches nothing is rejected.
+    pub fn resolve_caller(&self, token: Option<&str>) -> anyhow::Result<Caller> {
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(Caller::Anonymous),
+        };
+        if let Some(module_id) = self.authenticate_module(token) {
+            return Ok(Caller::Module(module_id));
+        }
+        let (session, user) = self
+            .users
+            .authenticate(token)
+            .ok_or_else(|| anyhow::anyhow!("Unknown or expired credential"))?;
+        Ok(Caller::User {
+            user,
+            session_id: session.session_id,
+        })
+    }
+
+    /// Check that `caller` may use `app` at `access` level
+    pub fn authorize(&self, caller: &Caller, app: App, access: Access) -> anyhow::Result<()> {
+        if self.access.allows(caller, app, access) {
+            return Ok(());
+        }
+        self.security_audit
+            .record(&caller.describe(), "access", &app.name(), false, access.name());
+        Err(AccessDenied(format!("{} may not {} {:?}", caller.describe(), access.name(), app)).into())
+    }
+
+    /// The module a call acts as. Modules always act as themselves; users with
+    /// `manage` access to `app` may act for a running module they name, whose
+    /// permissions then apply.
+    fn acting_module(&self, caller: &Caller, claimed: &str, app: App) -> anyhow::Result<String> {
+        match caller {
+            Caller::Module(id) if claimed.is_empty() || claimed == id => Ok(id.clone()),
+            Caller::Module(id) => Err(anyhow::anyhow!("Module {} cannot act as {}", id, claimed)),
+            Caller::User { .. } if claimed.is_empty() => Err(anyhow::anyhow!("No module given to act as")),
+            Caller::User { .. } => {
+                self.authorize(caller, app, Access::Manage).map_err(|_| {
+                    anyhow::anyhow!("{} may not act as module {}", caller.describe(), claimed)
+                })?;
+                if !self.modules.contains_key(claimed) {
+                    return Err(anyhow::anyhow!("Module {} not found", claimed));
+                }
+                Ok(claimed.to_string())
+            }
+            Caller::Anonymous => Err(anyhow::anyhow!("Anonymous callers cannot act as a module")),
+        }
+    }
+
+    /// Administrative IPC operations: modules need `Admin`, users IPC `manage` access
+    fn require_ipc_admin(&self, caller: &Caller) -> anyhow::Result<()> {
+        match caller {
+            Caller::Module(id) => self.permissions.check(id, PermPerm::Admin, None),
+            _ => self.authorize(caller, App::Ipc, Access::Manage),
+        }
+    }
+
+    /// Effective per-app access of a user: their role plus any overrides
+    pub fn user_permissions(&self, user_id: &str) -> anyhow::Result<AppAccess> {
+        let user = self
+            .users
+            .get_user(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User {} not found", user_id))?;
+        Ok(self.access.effective(&user.id, Role::of(&user)))
+    }
+
+    /// Give a user access levels on an app beyond their role
+    pub fn grant_user_permission(
+        &self,
+        caller: &Caller,
+        user_id: &str,
+        app: App,
+        levels: &[Access],
+    ) -> anyhow::Result<()> {
+        if self.users.get_user(user_id).is_none() {
+            return Err(anyhow::anyhow!("User {} not found", user_id));
+        }
+        if levels.is_empty() {
+            return Err(anyhow::anyhow!("No access levels to grant"));
+        }
+        self.access.grant(user_id, app, levels)?;
+        self.security_audit.record(
+            &caller.describe(),
+            "user_permission_grant",
+            user_id,
+            true,
+            &format!("{:?} {:?}", app, levels),
+        );
+        Ok(())
+    }
+
+    /// Take access levels on an app away from a user; no levels means all of them
+    pub fn revoke_user_permission(
+        &self,
+        caller: &Caller,
+        user_id: &str,
+        app: App,
+        levels: &[Access],
+    ) -> anyhow::Result<()> {
+        if self.users.get_user(user_id).is_none() {
+            return Err(anyhow::anyhow!("User {} not found", user_id));
+        }
+        self.access.revoke(user_id, app, levels)?;
+        self.security_audit.record(
+            &caller.describe(),
+            "user_permission_revoke",
+            user_id,
+            true,
+            &format!("{:?} {:?}", app, levels),
+        );
+        Ok(())
+    }
+
+    pub fn list_users(&self) -> Vec<User> {
+        let mut users = self.users.list_users();
+        users.sort_by_key(|u| u.created_at);
+        users
+    }
+
+    /// Add a user. The id and creation time are assigned here; the new user
+    /// has no password and sets one through a password reset.
+    pub fn create_user(&self, caller: &Caller, username: &str, display_name: &str, role: &str) -> anyhow::Result<User> {
+        let username = username.trim();
+        if username.len() < 3 || username.len() > 32 {
+            return Err(anyhow::anyhow!("Username must be 3 to 32 characters"));
+        }
+        if !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        {
+            return Err(anyhow::anyhow!(
+                "Username may only contain letters, digits, '.', '_' and '-'"
+            ));
+        }
+        if display_name.chars().count() > 64 {
+            return Err(anyhow::anyhow!("Display name must be at most 64 characters"));
+        }
+        let role = Role::parse(role).ok_or_else(|| anyhow::anyhow!("Unknown role: {}", role))?;
+
+        let user = self.users.create_user(User {
+            id: Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            display_name: display_name.trim().to_string(),
+            role: role.name().to_string(),
+            active: true,
+            created_at: chrono::Local::now().timestamp_millis(),
+        })?;
+        self.security_audit.record(
+            &caller.describe(),
+            "user_create",
+            &user.id,
+            true,
+            &format!("{} as {}", user.username, user.role),
+        );
+        Ok(user)
+    }
+
+    /// Remove a user, ending their sessions and dropping their access overrides
+    ///
+    /// Callers cannot delete themselves, and the last admin cannot be deleted.
+    pub fn delete_user(&self, caller: &Caller, user_id: &str) -> anyhow::Result<()> {
+        let user = self
+            .users
+            .get_user(user_id)
+            .ok_or_else(|| anyhow::anyhow!("User {} not found", user_id))?;
+        if matches!(caller, Caller::User { user: me, .. } if me.id == user.id) {
+            return Err(anyhow::anyhow!("Users cannot delete themselves"));
+        }
+        if Role::of(&user) == Role::Admin
+            && !self
+                .users
+                .list_users()
+                .iter()
+                .any(|u| u.id != user.id && u.active && Role::of(u) == Role::Admin)
+        {
+            return Err(anyhow::anyhow!("Cannot delete the last admin"));
+        }
+
+        let sessions = self.users.list_sessions(user_id).len();
+        self.users.delete_user(user_id).map_err(|e| anyhow::anyhow!(e))?;
+        self.access.forget_user(user_id)?;
+        if let Some(biometrics) = &self.biometrics {
+            biometrics.remove_user(user_id)?;
+        }
+        self.security_audit.record(
+            &caller.describe(),
+            "user_delete",
+            &user.id,
+            true,
+            &format!("{}, {} session(s) ended", user.username, sessions),
+        );
+        Ok(())
+    }
+
+    pub fn list_sessions(&self, user_id: &str) -> Vec<Session> {
+        let mut sessions = self.users.list_sessions(user_id);
+        sessions.sort_by_key(|s| s.started_at);
+        sessions
+    }
+
+    /// User a session belongs to
+    pub fn session_owner(&self, session_id: &str) -> Option<String> {
+        self.users.get_session(session_id).map(|s| s.user_id)
+    }
+
+    pub fn terminate_session(&self, caller: &Caller, session_id: &str) -> anyhow::Result<()> {
+        let session = self
+            .users
+            .get_session(session_id)
+            .ok_or_else(|| anyhow::anyhow!("Session {} not found", session_id))?;
+        self.users.terminate_session(session_id).map_err(|e| anyhow::anyhow!(e))?;
+        self.security_audit.record(
+            &caller.describe(),
+            "session_terminate",
+            session_id,
+            true,
+            &format!("user {}", session.user_id),
+        );
+        Ok(())
+    }
+
+    /// Publish an event on behalf of a module
+    ///
+    /// Only modules publish; the event is stamped with the calling module's id
+    /// regardless of what the client claimed.
+    pub async fn publish_event(&self, caller: &Caller, mut event: Event) -> anyhow::Result<()> {
+        let source = match caller {
+            Caller::Module(id) => id.as_str(),
+            _ => return Err(anyhow::anyhow!("{} cannot publish events", caller.describe())),
+        };
+        self.permissions.check(source, PermPerm::PublishEvents, None)?;
+        if event.event_type.is_empty() || event.event_type.split('.').any(|s| s.is_empty() || s == "*" || s == "#") {
+            return Err(anyhow::anyhow!("Invalid event type: {:?}", event.event_type));
+        }
+
+        event.source = source.to_string();
+        event.timestamp = chrono::Local::now().timestamp_millis();
+        let event_type = event.event_type.clone();
+        self.event_bus.publish(event).await?;
+        self.security_audit
+            .record(&caller.describe(), "event_publish", &event_type, true, "");
+        Ok(())
     }
 
     /// Send a message between modules via IPC
-    pub async fn ipc_send(&self, from: &str, to: &str, data: IpcMessage) -> anyhow::Result<()> {
+    ///
+    /// `from` is checked against the caller (see `acting_module`) and must hold
+    /// `SendIpc` for `to`.
+    pub async fn ipc_send(&self, caller: &Caller, from: &str, to: &str, mut data: IpcMessage) -> anyhow::Result<()> {
+        let from = self.acting_module(caller, from, App::Ipc)?;
+        let from = from.as_str();
+        data.from = from.to_string();
+
         // Check permissions
-        self.permissions.check(from, PermPerm::SendIpc)?;
-
-        if let Some(channel) = self.ipc_channels.get(to) {
-            channel.send(data).await?;
-            self.security_audit.log("ipc_send", &format!("{} -> {}", from, to));
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Module {} not found", to))
-        }
-    }
-
-    /// Check if a module has permission for an action
-    pub async fn check_permission(&self, module_id: &str, permission: crate::proto::Permission) -> anyhow::Result<bool> {
-        let perm = match permission {
-            crate::proto::Permission::PermissionSendIpc => PermPerm::SendIpc,
-            crate::proto::Permission::PermissionRunWasm => PermPerm::RunWasm,
-            crate::proto::Permission::PermissionAccessMemory => PermPerm::AccessMemory,
-            crate::proto::Permission::PermissionAccessVision => PermPerm::AccessVision,
-            crate::proto::Permission::PermissionAccessAudio => PermPerm::AccessAudio,
-            crate::proto::Permission::PermissionSystemCall => PermPerm::SystemCall,
-            crate::proto::Permission::PermissionAdmin => PermPerm::Admin,
-            _ => return Ok(false),
-        };
-        Ok(self.permissions.check(module_id, perm).is_ok())
-    }
-
-    /// Grant permission to a module
-    pub fn grant_permission(&self, module_id: &str, permission: crate::proto::Permission) -> anyhow::Result<()> {
-        let perm = match permission {
-            crate::proto::Permission::PermissionSendIpc => PermPerm::SendIpc,
-            crate::proto::Permission::PermissionRunWasm => PermPerm::RunWasm,
-            crate::proto::Permission::PermissionAccessMemory => PermPerm::AccessMemory,
-            crate::proto::Permission::PermissionAccessVision => PermPerm::AccessVision,
-            crate::proto::Permission::PermissionAccessAudio => PermPerm::AccessAudio,
-            crate::proto::Permission::PermissionSystemCall => PermPerm::SystemCall,
-            crate::proto::Permission::PermissionAdmin => PermPerm::Admin,
-            _ => return Err(anyhow::anyhow!("Unknown permission")),
-        };
-        self.permissions.grant(module_id, perm);
+        self.permissions.check(from, PermPerm::SendIpc, Some(to))?;
+        data.validate()?;
+
+        // Replies to an outstanding CallIpc go straight back to the waiting caller
+        if data.is_reply() {
+            if let Some((_, pending)) = self
+                .pending_calls
+                .remove_if(&data.correlation_id, |_, p| p.responder == from)
+            {
+                if std::time::Instant::now() > pending.deadline {
+                    return Err(anyhow::anyhow!("Reply {} arrived after its deadline", data.correlation_id));
+                }
+                let _ = pending.reply.send(data);
+                self.security_audit
+                    .record(&caller.describe(), "ipc_reply", to, true, &format!("from {}", from));
+                return Ok(());
+            }
+        }
+
+        self.deliver(to, data).await?;
+        self.security_audit
+            .record(&caller.describe(), "ipc_send", to, true, &format!("from {}", from));
         Ok(())
     }
 
-    /// Revoke permission from a module
-    pub fn revoke_permission(&self, module_id: &str, permission: crate::proto::Permission) -> anyhow::Result<()> {
-        let perm = match permission {
-            crate::proto::Permission::PermissionSendIpc => PermPerm::SendIpc,
-            crate::proto::Permission::PermissionRunWasm => PermPerm::RunWasm,
-            crate::proto::Permission::PermissionAccessMemory => PermPerm::AccessMemory,
-            crate::proto::Permission::PermissionAccessVision => PermPerm::AccessVision,
-            crate::proto::Permission::PermissionAccessAudio => PermPerm::AccessAudio,
-            crate::proto::Permission::PermissionSystemCall => PermPerm::SystemCall,
-            crate::proto::Permission::PermissionAdmin => PermPerm::Admin,
-            _ => return Err(anyhow::anyhow!("Unknown permission")),
+    /// Put a message into the target's mailbox, applying its overflow policy
+    ///
+    /// Anything that cannot be delivered ends up in the dead-letter store.
+    async fn deliver(&self, to: &str, data: IpcMessage) -> anyhow::Result<()> {
+        let channel = match self.ipc_channels.get(to).map(|c| c.clone()) {
+            Some(channel) => channel,
+            None => {
+                self.dead_letters.push(data, "module not found");
+                return Err(anyhow::anyhow!("Module {} not found", to));
+            }
         };
-        self.permissions.revoke(module_id, perm);
+
+        match channel.send(data).await {
+            Ok(Some(evicted)) => {
+                self.dead_letters.push(evicted, "evicted from full mailbox");
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err((e, message)) => {
+                self.dead_letters.push(message, &e.to_string());
+                Err(e.into())
+            }
+        }
+    }
+
+    /// List undeliverable messages (admin only)
+    pub fn list_dead_letters(&self, caller: &Caller) -> anyhow::Result<Vec<DeadLetter>> {
+        self.require_ipc_admin(caller)?;
+        Ok(self.dead_letters.list())
+    }
+
+    /// Try to deliver a dead letter again (admin only)
+    ///
+    /// If delivery fails again the message goes back into the store with the new reason.
+    pub async fn replay_dead_letter(&self, caller: &Caller, letter_id: &str) -> anyhow::Result<()> {
+        self.require_ipc_admin(caller)?;
+        let letter = self
+            .dead_letters
+            .take(letter_id)
+            .ok_or_else(|| anyhow::anyhow!("Dead letter {} not found", letter_id))?;
+        let to = letter.message.to.clone();
+        self.deliver(&to, letter.message).await?;
+        self.security_audit
+            .record(&caller.describe(), "ipc_dead_letter_replay", letter_id, true, &format!("to {}", to));
         Ok(())
     }
 
-    /// Get current resource utilization
+    /// Send a request to a module and wait for its reply
+    ///
+    /// The request is delivered like any IPC message with a fresh `correlation_id`
+    /// and `reply_to` set to the caller; the target answers by sending a message
+    /// with the same `correlation_id` back through `ipc_send`.
+    pub async fn call_ipc(
+        &self,
+        caller: &Caller,
+        from: &str,
+        to: &str,
+        mut request: IpcMessage,
+        timeout: Option<std::time::Duration>,
+    ) -> anyhow::Result<IpcMessage> {
+        let from = self.acting_module(caller, from, App::Ipc)?;
+        let from = from.as_str();
+        let timeout = timeout.unwrap_or(DEFAULT_CALL_TIMEOUT).min(MAX_CALL_TIMEOUT);
+        let correlation_id = Uuid::new_v4().to_string();
+        request.correlation_id = correlation_id.clone();
+        request.reply_to = from.to_string();
+
+        // Drop entries whose callers went away without timing out themselves
+        let now = std::time::Instant::now();
+        self.pending_calls.retain(|_, p| p.deadline > now);
+
+        let (reply, response) = tokio::sync::oneshot::channel();
+        self.pending_calls.insert(
+            correlation_id.clone(),
+            PendingCall {
+                responder: to.to_string(),
+                deadline: now + timeout,
+                reply,
+            },
+        );
+
+        if let Err(e) = self.ipc_send(caller, from, to, request).await {
+            self.pending_calls.remove(&correlation_id);
+            return Err(e);
+        }
+
+        match tokio::time::timeout(timeout, response).await {
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(_)) => Err(anyhow::anyhow!("IPC call {} was abandoned", correlation_id)),
+            Err(_) => {
+                self.pending_calls.remove(&correlation_id);
+                self.security_audit.record(
+                    &caller.describe(),
+                    "ipc_call",
+                    to,
+                    false,
+                    &format!("from {}: timed out after {:?}", from, timeout),
+                );
+                Err(IpcError::Timeout(to.to_string(), timeout).into())
+            }
+        }
+    }
+
+    /// Take the read side of a module's mailbox
+    ///
+    /// Only one reader may drain a mailbox at a time; it is released when the
+    /// returned reader is dropped.
+    pub fn open_mailbox(&self, caller: &Caller, module_id: &str) -> anyhow::Result<MailboxReader> {
+        let module_id = self.acting_module(caller, module_id, App::Ipc)?;
+        let module_id = module_id.as_str();
+        let mailbox = self
+            .ipc_channels
+            .get(module_id)
+            .map(|m| m.clone())
+            .ok_or_else(|| anyhow::anyhow!("Module {} not found", module_id))?;
+        let reader = mailbox
+            .reader()
+            .ok_or_else(|| anyhow::anyhow!("Mailbox of {} already has a reader", module_id))?;
+        self.security_audit
+            .record(&caller.describe(), "ipc_mailbox_open", module_id, true, "");
+        Ok(reader)
+    }
+
+    /// Check if a module has permission for an action, on `target` when given
+    pub async fn check_permission(
+        &self,
+        module_id: &str,
+        permission: crate::proto::Permission,
+        target: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let perm = match to_kernel_permission(permission) {
+            Some(perm) => perm,
+            None => return Ok(false),
+        };
+        Ok(self.permissions.check(module_id, perm, target).is_ok())
+    }
+
+    /// Grant permission to a module, optionally scoped to resources and expiring at
+    /// `expires_at` (unix millis). The grant is kept under the module's name, so
+    /// it also applies to later spawns of the same module.
+    pub fn grant_permission(
+        &self,
+        caller: &Caller,
+        module_id: &str,
+        permission: crate::proto::Permission,
+        scope: Vec<String>,
+        expires_at: Option<i64>,
+    ) -> anyhow::Result<()> {
+        let perm = to_kernel_permission(permission).ok_or_else(|| anyhow::anyhow!("Unknown permission"))?;
+        if expires_at.map_or(false, |at| at <= chrono::Local::now().timestamp_millis()) {
+            return Err(anyhow::anyhow!("Grant expiry is in the past"));
+        }
+        let grant = Grant {
+            permission: perm,
+            scope: scope.into_iter().collect(),
+            expires_at,
+        };
+        self.permissions.grant(module_id, grant.clone())?;
+        self.security_audit
+            .record(&caller.describe(), "permission_grant", module_id, true, &format!("{:?}", grant));
+        Ok(())
+    }
+
+    /// Revoke permission from a module, whatever its scope
+    pub fn revoke_permission(
+        &self,
+        caller: &Caller,
+        module_id: &str,
+        permission: crate::proto::Permission,
+    ) -> anyhow::Result<()> {
+        let perm = to_kernel_permission(permission).ok_or_else(|| anyhow::anyhow!("Unknown permission"))?;
+        self.permissions.revoke(module_id, perm.clone())?;
+        self.security_audit
+            .record(&caller.describe(), "permission_revoke", module_id, true, &format!("{:?}", perm));
+        Ok(())
+    }
+
+    /// Get current resource utilization, including WASM compile cache counters
     pub async fn get_resources(&self) -> anyhow::Result<HashMap<String, f64>> {
-        let stats = self.resources.get_stats().await;
+        let mut stats = self.resources.get_stats().await;
+        let cache = self.wasm_runtime.cache_stats();
+        stats.insert("wasm_cache_hits".to_string(), cache.hits as f64);
+        stats.insert("wasm_cache_disk_hits".to_string(), cache.disk_hits as f64);
+        stats.insert("wasm_cache_misses".to_string(), cache.misses as f64);
+        stats.insert("wasm_cache_evictions".to_string(), cache.evictions as f64);
+        stats.insert("wasm_cache_entries".to_string(), cache.entries as f64);
         Ok(stats)
     }
 
     /// Run WASM code in a sandbox
-    pub async fn run_wasm(&self, module_id: &str, wasm_data: &[u8], args: Vec<String>) -> anyhow::Result<String> {
-        self.permissions.check(module_id, PermPerm::RunWasm)?;
-        let result = self.wasm_runtime.execute(wasm_data, args).await?;
-        self.security_audit.log("wasm_run", module_id);
-        Ok(result)
+    ///
+    /// `RunWasm` is checked against the module's content hash and every host
+    /// import, WASI functions included, against the caller's `SystemCall`
+    /// scope. The guest's stdout and stderr are captured and returned, and it
+    /// runs under the sandbox policy's memory, CPU time and import limits.
+    pub async fn run_wasm(
+        &self,
+        caller: &Caller,
+        module_id: &str,
+        wasm_data: Vec<u8>,
+        args: Vec<String>,
+        env: HashMap<String, String>,
+    ) -> anyhow::Result<WasmOutput> {
+        let module_id = self.acting_module(caller, module_id, App::Wasm)?;
+        let module_id = module_id.as_str();
+        let content_hash = hex::encode(Sha256::digest(&wasm_data));
+        self.permissions.check(module_id, PermPerm::RunWasm, Some(&content_hash))?;
+        let runtime = self.wasm_runtime.clone();
+        let module = tokio::task::spawn_blocking(move || runtime.compile(&wasm_data)).await??;
+        for import in wasm_runtime::imports(&module) {
+            self.permissions.check(module_id, PermPerm::SystemCall, Some(&import))?;
+        }
+        let invocation = WasmInvocation {
+            program: module_id.to_string(),
+            args,
+            env: env.into_iter().collect(),
+            capture_output: true,
+            cpu_limit: true,
+        };
+        let result = self.wasm_runtime.execute(module, invocation).await;
+        let details = match &result {
+            Ok(output) if output.exit_code != 0 => format!("exited with code {}", output.exit_code),
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        };
+        let success = matches!(&result, Ok(output) if output.exit_code == 0);
+        self.security_audit
+            .record(&caller.describe(), "wasm_run", module_id, success, &details);
+        result
     }
 
-    /// Get security audit logs
-    pub async fn get_audit_logs(&self) -> anyhow::Result<Vec<String>> {
-        Ok(self.security_audit.get_logs())
+    /// Register a WASM module that stays instantiated between calls
+    ///
+    /// `config` takes the `args`, `env.<NAME>`, `permissions` and mailbox keys
+    /// of `spawn`. If the guest exports `kiacha_alloc` and `kiacha_on_message`
+    /// (see `WasmInstance::handle_message`), messages sent to the module are
+    /// handed to it, and its reply goes back to the requester's `reply_to`.
+    ///
+    /// As in `run_wasm`, the module needs `RunWasm` for its content hash and
+    /// `SystemCall` for each host import, from its manifest or the policy.
+    /// The hash is checked before the bytes are compiled, the imports before
+    /// any guest code runs.
+    pub async fn register_wasm_module(
+        &self,
+        caller: &Caller,
+        name: String,
+        module_type: crate::proto::ModuleType,
+        wasm_data: Vec<u8>,
+        config: HashMap<String, String>,
+    ) -> anyhow::Result<String> {
+        let module_id = Uuid::new_v4().to_string();
+        let converted_type = to_kernel_module_type(module_type, &name);
+        let mailbox = MailboxConfig::from_config(&config)?;
+        let manifest = match config.get("permissions") {
+            Some(raw) => Grant::parse_manifest(raw)?,
+            None => Vec::new(),
+        };
+        let args = module_host::config_args(&config)?;
+        let mut env: Vec<(String, String)> = module_host::config_env(&config).into_iter().collect();
+        env.push(("KIACHA_MODULE_ID".to_string(), module_id.clone()));
+
+        let invocation = WasmInvocation {
+            program: module_id.clone(),
+            args,
+            env,
+            capture_output: false,
+            cpu_limit: true,
+        };
+        let content_hash = hex::encode(Sha256::digest(&wasm_data));
+
+        self.check_module_name(caller, &name)?;
+        let denied = self
+            .permissions
+            .apply_manifest(&module_id, &name, converted_type.policy_key(), &manifest)?;
+        if !denied.is_empty() {
+            self.security_audit.record(
+                &caller.describe(),
+                "permission_manifest",
+                &module_id,
+                false,
+                &format!("denied for {}: {:?}", converted_type.policy_key(), denied),
+            );
+        }
+
+        let control = Arc::new(WasmControl::new());
+        let runtime = self.wasm_runtime.clone();
+        let permissions = self.permissions.clone();
+        let guest_id = module_id.clone();
+        let guest_control = control.clone();
+        // Unapproved bytes are refused before they are compiled or cached
+        let result = match self.permissions.check(&module_id, PermPerm::RunWasm, Some(&content_hash)) {
+            Ok(()) => tokio::task::spawn_blocking(move || {
+                let module = runtime.compile(&wasm_data)?;
+                for import in wasm_runtime::imports(&module) {
+                    permissions.check(&guest_id, PermPerm::SystemCall, Some(&import))?;
+                }
+                runtime.instantiate_persistent(&module, invocation, guest_control)
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|result| result),
+            Err(e) => Err(e),
+        };
+        let mut instance = match result {
+            Ok(instance) => instance,
+            Err(e) => {
+                self.permissions.clear_module(&module_id);
+                self.security_audit
+                    .record(&caller.describe(), "module_register", &module_id, false, &e.to_string());
+                return Err(e);
+            }
+        };
+        let handles_messages = instance.handles_messages();
+
+        let channel = Arc::new(IpcChannel::new(mailbox));
+        let reader = if handles_messages { channel.reader() } else { None };
+        self.ipc_channels.insert(module_id.clone(), channel);
+        let instance = Arc::new(parking_lot::Mutex::new(instance));
+        self.wasm_instances.insert(module_id.clone(), instance.clone());
+        self.wasm_controls.insert(module_id.clone(), control);
+        self.modules.insert(
+            module_id.clone(),
+            ModuleInfo {
+                id: module_id.clone(),
+                name: name.clone(),
+                module_type: converted_type.clone(),
+                status: ModuleStatus::Running,
+                runtime: ModuleRuntime::Wasm,
+                pid: None,
+                last_exit: None,
+                restarts: 0,
+                created_at: chrono::Local::now().timestamp_millis(),
+            },
+        );
+        if let Some(reader) = reader {
+            let pump = self.pump_messages(module_id.clone(), instance, reader);
+            self.wasm_pumps.insert(module_id.clone(), pump);
+        }
+
+        let event = Event::json("module.spawned", "kernel", serde_json::json!({
+            "module_id": &module_id,
+            "name": &name,
+            "runtime": ModuleRuntime::Wasm,
+            "persistent": true,
+        }));
+        self.event_bus.publish(event).await?;
+
+        self.security_audit.record(
+            &caller.describe(),
+            "module_register",
+            &module_id,
+            true,
+            &format!("{} ({:?}), sha256 {}", name, converted_type, content_hash),
+        );
+        info!("✓ Registered WASM module: {} ({})", name, module_id);
+        Ok(module_id)
+    }
+
+    /// Hand the messages in a registered module's mailbox to its handler
+    ///
+    /// Messages the handler fails on are kept as dead letters.
+    fn pump_messages(
+        &self,
+        module_id: String,
+        instance: Arc<parking_lot::Mutex<WasmInstance>>,
+        mut reader: MailboxReader,
+    ) -> tokio::task::JoinHandle<()> {
+        let kernel = self.clone();
+        tokio::spawn(async move {
+            let caller = Caller::Module(module_id.clone());
+            loop {
+                let message = reader.recv().await;
+                let body = if message.data.is_empty() {
+                    message.payload.clone().into_bytes()
+                } else {
+                    message.data.clone()
+                };
+
+                let guest = instance.clone();
+                let result = tokio::task::spawn_blocking(move || guest.lock().handle_message(&body))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|result| result);
+                let reply = match result {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        warn!("WASM module {} failed to handle a message: {}", module_id, e);
+                        kernel.dead_letters.push(message, &format!("handler failed: {}", e));
+                        continue;
+                    }
+                };
+
+                // Only requests get an answer back
+                let Some(body) = reply else { continue };
+                if message.reply_to.is_empty() {
+                    continue;
+                }
+                let mut response = IpcMessage::new(module_id.clone(), message.reply_to.clone(), String::new());
+                response.correlation_id = message.correlation_id.clone();
+                response.content_type = message.content_type;
+                match message.content_type {
+                    crate::ipc::ContentType::Text => {
+                        response.payload = String::from_utf8_lossy(&body).into_owned()
+                    }
+                    _ => response.data = body,
+                }
+                if let Err(e) = kernel
+                    .ipc_send(&caller, &module_id, &message.reply_to, response)
+                    .await
+                {
+                    warn!("WASM module {} could not reply to {}: {}", module_id, message.reply_to, e);
+                }
+            }
+        })
+    }
+
+    /// Stop a registered WASM module and forget it
+    ///
+    /// Its message pump is stopped and messages still in its mailbox become
+    /// dead letters. A call already running finishes under the CPU limit,
+    /// resumed first if the module was paused.
+    pub async fn unregister_wasm_module(&self, caller: &Caller, module_id: &str) -> anyhow::Result<()> {
+        if self.wasm_instances.remove(module_id).is_none() {
+            return Err(anyhow::anyhow!("Module {} is not a registered WASM module", module_id));
+        }
+        if let Some((_, pump)) = self.wasm_pumps.remove(module_id) {
+            pump.abort();
+        }
+        if let Some((_, control)) = self.wasm_controls.remove(module_id) {
+            control.resume();
+        }
+        if let Some((_, channel)) = self.ipc_channels.remove(module_id) {
+            for message in channel.drain() {
+                self.dead_letters.push(message, "module unregistered");
+            }
+        }
+        self.modules.remove(module_id);
+        self.permissions.clear_module(module_id);
+
+        let event = Event::json("module.exited", "kernel", serde_json::json!({
+            "module_id": module_id,
+            "success": true,
+            "reason": "unregistered",
+        }));
+        self.event_bus.publish(event).await?;
+
+        self.security_audit
+            .record(&caller.describe(), "module_unregister", module_id, true, "");
+        info!("✓ Unregistered WASM module: {}", module_id);
+        Ok(())
+    }
+
+    /// Call an export of a registered WASM module
+    ///
+    /// Modules other than the target itself need `SendIpc` for it. The call is
+    /// refused while the module is paused and is bound by the sandbox CPU limit.
+    pub async fn invoke_wasm_export(
+        &self,
+        caller: &Caller,
+        module_id: &str,
+        export: &str,
+        args: Vec<WasmValue>,
+    ) -> anyhow::Result<Vec<WasmValue>> {
+        if let Caller::Module(id) = caller {
+            if id != module_id {
+                self.permissions.check(id, PermPerm::SendIpc, Some(module_id))?;
+            }
+        }
+        let instance = self
+            .wasm_instances
+            .get(module_id)
+            .map(|i| i.clone())
+            .ok_or_else(|| anyhow::anyhow!("Module {} is not a registered WASM module", module_id))?;
+        if self.modules.get(module_id).map(|m| m.status.clone()) != Some(ModuleStatus::Running) {
+            return Err(anyhow::anyhow!("Module {} is not running", module_id));
+        }
+
+        let name = export.to_string();
+        let result = tokio::task::spawn_blocking(move || instance.lock().call(&name, &args)).await?;
+        let details = match &result {
+            Ok(_) => export.to_string(),
+            Err(e) => format!("{}: {}", export, e),
+        };
+        self.security_audit
+            .record(&caller.describe(), "wasm_invoke", module_id, result.is_ok(), &details);
+        result
+    }
+
+    /// Limits WASM guests run under
+    pub fn sandbox_policy(&self) -> &SandboxPolicy {
+        self.wasm_runtime.policy()
+    }
+
+    /// Audit records as they are written, and the sequence number of the first
+    pub fn subscribe_audit(&self) -> (tokio::sync::broadcast::Receiver<AuditRecord>, u64) {
+        self.security_audit.subscribe()
+    }
+
+    /// Stored audit records matching a filter, oldest first
+    pub async fn audit_records(&self, filter: AuditFilter) -> anyhow::Result<Vec<AuditRecord>> {
+        let audit = self.security_audit.clone();
+        tokio::task::spawn_blocking(move || audit.query(&filter)).await?
+    }
+
+    /// Check the stored audit log for tampering
+    pub async fn verify_audit_chain(&self, caller: &Caller) -> anyhow::Result<ChainReport> {
+        let audit = self.security_audit.clone();
+        let report = tokio::task::spawn_blocking(move || audit.verify_chain()).await??;
+        self.security_audit.record(
+            &caller.describe(),
+            "audit_verify",
+            "",
+            report.valid(),
+            report.error.as_deref().unwrap_or_default(),
+        );
+        Ok(report)
     }
 
-    /// Subscribe to events
-    pub fn subscribe_to_events(&self, event_type: &str) -> tokio::sync::broadcast::Receiver<Event> {
+    /// Subscribe to events matching a topic pattern such as `module.*` or `security.#`
+    pub fn subscribe_to_events(&self, event_type: &str) -> Subscription {
         self.event_bus.subscribe(event_type.to_string())
     }
 
+    /// Replay journaled events matching a pattern from a cursor, then follow live ones
+    pub fn subscribe_to_events_from(
+        &self,
+        pattern: &str,
+        cursor: JournalCursor,
+    ) -> (mpsc::Receiver<anyhow::Result<Event>>, Subscription) {
+        self.event_bus.subscribe_from(pattern.to_string(), cursor)
+    }
+
+    /// Delivery and lag counters for every live event subscriber
+    pub fn event_subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.event_bus.subscriber_stats()
+    }
+
     /// List all active modules
     pub fn list_modules(&self) -> Vec<ModuleInfo> {
         self.modules.iter().map(|r| r.value().clone()).collect()
     }
 
     /// Pause a module
-    pub async fn pause_module(&self, module_id: &str) -> anyhow::Result<()> {
-        if let Some(mut module) = self.modules.get_mut(module_id) {
-            module.status = ModuleStatus::Paused;
-            self.security_audit.log("module_pause", module_id);
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Module not found"))
+    ///
+    /// Process modules are stopped with SIGSTOP; WASM modules are suspended at the
+    /// next epoch tick. IPC addressed to the module stays in its mailbox, under
+    /// the mailbox's overflow policy, until it resumes.
+    pub async fn pause_module(&self, caller: &Caller, module_id: &str) -> anyhow::Result<()> {
+        let mut module = self
+            .modules
+            .get_mut(module_id)
+            .ok_or_else(|| anyhow::anyhow!("Module not found"))?;
+
+        if module.status != ModuleStatus::Running {
+            return Err(anyhow::anyhow!(
+                "Module {} cannot be paused while {:?}",
+                module_id,
+                module.status
+            ));
+        }
+
+        match module.runtime {
+            ModuleRuntime::Process => {
+                let pid = module
+                    .pid
+                    .ok_or_else(|| anyhow::anyhow!("Module {} has no running process", module_id))?;
+                send_signal(pid, Signal::SIGSTOP)?;
+            }
+            // Nothing to suspend; only its messages are held back
+            ModuleRuntime::External => {}
+            ModuleRuntime::Wasm => {
+                let control = self
+                    .wasm_controls
+                    .get(module_id)
+                    .ok_or_else(|| anyhow::anyhow!("Module {} has no running instance", module_id))?;
+                control.pause();
+            }
         }
+
+        module.status = ModuleStatus::Paused;
+        if let Some(channel) = self.ipc_channels.get(module_id) {
+            channel.set_paused(true);
+        }
+        self.security_audit
+            .record(&caller.describe(), "module_pause", module_id, true, "");
+        Ok(())
     }
 
-    /// Resume a module
-    pub async fn resume_module(&self, module_id: &str) -> anyhow::Result<()> {
-        if let Some(mut module) = self.modules.get_mut(module_id) {
+    /// Resume a module and release the IPC held in its mailbox while it was paused
+    pub async fn resume_module(&self, caller: &Caller, module_id: &str) -> anyhow::Result<()> {
+        {
+            let mut module = self
+                .modules
+                .get_mut(module_id)
+                .ok_or_else(|| anyhow::anyhow!("Module not found"))?;
+
+            if module.status != ModuleStatus::Paused {
+                return Err(anyhow::anyhow!("Module {} is not paused", module_id));
+            }
+
+            match module.runtime {
+                ModuleRuntime::Process => {
+                    if let Some(pid) = module.pid {
+                        send_signal(pid, Signal::SIGCONT)?;
+                    }
+                }
+                ModuleRuntime::External => {}
+                ModuleRuntime::Wasm => {
+                    if let Some(control) = self.wasm_controls.get(module_id) {
+                        control.resume();
+                    }
+                }
+            }
+
             module.status = ModuleStatus::Running;
-            self.security_audit.log("module_resume", module_id);
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Module not found"))
         }
+
+        if let Some(channel) = self.ipc_channels.get(module_id) {
+            channel.set_paused(false);
+        }
+
+        self.security_audit
+            .record(&caller.describe(), "module_resume", module_id, true, "");
+        Ok(())
     }
 }
+
+fn to_kernel_module_type(module_type: crate::proto::ModuleType, name: &str) -> ModuleType {
+    match module_type {
+        crate::proto::ModuleType::ModuleBrain => ModuleType::Brain,
+        crate::proto::ModuleType::ModuleInterface => ModuleType::Interface,
+        crate::proto::ModuleType::ModuleVision => ModuleType::Vision,
+        crate::proto::ModuleType::ModuleAudio => ModuleType::Audio,
+        crate::proto::ModuleType::ModuleMemory => ModuleType::Memory,
+        crate::proto::ModuleType::ModuleReasoning => ModuleType::Reasoning,
+        crate::proto::ModuleType::ModuleCustom => ModuleType::Custom(name.to_string()),
+        _ => ModuleType::Custom(name.to_string()),
+    }
+}
+
+fn to_kernel_permission(permission: crate::proto::Permission) -> Option<PermPerm> {
+    match permission {
+        crate::proto::Permission::PermissionSendIpc => Some(PermPerm::SendIpc),
+        crate::proto::Permission::PermissionRunWasm => Some(PermPerm::RunWasm),
+        crate::proto::Permission::PermissionAccessMemory => Some(PermPerm::AccessMemory),
+        crate::proto::Permission::PermissionAccessVision => Some(PermPerm::AccessVision),
+        crate::proto::Permission::PermissionAccessAudio => Some(PermPerm::AccessAudio),
+        crate::proto::Permission::PermissionSystemCall => Some(PermPerm::SystemCall),
+        crate::proto::Permission::PermissionAdmin => Some(PermPerm::Admin),
+        crate::proto::Permission::PermissionPublishEvents => Some(PermPerm::PublishEvents),
+        _ => None,
+    }
+}
+
+fn send_signal(pid: u32, signal: Signal) -> anyhow::Result<()> {
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), signal)?;
+    Ok(())
+}
@@ -10,13 +10,25 @@ mod grpc_server;
 mod system_info;
 mod network_info;
 mod user_manager;
+mod user_store;
+mod module_host;
+mod supervisor;
+mod config;
+mod event_journal;
+mod rbac;
+mod biometric;
+mod wasm_cache;
+#[cfg(test)]
+mod test_util;
 
 use kernel::KiachaKernel;
-use grpc_server::KiachaKernelService;
+use config::KernelConfig;
+use grpc_server::{AuthInterceptor, KiachaEventBusService, KiachaKernelService};
 use std::net::SocketAddr;
 use tracing::{info, Level};
 use tracing_subscriber;
 use std::sync::Arc;
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Server;
 
 #[tokio::main]
@@ -26,20 +38,43 @@ async fn main() -> anyhow::Result<()> {
         .with_max_level(Level::INFO)
         .init();
 
+    let config = KernelConfig::from_env()?;
+
+    // `kiacha-kernel sign-policy` re-signs a hand-edited permission policy
+    if std::env::args().nth(1).as_deref() == Some("sign-policy") {
+        let path = config.policy_file();
+        permissions::sign_policy_file(&path, &kernel::policy_signing_key(&config)?)?;
+        info!("✓ Signed permission policy {:?}", path);
+        return Ok(());
+    }
+
     info!("🚀 Kiacha OS Kernel starting...");
 
     // Initialize kernel
-    let kernel = Arc::new(KiachaKernel::new().await?);
+    let kernel = Arc::new(KiachaKernel::new(config).await?);
 
-    // Create gRPC service
+    // Create gRPC services
     let svc = KiachaKernelService::new(kernel.clone());
+    let event_bus_svc = KiachaEventBusService::new(kernel.clone());
 
     // Start IPC gRPC server
     let addr: SocketAddr = "[::1]:50051".parse()?;
     info!("🌐 gRPC server listening on {}", addr);
 
+    // Every call is authenticated before it reaches a handler
+    let auth = AuthInterceptor::new(kernel.clone());
+
     Server::builder()
-        .add_service(proto::kiacha_kernel::kiacha_kernel_server::KiachaKernelServer::new(svc))
+        .add_service(InterceptedService::new(
+            proto::kiacha_kernel::kiacha_kernel_server::KiachaKernelServer::new(svc)
+                // Leave room for raw IPC frames (see ipc::ContentType::max_size)
+                .max_decoding_message_size(16 * 1024 * 1024),
+            auth.clone(),
+        ))
+        .add_service(InterceptedService::new(
+            proto::kiacha_event_bus_server::KiachaEventBusServer::new(event_bus_svc),
+            auth,
+        ))
         .serve(addr)
         .await?;
 
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use crate::wasm_runtime::{WasmControl, WasmInvocation, WasmOutput, WasmRuntime};
+
+/// How a module is executed, selected by `ModuleRequest.config["runtime"]`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ModuleRuntime {
+    Process,
+    Wasm,
+    /// Runs outside the kernel and connects with the credential returned by spawn
+    External,
+}
+
+/// Launch description parsed from `ModuleRequest.config`.
+///
+/// Recognised keys:
+/// - `runtime`: `process`, `wasm` or `external` (inferred from `command` /
+///   `wasm_path` when absent; neither means `external`)
+/// - `command`, `args`, `cwd`: child process to start; `args` is a JSON array or whitespace separated
+/// - `env.<NAME>`: extra environment variables for the child
+/// - `wasm_path`: WASM module executed through the kernel `WasmRuntime`; it gets
+///   `args` and `env.<NAME>` through WASI
+#[derive(Clone, Debug)]
+pub struct ModuleSpec {
+    pub runtime: ModuleRuntime,
+    pub command: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cwd: Option<String>,
+    pub wasm_path: Option<String>,
+}
+
+impl ModuleSpec {
+    pub fn from_config(config: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let runtime = match config.get("runtime").map(|s| s.as_str()) {
+            Some("process") => ModuleRuntime::Process,
+            Some("wasm") => ModuleRuntime::Wasm,
+            Some("external") => ModuleRuntime::External,
+            Some(other) => return Err(anyhow::anyhow!("Unknown module runtime: {}", other)),
+            None if config.contains_key("command") => ModuleRuntime::Process,
+            None if config.contains_key("wasm_path") => ModuleRuntime::Wasm,
+            None => ModuleRuntime::External,
+        };
+
+        let command = config.get("command").cloned().unwrap_or_default();
+        let wasm_path = config.get("wasm_path").cloned();
+        match runtime {
+            ModuleRuntime::Process if command.is_empty() => {
+                return Err(anyhow::anyhow!("Process modules require a `command`"))
+            }
+            ModuleRuntime::Wasm if wasm_path.is_none() => {
+                return Err(anyhow::anyhow!("WASM modules require a `wasm_path`"))
+            }
+            _ => {}
+        }
+
+        Ok(ModuleSpec {
+            runtime,
+            command,
+            args: config_args(config)?,
+            env: config_env(config),
+            cwd: config.get("cwd").cloned(),
+            wasm_path,
+        })
+    }
+}
+
+/// The `args` config key: a JSON array or whitespace separated
+pub fn config_args(config: &HashMap<String, String>) -> anyhow::Result<Vec<String>> {
+    Ok(match config.get("args") {
+        Some(raw) if raw.trim_start().starts_with('[') => serde_json::from_str(raw)?,
+        Some(raw) => raw.split_whitespace().map(String::from).collect(),
+        None => Vec::new(),
+    })
+}
+
+/// The `env.<NAME>` config keys
+pub fn config_env(config: &HashMap<String, String>) -> HashMap<String, String> {
+    config
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix("env.").map(|name| (name.to_string(), v.clone())))
+        .collect()
+}
+
+/// How a module stopped running.
+#[derive(Clone, Debug)]
+pub enum ModuleExit {
+    Code(i32),
+    Signal(i32),
+    Error(String),
+}
+
+impl ModuleExit {
+    pub fn is_success(&self) -> bool {
+        matches!(self, ModuleExit::Code(0))
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ModuleExit::Code(code) => format!("exited with code {}", code),
+            ModuleExit::Signal(signal) => format!("killed by signal {}", signal),
+            ModuleExit::Error(e) => format!("failed: {}", e),
+        }
+    }
+}
+
+/// Handle to a launched module.
+pub enum RunningModule {
+    Process(Child),
+    Wasm {
+        task: JoinHandle<anyhow::Result<WasmOutput>>,
+        control: Arc<WasmControl>,
+    },
+}
+
+impl RunningModule {
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            RunningModule::Process(child) => child.id(),
+            RunningModule::Wasm { .. } => None,
+        }
+    }
+
+    /// Suspension switch for WASM modules
+    pub fn wasm_control(&self) -> Option<Arc<WasmControl>> {
+        match self {
+            RunningModule::Process(_) => None,
+            RunningModule::Wasm { control, .. } => Some(control.clone()),
+        }
+    }
+
+    /// Wait for the module to stop and report how it ended
+    pub async fn wait(self) -> ModuleExit {
+        match self {
+            RunningModule::Process(mut child) => match child.wait().await {
+                Ok(status) => {
+                    if let Some(code) = status.code() {
+                        return ModuleExit::Code(code);
+                    }
+                    #[cfg(unix)]
+                    {
+                        use std::os::unix::process::ExitStatusExt;
+                        if let Some(signal) = status.signal() {
+                            return ModuleExit::Signal(signal);
+                        }
+                    }
+                    ModuleExit::Error("unknown exit status".to_string())
+                }
+                Err(e) => ModuleExit::Error(e.to_string()),
+            },
+            RunningModule::Wasm { task, .. } => match task.await {
+                Ok(Ok(output)) => ModuleExit::Code(output.exit_code),
+                Ok(Err(e)) => ModuleExit::Error(e.to_string()),
+                Err(e) => ModuleExit::Error(e.to_string()),
+            },
+        }
+    }
+}
+
+/// Start a module according to its spec
+pub async fn launch(
+    module_id: &str,
+    spec: &ModuleSpec,
+    wasm_runtime: Arc<WasmRuntime>,
+) -> anyhow::Result<RunningModule> {
+    match spec.runtime {
+        ModuleRuntime::Process => {
+            let mut command = Command::new(&spec.command);
+            command
+                .args(&spec.args)
+                .envs(&spec.env)
+                .env("KIACHA_MODULE_ID", module_id)
+                .stdin(Stdio::null())
+                .kill_on_drop(true);
+            if let Some(cwd) = &spec.cwd {
+                command.current_dir(cwd);
+            }
+            let child = command.spawn()?;
+            Ok(RunningModule::Process(child))
+        }
+        ModuleRuntime::External => Err(anyhow::anyhow!("External modules are not launched by the kernel")),
+        ModuleRuntime::Wasm => {
+            let path = spec.wasm_path.as_deref().unwrap_or_default();
+            let wasm_data = tokio::fs::read(path).await?;
+            let mut env: Vec<(String, String)> = spec.env.clone().into_iter().collect();
+            env.push(("KIACHA_MODULE_ID".to_string(), module_id.to_string()));
+            let invocation = WasmInvocation {
+                program: module_id.to_string(),
+                args: spec.args.clone(),
+                env,
+                capture_output: false,
+                cpu_limit: false,
+            };
+            let control = Arc::new(WasmControl::new());
+            let guest_control = control.clone();
+            // Guests block their thread while paused, so keep them off the async workers
+            let task = tokio::task::spawn_blocking(move || {
+                let module = wasm_runtime.compile(&wasm_data)?;
+                wasm_runtime.execute_blocking(&module, invocation, Some(guest_control))
+            });
+            Ok(RunningModule::Wasm { task, control })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn runtime_is_inferred_from_config() {
+        let process = ModuleSpec::from_config(&config(&[("command", "/bin/true")])).unwrap();
+        assert_eq!(process.runtime, ModuleRuntime::Process);
+        let wasm = ModuleSpec::from_config(&config(&[("wasm_path", "m.wasm")])).unwrap();
+        assert_eq!(wasm.runtime, ModuleRuntime::Wasm);
+        let external = ModuleSpec::from_config(&config(&[("version", "0.1.0")])).unwrap();
+        assert_eq!(external.runtime, ModuleRuntime::External);
+
+        assert!(ModuleSpec::from_config(&config(&[("runtime", "process")])).is_err());
+        assert!(ModuleSpec::from_config(&config(&[("runtime", "wasm")])).is_err());
+        assert!(ModuleSpec::from_config(&config(&[("runtime", "docker")])).is_err());
+    }
+
+    #[test]
+    fn args_and_env_are_parsed() {
+        let spec = ModuleSpec::from_config(&config(&[
+            ("command", "/usr/bin/env"),
+            ("args", r#"["-i", "a b"]"#),
+            ("env.MODE", "fast"),
+            ("cwd", "/tmp"),
+        ]))
+        .unwrap();
+        assert_eq!(spec.args, vec!["-i", "a b"]);
+        assert_eq!(spec.env.get("MODE").map(String::as_str), Some("fast"));
+        assert_eq!(spec.cwd.as_deref(), Some("/tmp"));
+
+        let spec = ModuleSpec::from_config(&config(&[("command", "x"), ("args", "one  two")])).unwrap();
+        assert_eq!(spec.args, vec!["one", "two"]);
+        assert!(ModuleSpec::from_config(&config(&[("command", "x"), ("args", "[oops")])).is_err());
+    }
+}
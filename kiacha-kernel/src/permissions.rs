@@ -1,6 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use crate::user_store::open_private;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Permission {
     SendIpc,
     RunWasm,
@@ -9,55 +16,531 @@ pub enum Permission {
     AccessAudio,
     SystemCall,
     Admin,
+    PublishEvents,
 }
 
+impl Permission {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        serde_json::from_value(serde_json::Value::String(name.trim().to_string()))
+            .map_err(|_| anyhow::anyhow!("Unknown permission: {}", name))
+    }
+
+    /// Whether the kernel checks this permission against a resource, so a
+    /// grant of it can be limited by scope
+    pub fn is_scoped(&self) -> bool {
+        matches!(self, Permission::SendIpc | Permission::RunWasm | Permission::SystemCall)
+    }
+}
+
+/// A permission granted to a module, optionally limited to named resources and
+/// to a point in time.
+///
+/// Scope entries name what the grant covers for its permission: target module
+/// ids for `SendIpc`, host imports (`module::name`) for `SystemCall`, module
+/// content hashes for `RunWasm`. Other permissions are not checked against a
+/// resource and cannot be scoped. A trailing `*` matches by prefix. An empty
+/// scope covers everything.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "GrantRepr", into = "GrantRepr")]
+pub struct Grant {
+    pub permission: Permission,
+    pub scope: BTreeSet<String>,
+    /// Unix millis after which the grant no longer applies
+    pub expires_at: Option<i64>,
+}
+
+impl Grant {
+    pub fn unrestricted(permission: Permission) -> Self {
+        Grant {
+            permission,
+            scope: BTreeSet::new(),
+            expires_at: None,
+        }
+    }
+
+    /// Refuse a scope on a permission the kernel never checks against a resource
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.scope.is_empty() && !self.permission.is_scoped() {
+            return Err(anyhow::anyhow!("{:?} grants cannot be scoped", self.permission));
+        }
+        Ok(())
+    }
+
+    /// Parse a module manifest: a comma separated list of permissions, each
+    /// optionally limited to `|` separated scope entries, such as
+    /// `send_ipc=memory-*|vision,publish_events`
+    pub fn parse_manifest(raw: &str) -> anyhow::Result<Vec<Grant>> {
+        raw.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (name, scope) = entry.split_once('=').unwrap_or((entry, ""));
+                let grant = Grant {
+                    permission: Permission::parse(name)?,
+                    scope: scope.split('|').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect(),
+                    expires_at: None,
+                };
+                grant.validate()?;
+                Ok(grant)
+            })
+            .collect()
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.map_or(false, |at| at <= now)
+    }
+
+    /// Whether this grant allows `permission` on `resource` at time `now`.
+    /// A scoped grant never covers a request that names no resource.
+    pub fn covers(&self, permission: &Permission, resource: Option<&str>, now: i64) -> bool {
+        if &self.permission != permission || self.is_expired(now) {
+            return false;
+        }
+        if self.scope.is_empty() {
+            return true;
+        }
+        match resource {
+            Some(resource) => self.scope.iter().any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => resource.starts_with(prefix),
+                None => allowed == resource,
+            }),
+            None => false,
+        }
+    }
+}
+
+/// Unscoped, permanent grants are stored as a bare permission name so policy
+/// files written before scopes existed keep their signature.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum GrantRepr {
+    Plain(Permission),
+    Scoped {
+        permission: Permission,
+        #[serde(default)]
+        scope: BTreeSet<String>,
+        #[serde(default)]
+        expires_at: Option<i64>,
+    },
+}
+
+impl From<GrantRepr> for Grant {
+    fn from(repr: GrantRepr) -> Self {
+        match repr {
+            GrantRepr::Plain(permission) => Grant::unrestricted(permission),
+            GrantRepr::Scoped {
+                permission,
+                scope,
+                expires_at,
+            } => Grant {
+                permission,
+                scope,
+                expires_at,
+            },
+        }
+    }
+}
+
+impl From<Grant> for GrantRepr {
+    fn from(grant: Grant) -> Self {
+        if grant.scope.is_empty() && grant.expires_at.is_none() {
+            GrantRepr::Plain(grant.permission)
+        } else {
+            GrantRepr::Scoped {
+                permission: grant.permission,
+                scope: grant.scope,
+                expires_at: grant.expires_at,
+            }
+        }
+    }
+}
+
+/// The persisted part of the permission state.
+///
+/// `module_types` is the ceiling of what a module of each type may be granted
+/// from its manifest; `grants` are explicit grants made through the API, keyed
+/// by module name so they outlive any one spawn of the module.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Policy {
+    module_types: BTreeMap<String, BTreeSet<Permission>>,
+    grants: BTreeMap<String, Vec<Grant>>,
+}
+
+impl Policy {
+    /// Policy written on first start
+    fn initial() -> Self {
+        let mut policy = Policy::default();
+        policy.module_types.insert(
+            "brain".to_string(),
+            [
+                Permission::SendIpc,
+                Permission::RunWasm,
+                Permission::AccessMemory,
+                Permission::AccessVision,
+                Permission::AccessAudio,
+            ]
+            .into_iter()
+            .collect(),
+        );
+        policy.module_types.insert(
+            "interface".to_string(),
+            [Permission::SendIpc, Permission::AccessVision, Permission::AccessAudio]
+                .into_iter()
+                .collect(),
+        );
+        policy
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SignedPolicy {
+    policy: Policy,
+    /// Hex HMAC-SHA256 of the serialized `policy`
+    signature: String,
+}
+
+/// Module permissions, default deny.
+///
+/// Explicit grants are persisted to a policy file signed with a kernel key; a
+/// file whose signature does not verify is refused at startup (re-sign a
+/// hand-edited file with `kiacha-kernel sign-policy`). Grants derived from a
+/// module's manifest at spawn live only in memory.
 pub struct PermissionManager {
-    permissions: dashmap::DashMap<String, Vec<Permission>>,
+    /// Manifest grants of running modules, by module id
+    permissions: dashmap::DashMap<String, Vec<Grant>>,
+    /// Module id -> name under which its persisted grants are kept
+    names: dashmap::DashMap<String, String>,
+    policy: parking_lot::Mutex<Policy>,
+    path: PathBuf,
+    key: Vec<u8>,
 }
 
 impl PermissionManager {
-    pub fn new() -> Self {
+    pub fn load(path: &Path, key: &[u8]) -> anyhow::Result<Self> {
+        let policy = if path.exists() {
+            let signed: SignedPolicy = serde_json::from_slice(&std::fs::read(path)?)?;
+            let expected = hex::decode(&signed.signature)
+                .map_err(|_| anyhow::anyhow!("Malformed signature in {:?}", path))?;
+            policy_mac(key, &signed.policy)?
+                .verify_slice(&expected)
+                .map_err(|_| anyhow::anyhow!("Permission policy {:?} failed signature check", path))?;
+            signed.policy
+        } else {
+            Policy::initial()
+        };
+
         let pm = PermissionManager {
             permissions: dashmap::DashMap::new(),
+            names: dashmap::DashMap::new(),
+            policy: parking_lot::Mutex::new(policy),
+            path: path.to_path_buf(),
+            key: key.to_vec(),
         };
-        
-        // Default permissions for known modules
-        pm.permissions.insert("brain".to_string(), vec![
-            Permission::SendIpc,
-            Permission::RunWasm,
-            Permission::AccessMemory,
-            Permission::AccessVision,
-            Permission::AccessAudio,
-        ]);
-
-        pm.permissions.insert("interface".to_string(), vec![
-            Permission::SendIpc,
-            Permission::AccessVision,
-            Permission::AccessAudio,
-        ]);
-
-        pm
-    }
-
-    pub fn check(&self, module_id: &str, permission: Permission) -> anyhow::Result<()> {
-        if let Some(perms) = self.permissions.get(module_id) {
-            if perms.contains(&permission) {
+        pm.persist(&pm.policy.lock())?;
+        Ok(pm)
+    }
+
+    /// Key of a module's persisted grants: its name while it is known, otherwise
+    /// whatever the caller named it by
+    fn principal(&self, module: &str) -> String {
+        self.names
+            .get(module)
+            .map(|name| name.clone())
+            .unwrap_or_else(|| module.to_string())
+    }
+
+    /// Check `permission` for a module, on `resource` when the action targets one
+    pub fn check(&self, module_id: &str, permission: Permission, resource: Option<&str>) -> anyhow::Result<()> {
+        let now = chrono::Local::now().timestamp_millis();
+        if let Some(grants) = self.permissions.get(module_id) {
+            if grants.iter().any(|g| g.covers(&permission, resource, now)) {
                 return Ok(());
             }
         }
-        Err(anyhow::anyhow!("Permission denied for {}: {:?}", module_id, permission))
+        if let Some(grants) = self.policy.lock().grants.get(&self.principal(module_id)) {
+            if grants.iter().any(|g| g.covers(&permission, resource, now)) {
+                return Ok(());
+            }
+        }
+        match resource {
+            Some(resource) => Err(anyhow::anyhow!(
+                "Permission denied for {}: {:?} on {}",
+                module_id,
+                permission,
+                resource
+            )),
+            None => Err(anyhow::anyhow!("Permission denied for {}: {:?}", module_id, permission)),
+        }
     }
 
-    pub fn grant(&self, module_id: &str, permission: Permission) {
-        self.permissions
-            .entry(module_id.to_string())
-            .or_insert_with(Vec::new)
-            .push(permission);
+    /// Whether grants are persisted under a module name
+    pub fn has_saved_grants(&self, module_name: &str) -> bool {
+        self.policy.lock().grants.contains_key(module_name)
     }
 
-    pub fn revoke(&self, module_id: &str, permission: Permission) {
-        if let Some(mut perms) = self.permissions.get_mut(module_id) {
-            perms.retain(|p| p != &permission);
+    /// Grant the permissions a module requested at spawn, limited to what the
+    /// policy allows for its type, and attach the persisted grants kept under
+    /// `module_name`. Returns the requested permissions that were denied.
+    ///
+    /// Fails when another running module already has the name, as the two
+    /// would share its persisted grants.
+    pub fn apply_manifest(
+        &self,
+        module_id: &str,
+        module_name: &str,
+        module_type: &str,
+        requested: &[Grant],
+    ) -> anyhow::Result<Vec<Permission>> {
+        // Held while the name is claimed so two spawns cannot both take it
+        let policy = self.policy.lock();
+        if self.names.iter().any(|entry| entry.key() != module_id && entry.value() == module_name) {
+            return Err(anyhow::anyhow!("A module named {} is already running", module_name));
         }
+        let allowed = policy.module_types.get(module_type).cloned().unwrap_or_default();
+
+        let (granted, denied): (Vec<Grant>, Vec<Grant>) =
+            requested.iter().cloned().partition(|g| allowed.contains(&g.permission));
+        self.permissions.insert(module_id.to_string(), granted);
+        self.names.insert(module_id.to_string(), module_name.to_string());
+        Ok(denied.into_iter().map(|g| g.permission).collect())
+    }
+
+    /// Forget the manifest grants of a module that is gone; its persisted
+    /// grants stay with its name
+    pub fn clear_module(&self, module_id: &str) {
+        self.permissions.remove(module_id);
+        self.names.remove(module_id);
+    }
+
+    /// Add a grant for a running module's id or for a module name; a grant with
+    /// the same permission and scope is replaced
+    pub fn grant(&self, module: &str, grant: Grant) -> anyhow::Result<()> {
+        grant.validate()?;
+        let mut policy = self.policy.lock();
+        let grants = policy.grants.entry(self.principal(module)).or_insert_with(Vec::new);
+        grants.retain(|g| !(g.permission == grant.permission && g.scope == grant.scope));
+        grants.push(grant);
+        self.persist(&policy)
+    }
+
+    /// Drop expired grants from the policy, returning what was removed
+    pub fn purge_expired(&self) -> anyhow::Result<Vec<(String, Grant)>> {
+        let now = chrono::Local::now().timestamp_millis();
+        let mut policy = self.policy.lock();
+        let mut expired = Vec::new();
+        for (module_id, grants) in policy.grants.iter_mut() {
+            grants.retain(|g| {
+                if g.is_expired(now) {
+                    expired.push((module_id.clone(), g.clone()));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        if !expired.is_empty() {
+            policy.grants.retain(|_, grants| !grants.is_empty());
+            self.persist(&policy)?;
+        }
+        Ok(expired)
+    }
+
+    /// Revoke a permission from a running module's id or from a module name
+    pub fn revoke(&self, module: &str, permission: Permission) -> anyhow::Result<()> {
+        if let Some(mut grants) = self.permissions.get_mut(module) {
+            grants.retain(|g| g.permission != permission);
+        }
+        let principal = self.principal(module);
+        let mut policy = self.policy.lock();
+        if let Some(grants) = policy.grants.get_mut(&principal) {
+            grants.retain(|g| g.permission != permission);
+            if grants.is_empty() {
+                policy.grants.remove(&principal);
+            }
+        }
+        self.persist(&policy)
+    }
+
+    fn persist(&self, policy: &Policy) -> anyhow::Result<()> {
+        write_signed(&self.path, &self.key, policy)
+    }
+}
+
+/// Write the signed policy atomically
+fn write_signed(path: &Path, key: &[u8], policy: &Policy) -> anyhow::Result<()> {
+    let signature = hex::encode(policy_mac(key, policy)?.finalize().into_bytes());
+    let signed = SignedPolicy {
+        policy: policy.clone(),
+        signature,
+    };
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(&signed)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Re-sign a policy file after an operator edited it by hand.
+///
+/// Accepts either a signed policy, whose stale signature is ignored, or a bare
+/// `{"module_types": ..., "grants": ...}` document.
+pub fn sign_policy_file(path: &Path, key: &[u8]) -> anyhow::Result<()> {
+    let raw: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    let policy: Policy = match raw.get("policy") {
+        Some(policy) => serde_json::from_value(policy.clone())?,
+        None => serde_json::from_value(raw)?,
+    };
+    write_signed(path, key, &policy)
+}
+
+fn policy_mac(key: &[u8], policy: &Policy) -> anyhow::Result<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|_| anyhow::anyhow!("Invalid policy signing key"))?;
+    mac.update(&serde_json::to_vec(policy)?);
+    Ok(mac)
+}
+
+/// Load a signing key, creating a random one on first start. A new key file
+/// is readable only by its owner from the moment it exists.
+pub fn load_or_create_key(path: &Path) -> anyhow::Result<Vec<u8>> {
+    if path.exists() {
+        return Ok(hex::decode(std::fs::read_to_string(path)?.trim())?);
+    }
+
+    let key: [u8; 32] = rand::random();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = open_private(std::fs::OpenOptions::new().write(true).create_new(true), path)?;
+    file.write_all(hex::encode(key).as_bytes())?;
+    file.sync_all()?;
+    Ok(key.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const KEY: &[u8] = b"test-policy-key";
+
+    fn scoped(permission: Permission, scope: &[&str], expires_at: Option<i64>) -> Grant {
+        Grant {
+            permission,
+            scope: scope.iter().map(|s| s.to_string()).collect(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn grant_covers_scope_and_prefix() {
+        let grant = scoped(Permission::SendIpc, &["memory", "vision-*"], None);
+        assert!(grant.covers(&Permission::SendIpc, Some("memory"), 0));
+        assert!(grant.covers(&Permission::SendIpc, Some("vision-left"), 0));
+        assert!(!grant.covers(&Permission::SendIpc, Some("memory-2"), 0));
+        assert!(!grant.covers(&Permission::SendIpc, None, 0));
+        assert!(!grant.covers(&Permission::RunWasm, Some("memory"), 0));
+
+        let any = Grant::unrestricted(Permission::SendIpc);
+        assert!(any.covers(&Permission::SendIpc, Some("anything"), 0));
+        assert!(any.covers(&Permission::SendIpc, None, 0));
+    }
+
+    #[test]
+    fn grant_expires_at_its_deadline() {
+        let grant = scoped(Permission::PublishEvents, &[], Some(1_000));
+        assert!(grant.covers(&Permission::PublishEvents, None, 999));
+        assert!(!grant.covers(&Permission::PublishEvents, None, 1_000));
+        assert!(grant.is_expired(1_001));
+    }
+
+    #[test]
+    fn grant_repr_round_trips() {
+        let plain = Grant::unrestricted(Permission::RunWasm);
+        assert_eq!(serde_json::to_value(&plain).unwrap(), serde_json::json!("run_wasm"));
+
+        let grants = vec![
+            plain,
+            scoped(Permission::SystemCall, &["wasi_snapshot_preview1::fd_write"], None),
+            scoped(Permission::AccessAudio, &[], Some(42)),
+        ];
+        let json = serde_json::to_string(&grants).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Grant>>(&json).unwrap(), grants);
+
+        let legacy: Grant = serde_json::from_str(r#"{"permission":"send_ipc"}"#).unwrap();
+        assert_eq!(legacy, Grant::unrestricted(Permission::SendIpc));
+    }
+
+    #[test]
+    fn manifest_grants_are_scoped() {
+        let manifest = Grant::parse_manifest("send_ipc=memory|vision-*, publish_events").unwrap();
+        assert_eq!(manifest[0], scoped(Permission::SendIpc, &["memory", "vision-*"], None));
+        assert_eq!(manifest[1], Grant::unrestricted(Permission::PublishEvents));
+        assert!(Grant::parse_manifest("access_memory=notes").is_err());
+        assert!(Grant::parse_manifest("teleport").is_err());
+
+        let dir = TempDir::new("permissions-test");
+        let path = dir.join("permissions.json");
+        let pm = PermissionManager::load(&path, KEY).unwrap();
+        let denied = pm.apply_manifest("id", "name", "brain", &manifest).unwrap();
+        assert_eq!(denied, vec![Permission::PublishEvents]);
+        assert!(pm.check("id", Permission::SendIpc, Some("vision-right")).is_ok());
+        assert!(pm.check("id", Permission::SendIpc, Some("audio")).is_err());
+        assert!(pm.grant("id", scoped(Permission::AccessMemory, &["notes"], None)).is_err());
+    }
+
+    #[test]
+    fn persisted_grants_follow_the_module_name_across_spawns() {
+        let dir = TempDir::new("permissions-test");
+        let path = dir.join("permissions.json");
+        let pm = PermissionManager::load(&path, KEY).unwrap();
+        pm.apply_manifest("first-spawn", "vision", "brain", &[]).unwrap();
+        assert!(!pm.has_saved_grants("vision"));
+        pm.grant("first-spawn", Grant::unrestricted(Permission::PublishEvents)).unwrap();
+        pm.clear_module("first-spawn");
+        assert!(pm.check("first-spawn", Permission::PublishEvents, None).is_err());
+
+        let pm = PermissionManager::load(&path, KEY).unwrap();
+        assert!(pm.has_saved_grants("vision"));
+        pm.apply_manifest("second-spawn", "vision", "brain", &[]).unwrap();
+        assert!(pm.check("second-spawn", Permission::PublishEvents, None).is_ok());
+        pm.apply_manifest("other", "audio", "brain", &[]).unwrap();
+        assert!(pm.check("other", Permission::PublishEvents, None).is_err());
+        assert!(pm.apply_manifest("third-spawn", "vision", "brain", &[]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn created_keys_are_private_and_reused() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("permissions-test");
+        let path = dir.join("policy.key");
+        let key = load_or_create_key(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_or_create_key(&path).unwrap(), key);
+    }
+
+    #[test]
+    fn hand_edited_policy_is_refused_until_signed() {
+        let dir = TempDir::new("permissions-test");
+        let path = dir.join("permissions.json");
+        PermissionManager::load(&path, KEY).unwrap();
+
+        let mut signed: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        signed["policy"]["grants"]["vision"] = serde_json::json!(["admin"]);
+        std::fs::write(&path, serde_json::to_vec(&signed).unwrap()).unwrap();
+        assert!(PermissionManager::load(&path, KEY).is_err());
+
+        sign_policy_file(&path, KEY).unwrap();
+        let pm = PermissionManager::load(&path, KEY).unwrap();
+        pm.apply_manifest("id", "vision", "brain", &[]).unwrap();
+        assert!(pm.check("id", Permission::Admin, None).is_ok());
+        assert!(PermissionManager::load(&path, b"another-key").is_err());
     }
 }
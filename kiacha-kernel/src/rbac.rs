@@ -0,0 +1,360 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::user_manager::User;
+
+/// Roles a `User.role` can name. Unknown role names are treated as `Guest`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Operator,
+    Guest,
+}
+
+impl Role {
+    pub fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.trim().to_lowercase())).ok()
+    }
+
+    pub fn of(user: &User) -> Self {
+        Role::parse(&user.role).unwrap_or(Role::Guest)
+    }
+
+    /// Name as stored in `User.role`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::Guest => "guest",
+        }
+    }
+}
+
+/// Areas of the kernel API, one per section of the `KiachaKernel` service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum App {
+    Modules,
+    Ipc,
+    Events,
+    Permissions,
+    Resources,
+    Wasm,
+    Audit,
+    System,
+    Files,
+    Processes,
+    Network,
+    Users,
+    Updates,
+    Security,
+}
+
+impl App {
+    pub const ALL: [App; 14] = [
+        App::Modules,
+        App::Ipc,
+        App::Events,
+        App::Permissions,
+        App::Resources,
+        App::Wasm,
+        App::Audit,
+        App::System,
+        App::Files,
+        App::Processes,
+        App::Network,
+        App::Users,
+        App::Updates,
+        App::Security,
+    ];
+
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        serde_json::from_value(serde_json::Value::String(name.trim().to_string()))
+            .map_err(|_| anyhow::anyhow!("Unknown app: {}", name))
+    }
+
+    /// Name as used in `UserPermission.app`
+    pub fn name(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
+}
+
+/// What a user may do within an app. Levels are independent: `Write` does not
+/// imply `Read`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Access {
+    Read,
+    Write,
+    Manage,
+}
+
+impl Access {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        serde_json::from_value(serde_json::Value::String(name.trim().to_string()))
+            .map_err(|_| anyhow::anyhow!("Unknown access level: {}", name))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Manage => "manage",
+        }
+    }
+}
+
+pub type AppAccess = BTreeMap<App, BTreeSet<Access>>;
+
+/// Who is behind a kernel API call
+#[derive(Clone)]
+pub enum Caller {
+    /// A spawned module, authenticated by its credential
+    Module(String),
+    /// A user, authenticated by a session token from `Login`
+    User { user: User, session_id: String },
+    /// No credentials; evaluated as a guest
+    Anonymous,
+}
+
+impl Caller {
+    /// Name recorded in the audit log
+    pub fn describe(&self) -> String {
+        match self {
+            Caller::Module(id) => format!("module:{}", id),
+            Caller::User { user, .. } => format!("user:{}", user.username),
+            Caller::Anonymous => "anonymous".to_string(),
+        }
+    }
+}
+
+/// A caller lacks the access an operation needs
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct AccessDenied(pub String);
+
+/// Per-user changes on top of the user's role
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct UserOverride {
+    #[serde(default)]
+    granted: AppAccess,
+    #[serde(default)]
+    revoked: AppAccess,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct AccessPolicy {
+    roles: BTreeMap<Role, AppAccess>,
+    #[serde(default)]
+    users: BTreeMap<String, UserOverride>,
+}
+
+impl AccessPolicy {
+    /// Role definitions written on first start
+    fn initial() -> Self {
+        use Access::*;
+
+        let all: BTreeSet<Access> = [Read, Write, Manage].into_iter().collect();
+        let read_write: BTreeSet<Access> = [Read, Write].into_iter().collect();
+        let read: BTreeSet<Access> = [Read].into_iter().collect();
+
+        let admin = App::ALL.iter().map(|app| (*app, all.clone())).collect();
+        let operator = App::ALL
+            .iter()
+            .map(|app| match app {
+                App::Permissions | App::Audit | App::Users | App::Security | App::Resources | App::System => {
+                    (*app, read.clone())
+                }
+                _ => (*app, read_write.clone()),
+            })
+            .collect();
+        let guest = [(App::Resources, read.clone()), (App::System, read)]
+            .into_iter()
+            .collect();
+
+        AccessPolicy {
+            roles: [(Role::Admin, admin), (Role::Operator, operator), (Role::Guest, guest)]
+                .into_iter()
+                .collect(),
+            users: BTreeMap::new(),
+        }
+    }
+
+    /// Role permissions plus the user's grants, minus the user's revocations
+    fn effective(&self, user_id: &str, role: Role) -> AppAccess {
+        let mut access = self.roles.get(&role).cloned().unwrap_or_default();
+        if let Some(overrides) = self.users.get(user_id) {
+            for (app, levels) in &overrides.granted {
+                access.entry(*app).or_default().extend(levels.iter().copied());
+            }
+            for (app, levels) in &overrides.revoked {
+                if let Some(current) = access.get_mut(app) {
+                    current.retain(|l| !levels.contains(l));
+                }
+            }
+        }
+        access.retain(|_, levels| !levels.is_empty());
+        access
+    }
+}
+
+/// Role-based access control for users of the kernel API.
+///
+/// Role definitions and per-user overrides are kept in a policy file; roles
+/// are edited there, overrides through the Users app. Modules are not users: their access is governed
+/// by `PermissionManager`, and through the API they may only use the apps
+/// `module_allows` lists.
+pub struct AccessControl {
+    policy: parking_lot::Mutex<AccessPolicy>,
+    path: PathBuf,
+}
+
+impl AccessControl {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let policy = if path.exists() {
+            serde_json::from_slice(&std::fs::read(path)?)?
+        } else {
+            AccessPolicy::initial()
+        };
+
+        let access = AccessControl {
+            policy: parking_lot::Mutex::new(policy),
+            path: path.to_path_buf(),
+        };
+        access.persist(&access.policy.lock())?;
+        Ok(access)
+    }
+
+    /// Whether `caller` may use `app` at `access` level
+    pub fn allows(&self, caller: &Caller, app: App, access: Access) -> bool {
+        match caller {
+            Caller::Module(_) => module_allows(app, access),
+            Caller::User { user, .. } if user.active => self
+                .effective(&user.id, Role::of(user))
+                .get(&app)
+                .map_or(false, |levels| levels.contains(&access)),
+            Caller::User { .. } | Caller::Anonymous => self
+                .policy
+                .lock()
+                .roles
+                .get(&Role::Guest)
+                .and_then(|apps| apps.get(&app))
+                .map_or(false, |levels| levels.contains(&access)),
+        }
+    }
+
+    pub fn effective(&self, user_id: &str, role: Role) -> AppAccess {
+        self.policy.lock().effective(user_id, role)
+    }
+
+    /// Give a user access levels on an app beyond their role
+    pub fn grant(&self, user_id: &str, app: App, levels: &[Access]) -> anyhow::Result<()> {
+        let mut policy = self.policy.lock();
+        let overrides = policy.users.entry(user_id.to_string()).or_default();
+        if let Some(revoked) = overrides.revoked.get_mut(&app) {
+            revoked.retain(|l| !levels.contains(l));
+        }
+        overrides.granted.entry(app).or_default().extend(levels.iter().copied());
+        Self::compact(&mut policy, user_id);
+        self.persist(&policy)
+    }
+
+    /// Take access levels on an app away from a user, including ones their role
+    /// gives them. An empty `levels` revokes every level.
+    pub fn revoke(&self, user_id: &str, app: App, levels: &[Access]) -> anyhow::Result<()> {
+        let levels: Vec<Access> = if levels.is_empty() {
+            vec![Access::Read, Access::Write, Access::Manage]
+        } else {
+            levels.to_vec()
+        };
+
+        let mut policy = self.policy.lock();
+        let overrides = policy.users.entry(user_id.to_string()).or_default();
+        if let Some(granted) = overrides.granted.get_mut(&app) {
+            granted.retain(|l| !levels.contains(l));
+        }
+        overrides.revoked.entry(app).or_default().extend(levels);
+        Self::compact(&mut policy, user_id);
+        self.persist(&policy)
+    }
+
+    /// Drop the overrides of a user that no longer exists
+    pub fn forget_user(&self, user_id: &str) -> anyhow::Result<()> {
+        let mut policy = self.policy.lock();
+        if policy.users.remove(user_id).is_some() {
+            self.persist(&policy)?;
+        }
+        Ok(())
+    }
+
+    fn compact(policy: &mut AccessPolicy, user_id: &str) {
+        if let Some(overrides) = policy.users.get_mut(user_id) {
+            overrides.granted.retain(|_, levels| !levels.is_empty());
+            overrides.revoked.retain(|_, levels| !levels.is_empty());
+            if overrides.granted.is_empty() && overrides.revoked.is_empty() {
+                policy.users.remove(user_id);
+            }
+        }
+    }
+
+    /// Write the policy atomically
+    fn persist(&self, policy: &AccessPolicy) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(policy)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+/// API areas a module may call into. What a module can actually do there is
+/// decided by its `PermissionManager` grants.
+pub fn module_allows(app: App, access: Access) -> bool {
+    match app {
+        App::Ipc | App::Events | App::Wasm => access != Access::Manage,
+        App::Resources | App::System => access == Access::Read,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn effective_applies_overrides_to_role() {
+        let mut policy = AccessPolicy::initial();
+        let mut overrides = UserOverride::default();
+        overrides.granted.insert(App::Audit, [Access::Manage].into_iter().collect());
+        overrides.revoked.insert(App::Modules, [Access::Write].into_iter().collect());
+        policy.users.insert("u1".to_string(), overrides);
+
+        let access = policy.effective("u1", Role::Operator);
+        assert_eq!(access[&App::Audit], [Access::Read, Access::Manage].into_iter().collect());
+        assert_eq!(access[&App::Modules], [Access::Read].into_iter().collect());
+
+        // Other users of the same role are unaffected
+        let other = policy.effective("u2", Role::Operator);
+        assert!(other[&App::Modules].contains(&Access::Write));
+    }
+
+    #[test]
+    fn guest_is_read_only() {
+        let policy = AccessPolicy::initial();
+        let access = policy.effective("anyone", Role::Guest);
+        assert!(access.values().all(|levels| levels.iter().all(|l| *l == Access::Read)));
+        assert!(!access.contains_key(&App::Users));
+    }
+
+    #[test]
+    fn unknown_role_is_guest() {
+        assert_eq!(Role::parse("Admin"), Some(Role::Admin));
+        assert_eq!(Role::parse("superuser"), None);
+    }
+}
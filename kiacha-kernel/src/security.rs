@@ -1,24 +1,941 @@
-use parking_lot::Mutex;
-use std::sync::Arc;
+use hmac::{Hmac, Mac};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Lines, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use tokio::sync::broadcast;
+use tracing::warn;
 
+/// `prev_hash` of the first record ever written
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Actor of records the kernel writes on its own behalf
+pub const KERNEL_ACTOR: &str = "kernel";
+
+/// Records a live subscriber may fall behind by before it has to catch up from disk
+const LIVE_BUFFER: usize = 1024;
+
+/// Signed position of the newest record written
+const HEAD_FILE: &str = "head.json";
+/// Signed position of the oldest record retention kept
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// Failed actions that call for attention beyond an ordinary failure
+const CRITICAL_ACTIONS: &[&str] = &["audit_verify", "module_restart_give_up", "permission_manifest"];
+
+/// How much attention a record deserves, derived from what it records
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// One audited action. Each record carries the hash of the one before it, so
+/// changing, removing or reordering records breaks the chain. Hashes are
+/// HMACs under a kernel key, so the chain cannot be rebuilt without it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub sequence: u64,
+    pub timestamp: i64,
+    /// Who acted, as `Caller::describe` names them
+    pub actor: String,
+    pub action: String,
+    /// What was acted on, such as a module or user id; empty when nothing specific
+    pub target: String,
+    pub success: bool,
+    pub details: String,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+/// The hashed fields of a record, in a fixed order
+#[derive(Serialize)]
+struct HashInput<'a> {
+    sequence: u64,
+    timestamp: i64,
+    actor: &'a str,
+    action: &'a str,
+    target: &'a str,
+    success: bool,
+    details: &'a str,
+    prev_hash: &'a str,
+}
+
+impl AuditRecord {
+    /// Area the action belongs to: its first `_`-separated word, e.g. `ipc`
+    pub fn event_type(&self) -> &str {
+        self.action.split('_').next().unwrap_or_default()
+    }
+
+    /// Successful actions are informational and failures warnings, except
+    /// for the failures listed in `CRITICAL_ACTIONS`
+    pub fn severity(&self) -> Severity {
+        if self.success {
+            Severity::Info
+        } else if CRITICAL_ACTIONS.contains(&self.action.as_str()) {
+            Severity::Critical
+        } else {
+            Severity::Warning
+        }
+    }
+
+    /// Whether the record is about a module, as the actor or the target
+    pub fn concerns_module(&self, module_id: &str) -> bool {
+        self.target == module_id || self.actor.strip_prefix("module:") == Some(module_id)
+    }
+
+    fn compute_hash(&self, key: &[u8]) -> String {
+        let input = HashInput {
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            actor: &self.actor,
+            action: &self.action,
+            target: &self.target,
+            success: self.success,
+            details: &self.details,
+            prev_hash: &self.prev_hash,
+        };
+        let bytes = serde_json::to_vec(&input).unwrap_or_default();
+        hex::encode(audit_mac(key, &bytes).finalize().into_bytes())
+    }
+}
+
+/// Which records a query returns. Actor and action match exactly, or by prefix
+/// with a trailing `*`; empty fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    /// Only records about this module (see `AuditRecord::concerns_module`)
+    pub module_id: Option<String>,
+    pub min_severity: Option<Severity>,
+    /// Only records with this sequence number or later
+    pub from_sequence: Option<u64>,
+    /// Unix millis, inclusive
+    pub since: Option<i64>,
+    /// Unix millis, exclusive
+    pub until: Option<i64>,
+    /// Return only the most recent this many matches
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        fn field(pattern: &Option<String>, value: &str) -> bool {
+            match pattern.as_deref() {
+                None | Some("") => true,
+                Some(p) => match p.strip_suffix('*') {
+                    Some(prefix) => value.starts_with(prefix),
+                    None => p == value,
+                },
+            }
+        }
+
+        field(&self.actor, &record.actor)
+            && field(&self.action, &record.action)
+            && self
+                .module_id
+                .as_deref()
+                .map_or(true, |id| id.is_empty() || record.concerns_module(id))
+            && self.min_severity.map_or(true, |min| record.severity() >= min)
+            && self.from_sequence.map_or(true, |from| record.sequence >= from)
+            && self.since.map_or(true, |since| record.timestamp >= since)
+            && self.until.map_or(true, |until| record.timestamp < until)
+    }
+}
+
+/// Result of checking the stored chain
+#[derive(Clone, Debug, Default)]
+pub struct ChainReport {
+    /// Records checked
+    pub records: u64,
+    pub first_sequence: Option<u64>,
+    pub last_sequence: Option<u64>,
+    /// Sequence number at or after which the chain does not hold
+    pub broken_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl ChainReport {
+    pub fn valid(&self) -> bool {
+        self.error.is_none()
+    }
+
+    fn broken(mut self, sequence: u64, error: String) -> Self {
+        self.broken_at = Some(sequence);
+        self.error = Some(error);
+        self
+    }
+}
+
+/// Size of audit segments and how many are kept. Whole segments are deleted,
+/// oldest first; a signed checkpoint records where the chain then starts.
+#[derive(Clone, Copy, Debug)]
+pub struct AuditRetention {
+    pub segment_bytes: u64,
+    pub max_segments: usize,
+}
+
+struct Segment {
+    first_sequence: u64,
+    path: PathBuf,
+    size: u64,
+}
+
+/// Where the chain continues; records are numbered and linked under this lock
+struct ChainState {
+    next_sequence: u64,
+    last_hash: String,
+    /// Queue to the writer thread, taken on drop
+    writer: Option<mpsc::Sender<WriterCommand>>,
+}
+
+enum WriterCommand {
+    Append(AuditRecord),
+    /// Answered once everything queued before it is on disk
+    Flush(mpsc::SyncSender<()>),
+}
+
+/// A chain position signed with the audit key: the newest record for the
+/// head, the oldest kept record's sequence and `prev_hash` for the checkpoint
+#[derive(Serialize, Deserialize)]
+struct Anchor {
+    sequence: u64,
+    hash: String,
+    mac: String,
+}
+
+impl Anchor {
+    fn new(key: &[u8], kind: &str, sequence: u64, hash: &str) -> Self {
+        Anchor {
+            sequence,
+            hash: hash.to_string(),
+            mac: anchor_mac(key, kind, sequence, hash),
+        }
+    }
+}
+
+/// Tamper-evident audit log.
+///
+/// Records are hash-chained and written as JSON lines to segment files named
+/// after the sequence number of their first record. Writing happens on a
+/// dedicated thread so auditing never blocks on disk; `query` and
+/// `verify_chain` wait for queued records first. The signed head and
+/// checkpoint files let `verify_chain` notice records cut from either end.
 pub struct SecurityAudit {
-    logs: Arc<Mutex<Vec<String>>>,
+    dir: PathBuf,
+    key: Vec<u8>,
+    chain: Mutex<ChainState>,
+    /// Shared with the writer thread, which adds and retires segments
+    segments: Arc<Mutex<Vec<Segment>>>,
+    /// Held for writing while retention deletes segments, so a verification
+    /// in progress sees the segments and checkpoint of one moment
+    retention_lock: Arc<RwLock<()>>,
+    writer_thread: Mutex<Option<JoinHandle<()>>>,
+    /// Every record as it is written, in sequence order
+    live: broadcast::Sender<AuditRecord>,
 }
 
 impl SecurityAudit {
-    pub fn new() -> Self {
-        SecurityAudit {
-            logs: Arc::new(Mutex::new(Vec::new())),
+    pub fn open(dir: &Path, retention: AuditRetention, key: &[u8]) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let first_sequence = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".jsonl"))
+                .and_then(|n| n.parse::<u64>().ok());
+            if let Some(first_sequence) = first_sequence {
+                paths.push((first_sequence, path));
+            }
+        }
+        paths.sort();
+
+        // Continue the chain from the last record that made it to disk; the
+        // newest segment may still be empty
+        let mut tail = None;
+        for (_, path) in paths.iter().rev() {
+            tail = last_record(path)?;
+            if tail.is_some() {
+                break;
+            }
+        }
+        let (mut next_sequence, mut last_hash) = match tail {
+            Some(record) => (record.sequence + 1, record.hash),
+            None => (paths.last().map_or(0, |(first, _)| *first), GENESIS_HASH.to_string()),
+        };
+
+        // Records the head vouches for but the segments lack were cut off;
+        // keep numbering after them so the gap stays visible to `verify_chain`
+        match read_anchor(&dir.join(HEAD_FILE), key, "head") {
+            Ok(Some(head)) if head.sequence >= next_sequence => {
+                warn!(
+                    "Audit log ends before record {} its head vouches for; records are missing",
+                    head.sequence
+                );
+                next_sequence = head.sequence + 1;
+                last_hash = head.hash;
+            }
+            Ok(_) => {}
+            Err(e) => warn!("{}", e),
+        }
+
+        let mut segments = Vec::new();
+        for (first_sequence, path) in paths {
+            let size = fs::metadata(&path)?.len();
+            segments.push(Segment {
+                first_sequence,
+                path,
+                size,
+            });
+        }
+
+        if segments.last().map_or(true, |s| s.size >= retention.segment_bytes) {
+            segments.push(Segment {
+                first_sequence: next_sequence,
+                path: segment_path(dir, next_sequence),
+                size: 0,
+            });
+        }
+        let active = open_append(&segments.last().expect("active segment").path)?;
+
+        let segments = Arc::new(Mutex::new(segments));
+        let retention_lock = Arc::new(RwLock::new(()));
+        let mut writer = AuditWriter {
+            dir: dir.to_path_buf(),
+            key: key.to_vec(),
+            retention,
+            active,
+            segments: segments.clone(),
+            retention_lock: retention_lock.clone(),
+        };
+        writer.enforce_retention()?;
+        let (sender, commands) = mpsc::channel();
+        let writer_thread = std::thread::Builder::new()
+            .name("kiacha-audit".to_string())
+            .spawn(move || writer.run(commands))?;
+
+        Ok(SecurityAudit {
+            dir: dir.to_path_buf(),
+            key: key.to_vec(),
+            chain: Mutex::new(ChainState {
+                next_sequence,
+                last_hash,
+                writer: Some(sender),
+            }),
+            segments,
+            retention_lock,
+            writer_thread: Mutex::new(Some(writer_thread)),
+            live: broadcast::channel(LIVE_BUFFER).0,
+        })
+    }
+
+    /// Append a record to the chain
+    ///
+    /// Auditing never fails the audited operation. The record is written in
+    /// the background; one that cannot be written is reported in the kernel
+    /// log and shows up as a gap in `verify_chain`.
+    pub fn record(&self, actor: &str, action: &str, target: &str, success: bool, details: &str) {
+        let mut chain = self.chain.lock();
+        let mut record = AuditRecord {
+            sequence: chain.next_sequence,
+            timestamp: chrono::Local::now().timestamp_millis(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            success,
+            details: details.to_string(),
+            prev_hash: chain.last_hash.clone(),
+            hash: String::new(),
+        };
+        record.hash = record.compute_hash(&self.key);
+        chain.next_sequence += 1;
+        chain.last_hash = record.hash.clone();
+
+        // Queued and sent under the lock so both see records in sequence order
+        let queued = chain
+            .writer
+            .as_ref()
+            .map_or(false, |writer| writer.send(WriterCommand::Append(record.clone())).is_ok());
+        if !queued {
+            warn!("Audit writer is gone; record {} ({}) was not written", record.sequence, action);
+        }
+        let _ = self.live.send(record);
+    }
+
+    /// Wait until every record queued so far is on disk
+    pub fn flush(&self) {
+        let (reply, done) = mpsc::sync_channel(1);
+        let queued = self
+            .chain
+            .lock()
+            .writer
+            .as_ref()
+            .map_or(false, |writer| writer.send(WriterCommand::Flush(reply)).is_ok());
+        if queued {
+            let _ = done.recv();
         }
     }
 
-    pub fn log(&self, event: &str, details: &str) {
-        let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S");
-        let log_entry = format!("[{}] {}: {}", timestamp, event, details);
-        self.logs.lock().push(log_entry);
+    /// Sequence number the next record will get
+    pub fn next_sequence(&self) -> u64 {
+        self.chain.lock().next_sequence
+    }
+
+    /// Receive records as they are written, together with the sequence number
+    /// of the first one the receiver will get. Subscribe before reading
+    /// history with `query` so nothing falls between the two.
+    pub fn subscribe(&self) -> (broadcast::Receiver<AuditRecord>, u64) {
+        // Records are numbered and broadcast under the chain lock
+        let chain = self.chain.lock();
+        (self.live.subscribe(), chain.next_sequence)
+    }
+
+    /// Stored records matching `filter`, oldest first
+    ///
+    /// Segments are read a line at a time; only matches are kept, and with a
+    /// `limit` only the most recent that many.
+    pub fn query(&self, filter: &AuditFilter) -> anyhow::Result<Vec<AuditRecord>> {
+        self.flush();
+        let mut records = VecDeque::new();
+        let mut lines = self.lines(filter.from_sequence.unwrap_or(0));
+        while let Some(line) = lines.next() {
+            match serde_json::from_str::<AuditRecord>(&line?) {
+                Ok(record) if filter.matches(&record) => {
+                    if filter.limit.map_or(false, |limit| records.len() >= limit) {
+                        records.pop_front();
+                    }
+                    if filter.limit != Some(0) {
+                        records.push_back(record);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Skipping unreadable audit record in {:?}: {}", lines.path(), e),
+            }
+        }
+        Ok(records.into())
+    }
+
+    /// Re-read the stored records and check that each one's hash is intact and
+    /// links to the one before it, with no sequence numbers missing between the
+    /// retention checkpoint and the head
+    pub fn verify_chain(&self) -> anyhow::Result<ChainReport> {
+        let expected_last = self.next_sequence().checked_sub(1);
+        self.flush();
+        let _retention = self.retention_lock.read();
+
+        let mut report = ChainReport::default();
+        let (checkpoint, head) = match (
+            read_anchor(&self.dir.join(CHECKPOINT_FILE), &self.key, "checkpoint"),
+            read_anchor(&self.dir.join(HEAD_FILE), &self.key, "head"),
+        ) {
+            (Ok(checkpoint), Ok(head)) => (checkpoint, head),
+            (Err(e), _) | (_, Err(e)) => return Ok(report.broken(0, e.to_string())),
+        };
+        let (start, start_hash) = checkpoint.map_or((0, GENESIS_HASH.to_string()), |c| (c.sequence, c.hash));
+        // Records written since the flush may be on disk as well
+        let last = expected_last.max(head.as_ref().map(|h| h.sequence));
+
+        let mut previous: Option<AuditRecord> = None;
+        let mut lines = self.lines(start);
+        while let Some(line) = lines.next() {
+            let broken_at = previous.as_ref().map_or(start, |p| p.sequence + 1);
+            let record = match serde_json::from_str::<AuditRecord>(&line?) {
+                Ok(record) => record,
+                Err(e) => {
+                    let error = format!("Unreadable record in {:?}: {}", lines.path(), e);
+                    return Ok(report.broken(broken_at, error));
+                }
+            };
+            // Retired by retention but not yet deleted, or written after `last`
+            if record.sequence < start && previous.is_none() {
+                continue;
+            }
+            if last.map_or(true, |last| record.sequence > last) {
+                break;
+            }
+
+            if let Some(previous) = &previous {
+                if record.sequence != previous.sequence + 1 {
+                    return Ok(report.broken(
+                        broken_at,
+                        format!("Record {} follows {}", record.sequence, previous.sequence),
+                    ));
+                }
+                if record.prev_hash != previous.hash {
+                    return Ok(report.broken(
+                        record.sequence,
+                        format!("Record {} does not link to its predecessor", record.sequence),
+                    ));
+                }
+            } else if record.sequence != start {
+                return Ok(report.broken(start, format!("Records from {} to {} are missing", start, record.sequence)));
+            } else if record.prev_hash != start_hash {
+                return Ok(report.broken(start, format!("Record {} does not start the chain", start)));
+            }
+            if record.compute_hash(&self.key) != record.hash {
+                let error = format!("Record {} was modified", record.sequence);
+                return Ok(report.broken(record.sequence, error));
+            }
+            if let Some(head) = head.as_ref().filter(|h| h.sequence == record.sequence) {
+                if head.hash != record.hash {
+                    let error = format!("Record {} is not the one the head vouches for", record.sequence);
+                    return Ok(report.broken(record.sequence, error));
+                }
+            }
+
+            report.records += 1;
+            report.first_sequence.get_or_insert(record.sequence);
+            report.last_sequence = Some(record.sequence);
+            previous = Some(record);
+        }
+
+        if report.last_sequence != last {
+            let from = report.last_sequence.map_or(start, |s| s + 1);
+            return Ok(report.broken(from, format!("Records from {} onwards are missing", from)));
+        }
+        Ok(report)
+    }
+
+    /// Lines of the stored segments, skipping those that end before `from_sequence`
+    fn lines(&self, from_sequence: u64) -> SegmentLines {
+        let segments = self.segments.lock();
+        let paths = segments
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| segments.get(i + 1).map_or(true, |next| next.first_sequence > from_sequence))
+            .map(|(_, s)| s.path.clone())
+            .collect();
+        SegmentLines { paths, current: None }
+    }
+
+    #[cfg(test)]
+    fn segment_paths(&self) -> Vec<PathBuf> {
+        self.segments.lock().iter().map(|s| s.path.clone()).collect()
+    }
+}
+
+impl Drop for SecurityAudit {
+    /// Let the writer drain its queue before the log is closed
+    fn drop(&mut self) {
+        self.chain.lock().writer.take();
+        if let Some(thread) = self.writer_thread.lock().take() {
+            let _ = thread.join();
+        }
     }
+}
+
+/// Owns the files of the log on the writer thread
+struct AuditWriter {
+    dir: PathBuf,
+    key: Vec<u8>,
+    retention: AuditRetention,
+    active: File,
+    segments: Arc<Mutex<Vec<Segment>>>,
+    retention_lock: Arc<RwLock<()>>,
+}
+
+impl AuditWriter {
+    /// Write queued records in batches, syncing and moving the head once per
+    /// batch, until the log is dropped
+    fn run(mut self, commands: mpsc::Receiver<WriterCommand>) {
+        while let Ok(command) = commands.recv() {
+            let mut flushes = Vec::new();
+            let mut newest = None;
+            for command in std::iter::once(command).chain(commands.try_iter()) {
+                match command {
+                    WriterCommand::Append(record) => match self.write(&record) {
+                        Ok(()) => newest = Some(record),
+                        Err(e) => warn!("Failed to write audit record {} ({}): {}", record.sequence, record.action, e),
+                    },
+                    WriterCommand::Flush(reply) => flushes.push(reply),
+                }
+            }
+            if let Some(record) = newest {
+                if let Err(e) = self.sync(&record) {
+                    warn!("Failed to sync audit log at record {}: {}", record.sequence, e);
+                }
+            }
+            for reply in flushes {
+                let _ = reply.send(());
+            }
+        }
+    }
+
+    fn write(&mut self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.active.write_all(&line)?;
+
+        let full = {
+            let mut segments = self.segments.lock();
+            let segment = segments.last_mut().expect("active segment");
+            segment.size += line.len() as u64;
+            segment.size >= self.retention.segment_bytes
+        };
+        if full {
+            self.active.sync_data()?;
+            let first_sequence = record.sequence + 1;
+            let path = segment_path(&self.dir, first_sequence);
+            self.active = open_append(&path)?;
+            self.segments.lock().push(Segment {
+                first_sequence,
+                path,
+                size: 0,
+            });
+            self.enforce_retention()?;
+        }
+        Ok(())
+    }
+
+    /// Make written records durable, then sign the newest as the head
+    fn sync(&mut self, newest: &AuditRecord) -> anyhow::Result<()> {
+        self.active.sync_data()?;
+        let head = Anchor::new(&self.key, "head", newest.sequence, &newest.hash);
+        write_anchor(&self.dir.join(HEAD_FILE), &head)
+    }
+
+    /// Delete the oldest segments beyond the retention limit, checkpointing
+    /// where the chain then starts first. The active segment is never removed.
+    fn enforce_retention(&mut self) -> anyhow::Result<()> {
+        let _retention = self.retention_lock.write();
+        loop {
+            let (oldest, next_first) = {
+                let segments = self.segments.lock();
+                if segments.len() <= self.retention.max_segments.max(1) {
+                    return Ok(());
+                }
+                (segments[0].path.clone(), segments[1].first_sequence)
+            };
+
+            // An empty segment takes no records with it
+            if let Some(record) = last_record(&oldest)? {
+                let checkpoint = Anchor::new(&self.key, "checkpoint", next_first, &record.hash);
+                write_anchor(&self.dir.join(CHECKPOINT_FILE), &checkpoint)?;
+            }
+
+            self.segments.lock().remove(0);
+            if let Err(e) = fs::remove_file(&oldest) {
+                warn!("Failed to remove audit segment {:?}: {}", oldest, e);
+            }
+        }
+    }
+}
+
+/// Lines of a run of segments, opened one at a time. Segments deleted by
+/// retention before the reader reaches them are skipped.
+struct SegmentLines {
+    paths: VecDeque<PathBuf>,
+    current: Option<(PathBuf, Lines<BufReader<File>>)>,
+}
+
+impl SegmentLines {
+    /// Segment the last line came from
+    fn path(&self) -> Option<&Path> {
+        self.current.as_ref().map(|(path, _)| path.as_path())
+    }
+}
+
+impl Iterator for SegmentLines {
+    type Item = anyhow::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.current.is_none() {
+                let path = self.paths.pop_front()?;
+                match File::open(&path) {
+                    Ok(file) => self.current = Some((path, BufReader::new(file).lines())),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Some(Err(e.into())),
+                }
+            }
+            match self.current.as_mut().expect("open segment").1.next() {
+                Some(Ok(line)) if line.is_empty() => {}
+                Some(Ok(line)) => return Some(Ok(line)),
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+fn audit_mac(key: &[u8], bytes: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(bytes);
+    mac
+}
+
+fn anchor_mac(key: &[u8], kind: &str, sequence: u64, hash: &str) -> String {
+    let bytes = format!("{}:{}:{}", kind, sequence, hash);
+    hex::encode(audit_mac(key, bytes.as_bytes()).finalize().into_bytes())
+}
+
+/// Read a signed anchor; a missing file is `None`, a forged one an error
+fn read_anchor(path: &Path, key: &[u8], kind: &str) -> anyhow::Result<Option<Anchor>> {
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let anchor: Anchor = serde_json::from_slice(&raw)
+        .map_err(|e| anyhow::anyhow!("Unreadable audit {} {:?}: {}", kind, path, e))?;
+    if anchor_mac(key, kind, anchor.sequence, &anchor.hash) != anchor.mac {
+        return Err(anyhow::anyhow!("Audit {} {:?} failed its signature check", kind, path));
+    }
+    Ok(Some(anchor))
+}
+
+/// Replace an anchor file atomically
+fn write_anchor(path: &Path, anchor: &Anchor) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(anchor)?)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Last record of a segment. A torn final line left by a crash is cut off.
+fn last_record(path: &Path) -> anyhow::Result<Option<AuditRecord>> {
+    let content = fs::read(path)?;
+    let (body, torn) = match content.strip_suffix(b"\n") {
+        Some(body) => (body, false),
+        None => (content.as_slice(), !content.is_empty()),
+    };
+    let start = body.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    if !torn {
+        if body.is_empty() {
+            return Ok(None);
+        }
+        // A whole but unreadable line is left for `verify_chain` to report
+        return Ok(serde_json::from_slice(&body[start..])
+            .map_err(|e| warn!("Unreadable last record in audit segment {:?}: {}", path, e))
+            .ok());
+    }
+
+    warn!("Dropping torn audit record at the end of {:?}", path);
+    OpenOptions::new().write(true).open(path)?.set_len(start as u64)?;
+    let before = &body[..start.saturating_sub(1)];
+    let prev_start = before.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    Ok(serde_json::from_slice(&before[prev_start..]).ok())
+}
+
+fn segment_path(dir: &Path, first_sequence: u64) -> PathBuf {
+    dir.join(format!("{:020}.jsonl", first_sequence))
+}
+
+fn open_append(path: &Path) -> anyhow::Result<File> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+
+    const KEY: &[u8] = b"audit test key";
+
+    fn open(dir: &Path, segment_bytes: u64) -> SecurityAudit {
+        open_with(dir, segment_bytes, 100, KEY)
+    }
+
+    fn open_with(dir: &Path, segment_bytes: u64, max_segments: usize, key: &[u8]) -> SecurityAudit {
+        SecurityAudit::open(
+            dir,
+            AuditRetention {
+                segment_bytes,
+                max_segments,
+            },
+            key,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn chain_continues_across_segments_and_restarts() {
+        let dir = TempDir::new("audit");
+        {
+            let audit = open(&dir, 512);
+            for i in 0..10 {
+                audit.record("user:alice", "module_spawn", &format!("m{}", i), true, "brain");
+            }
+        }
+
+        let audit = open(&dir, 512);
+        audit.record(KERNEL_ACTOR, "kernel_started", "", true, "");
+        audit.flush();
+        assert!(audit.segments.lock().len() > 2);
+
+        let report = audit.verify_chain().unwrap();
+        assert!(report.valid(), "{:?}", report.error);
+        assert_eq!((report.records, report.last_sequence), (11, Some(10)));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let dir = TempDir::new("audit");
+        let audit = open(&dir, 1 << 20);
+        audit.record("user:alice", "login", "alice", false, "bad password");
+        audit.record("user:alice", "login", "alice", true, "laptop");
+        audit.record("user:alice", "user_delete", "bob", true, "");
+        audit.flush();
+
+        let path = audit.segment_paths()[0].clone();
+        let original = fs::read_to_string(&path).unwrap();
+
+        fs::write(&path, original.replace("\"success\":false", "\"success\":true")).unwrap();
+        assert_eq!(audit.verify_chain().unwrap().broken_at, Some(0));
+
+        let without_second: Vec<&str> = original
+            .lines()
+            .enumerate()
+            .filter(|(i, _)| *i != 1)
+            .map(|(_, l)| l)
+            .collect();
+        fs::write(&path, without_second.join("\n") + "\n").unwrap();
+        assert_eq!(audit.verify_chain().unwrap().broken_at, Some(1));
+
+        let without_last: Vec<&str> = original.lines().take(2).collect();
+        fs::write(&path, without_last.join("\n") + "\n").unwrap();
+        assert_eq!(audit.verify_chain().unwrap().broken_at, Some(2));
+    }
+
+    #[test]
+    fn truncation_is_detected() {
+        let dir = TempDir::new("audit");
+        {
+            let audit = open(&dir, 512);
+            for i in 0..10 {
+                audit.record("user:alice", "module_spawn", &format!("m{}", i), true, "");
+            }
+            audit.flush();
+
+            // Oldest segment gone without a checkpoint to account for it
+            let oldest = audit.segment_paths()[0].clone();
+            let original = fs::read(&oldest).unwrap();
+            fs::remove_file(&oldest).unwrap();
+            assert_eq!(audit.verify_chain().unwrap().broken_at, Some(0));
+            fs::write(&oldest, original).unwrap();
+            assert!(audit.verify_chain().unwrap().valid());
+        }
+
+        // Newest segment gone across a restart; the head still knows it existed
+        let newest = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().map_or(false, |e| e == "jsonl"))
+            .filter(|p| fs::metadata(p).unwrap().len() > 0)
+            .max()
+            .unwrap();
+        fs::remove_file(&newest).unwrap();
+        let audit = open(&dir, 512);
+        assert!(!audit.verify_chain().unwrap().valid());
+    }
+
+    #[test]
+    fn retention_checkpoints_where_the_chain_starts() {
+        let dir = TempDir::new("audit");
+        let audit = open_with(&dir, 512, 2, KEY);
+        for i in 0..20 {
+            audit.record("user:alice", "module_spawn", &format!("m{}", i), true, "");
+        }
+
+        let report = audit.verify_chain().unwrap();
+        assert!(report.valid(), "{:?}", report.error);
+        assert!(report.first_sequence > Some(0));
+        assert_eq!(report.last_sequence, Some(19));
+        assert_eq!(audit.segment_paths().len(), 2);
+    }
+
+    #[test]
+    fn chain_does_not_verify_under_another_key() {
+        let dir = TempDir::new("audit");
+        {
+            let audit = open(&dir, 1 << 20);
+            audit.record("user:alice", "login", "alice", true, "");
+        }
+        let audit = open_with(&dir, 1 << 20, 100, b"another key");
+        assert!(!audit.verify_chain().unwrap().valid());
+    }
+
+    #[test]
+    fn torn_tail_is_dropped_on_open() {
+        let dir = TempDir::new("audit");
+        {
+            let audit = open(&dir, 1 << 20);
+            audit.record("user:alice", "login", "alice", true, "");
+        }
+        let path = segment_path(&dir, 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"sequence\":1,\"times").unwrap();
+
+        let audit = open(&dir, 1 << 20);
+        audit.record("user:alice", "logout", "alice", true, "");
+        assert!(audit.verify_chain().unwrap().valid());
+    }
+
+    #[test]
+    fn subscribers_see_records_as_written() {
+        let dir = TempDir::new("audit");
+        let audit = open(&dir, 1 << 20);
+        audit.record(KERNEL_ACTOR, "kernel_started", "", true, "");
+        let (mut live, from) = audit.subscribe();
+        audit.record("module:m1", "ipc_send", "m2", true, "");
+        audit.record(KERNEL_ACTOR, "module_restart_give_up", "m1", false, "5 restarts");
+
+        let first = live.try_recv().unwrap();
+        let second = live.try_recv().unwrap();
+        assert_eq!((from, first.sequence, second.sequence), (1, 1, 2));
+
+        let critical_m1 = AuditFilter {
+            module_id: Some("m1".to_string()),
+            min_severity: Some(Severity::Critical),
+            ..Default::default()
+        };
+        assert!(first.concerns_module("m1") && !critical_m1.matches(&first));
+        assert!(critical_m1.matches(&second));
+    }
+
+    #[test]
+    fn filter_by_actor_action_and_time() {
+        let dir = TempDir::new("audit");
+        let audit = open(&dir, 1 << 20);
+        audit.record("user:alice", "login", "alice", true, "");
+        audit.record("user:bob", "login", "bob", true, "");
+        audit.record("user:alice", "ipc_send", "a -> b", true, "");
+
+        let alice = AuditFilter {
+            actor: Some("user:alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(audit.query(&alice).unwrap().len(), 2);
+
+        let ipc = AuditFilter {
+            action: Some("ipc*".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(audit.query(&ipc).unwrap()[0].target, "a -> b");
+
+        let future = AuditFilter {
+            since: Some(chrono::Local::now().timestamp_millis() + 60_000),
+            ..Default::default()
+        };
+        assert!(audit.query(&future).unwrap().is_empty());
 
-    pub fn get_logs(&self) -> Vec<String> {
-        self.logs.lock().clone()
+        let last = AuditFilter {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(audit.query(&last).unwrap()[0].action, "ipc_send");
     }
 }
@@ -0,0 +1,368 @@
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use crate::event_bus::{Event, EventBus};
+use crate::ipc::{DeadLetterStore, IpcChannel};
+use crate::kernel::{ModuleInfo, ModuleStatus};
+use crate::module_host::{self, ModuleExit, ModuleSpec, RunningModule};
+use crate::permissions::PermissionManager;
+use crate::security::{SecurityAudit, KERNEL_ACTOR};
+use crate::wasm_runtime::{WasmControl, WasmRuntime};
+
+/// When a module is restarted after it exits, from `ModuleRequest.config["restart"]`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+/// Per-module restart settings.
+///
+/// Recognised config keys: `restart` (`never`, `on-failure`, `always`),
+/// `restart_backoff_ms`, `restart_max_backoff_ms`, `max_restarts` and
+/// `restart_window_secs`. A module that exceeds `max_restarts` within the
+/// window is given up on and left `Failed`.
+#[derive(Clone, Debug)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_restarts: usize,
+    pub window: Duration,
+}
+
+impl RestartConfig {
+    pub fn from_config(config: &HashMap<String, String>) -> anyhow::Result<Self> {
+        let policy = match config.get("restart").map(|s| s.as_str()) {
+            None | Some("never") => RestartPolicy::Never,
+            Some("on-failure") => RestartPolicy::OnFailure,
+            Some("always") => RestartPolicy::Always,
+            Some(other) => return Err(anyhow::anyhow!("Unknown restart policy: {}", other)),
+        };
+
+        let number = |key: &str, default: u64| -> anyhow::Result<u64> {
+            match config.get(key) {
+                Some(raw) => raw
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid value for {}: {}", key, raw)),
+                None => Ok(default),
+            }
+        };
+
+        Ok(RestartConfig {
+            policy,
+            initial_backoff: Duration::from_millis(number("restart_backoff_ms", 500)?),
+            max_backoff: Duration::from_millis(number("restart_max_backoff_ms", 30_000)?),
+            max_restarts: number("max_restarts", 5)? as usize,
+            window: Duration::from_secs(number("restart_window_secs", 60)?),
+        })
+    }
+
+    fn should_restart(&self, exit: &ModuleExit) -> bool {
+        match self.policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !exit.is_success(),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// Restart history of one module, used for backoff and the max-restarts window.
+struct RestartHistory {
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartHistory {
+    fn new() -> Self {
+        RestartHistory {
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Delay before the next restart, or `None` once the window budget is spent
+    fn next_delay(&mut self, config: &RestartConfig) -> Option<Duration> {
+        let now = Instant::now();
+        while let Some(first) = self.restarts.front() {
+            if now.duration_since(*first) > config.window {
+                self.restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.restarts.len() >= config.max_restarts {
+            return None;
+        }
+
+        let exponent = self.restarts.len().min(16) as u32;
+        let delay = config
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(config.max_backoff);
+        self.restarts.push_back(now);
+        Some(delay)
+    }
+}
+
+/// Watches spawned modules and restarts them according to their `RestartConfig`.
+pub struct ModuleSupervisor {
+    modules: Arc<DashMap<String, ModuleInfo>>,
+    ipc_channels: Arc<DashMap<String, Arc<IpcChannel>>>,
+    dead_letters: Arc<DeadLetterStore>,
+    /// Credential token -> module id, shared with the kernel
+    module_credentials: Arc<DashMap<String, String>>,
+    permissions: Arc<PermissionManager>,
+    wasm_controls: Arc<DashMap<String, Arc<WasmControl>>>,
+    wasm_runtime: Arc<WasmRuntime>,
+    security_audit: Arc<SecurityAudit>,
+    event_bus: Arc<EventBus>,
+}
+
+impl ModuleSupervisor {
+    pub fn new(
+        modules: Arc<DashMap<String, ModuleInfo>>,
+        ipc_channels: Arc<DashMap<String, Arc<IpcChannel>>>,
+        dead_letters: Arc<DeadLetterStore>,
+        module_credentials: Arc<DashMap<String, String>>,
+        permissions: Arc<PermissionManager>,
+        wasm_controls: Arc<DashMap<String, Arc<WasmControl>>>,
+        wasm_runtime: Arc<WasmRuntime>,
+        security_audit: Arc<SecurityAudit>,
+        event_bus: Arc<EventBus>,
+    ) -> Self {
+        ModuleSupervisor {
+            modules,
+            ipc_channels,
+            dead_letters,
+            module_credentials,
+            permissions,
+            wasm_controls,
+            wasm_runtime,
+            security_audit,
+            event_bus,
+        }
+    }
+
+    /// Register the handles of a freshly launched module
+    pub fn attach(&self, module_id: &str, running: &RunningModule) {
+        if let Some(control) = running.wasm_control() {
+            self.wasm_controls.insert(module_id.to_string(), control);
+        }
+        if let Some(mut module) = self.modules.get_mut(module_id) {
+            module.status = ModuleStatus::Running;
+            module.pid = running.pid();
+        }
+    }
+
+    /// Watch a launched module until it exits for good
+    pub fn supervise(
+        self: &Arc<Self>,
+        module_id: String,
+        spec: ModuleSpec,
+        restart: RestartConfig,
+        running: RunningModule,
+    ) {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let mut history = RestartHistory::new();
+            let mut running = Some(running);
+
+            loop {
+                let exit = match running.take() {
+                    Some(module) => module.wait().await,
+                    None => ModuleExit::Error("module failed to start".to_string()),
+                };
+                supervisor.record_exit(&module_id, &exit).await;
+
+                if !supervisor.modules.contains_key(&module_id) || !restart.should_restart(&exit) {
+                    supervisor.retire(&module_id);
+                    break;
+                }
+
+                let delay = match history.next_delay(&restart) {
+                    Some(delay) => delay,
+                    None => {
+                        supervisor.give_up(&module_id, &exit, &restart).await;
+                        supervisor.retire(&module_id);
+                        break;
+                    }
+                };
+
+                let attempt = history.restarts.len();
+                supervisor.security_audit.record(
+                    KERNEL_ACTOR,
+                    "module_restart",
+                    &module_id,
+                    true,
+                    &format!("attempt {} in {:?} after: {}", attempt, delay, exit.describe()),
+                );
+                supervisor
+                    .emit("module.restarting", serde_json::json!({
+                        "module_id": &module_id,
+                        "attempt": attempt,
+                        "delay_ms": delay.as_millis() as u64,
+                        "reason": exit.describe(),
+                    }))
+                    .await;
+
+                tokio::time::sleep(delay).await;
+
+                match module_host::launch(&module_id, &spec, supervisor.wasm_runtime.clone()).await {
+                    Ok(module) => {
+                        supervisor.attach(&module_id, &module);
+                        if let Some(mut info) = supervisor.modules.get_mut(&module_id) {
+                            info.restarts += 1;
+                        }
+                        info!("↻ Restarted module {} (attempt {})", module_id, attempt);
+                        running = Some(module);
+                    }
+                    Err(e) => {
+                        warn!("Failed to restart module {}: {}", module_id, e);
+                        supervisor.security_audit.record(
+                            KERNEL_ACTOR,
+                            "module_restart",
+                            &module_id,
+                            false,
+                            &e.to_string(),
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    async fn record_exit(&self, module_id: &str, exit: &ModuleExit) {
+        let description = exit.describe();
+        // Messages held back while paused wait in the mailbox for the next run,
+        // or become dead letters in `retire` if there is none
+        if let Some(channel) = self.ipc_channels.get(module_id) {
+            channel.set_paused(false);
+        }
+        self.wasm_controls.remove(module_id);
+
+        if let Some(mut module) = self.modules.get_mut(module_id) {
+            module.status = if exit.is_success() {
+                ModuleStatus::Idle
+            } else {
+                ModuleStatus::Failed
+            };
+            module.pid = None;
+            module.last_exit = Some(description.clone());
+        }
+
+        self.security_audit
+            .record(KERNEL_ACTOR, "module_exit", module_id, exit.is_success(), &description);
+        self.emit("module.exited", serde_json::json!({
+            "module_id": module_id,
+            "success": exit.is_success(),
+            "reason": &description,
+        }))
+        .await;
+
+        info!("Module {} {}", module_id, description);
+    }
+
+    /// Clean up after a module that will not run again
+    fn retire(&self, module_id: &str) {
+        // Restarts reuse the credential, so it stays valid until now
+        self.module_credentials.retain(|_, id| id != module_id);
+        self.permissions.clear_module(module_id);
+        // Later messages for the module become dead letters when sent
+        if let Some((_, channel)) = self.ipc_channels.remove(module_id) {
+            for message in channel.drain() {
+                self.dead_letters.push(message, "module exited");
+            }
+        }
+    }
+
+    async fn give_up(&self, module_id: &str, exit: &ModuleExit, restart: &RestartConfig) {
+        if let Some(mut module) = self.modules.get_mut(module_id) {
+            module.status = ModuleStatus::Failed;
+        }
+        let reason = format!(
+            "{} restarts within {:?}, last exit: {}",
+            restart.max_restarts,
+            restart.window,
+            exit.describe()
+        );
+        self.security_audit
+            .record(KERNEL_ACTOR, "module_restart_give_up", module_id, false, &reason);
+        self.emit("module.gave_up", serde_json::json!({
+            "module_id": module_id,
+            "reason": &reason,
+        }))
+        .await;
+        warn!("Giving up on module {}: {}", module_id, reason);
+    }
+
+    async fn emit(&self, event_type: &str, payload: serde_json::Value) {
+        if let Err(e) = self.event_bus.publish(Event::json(event_type, "kernel", payload)).await {
+            warn!("Failed to publish {}: {}", event_type, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn restart_config_reads_policy_and_limits() {
+        let restart = RestartConfig::from_config(&config(&[])).unwrap();
+        assert_eq!(restart.policy, RestartPolicy::Never);
+        assert_eq!(restart.max_restarts, 5);
+
+        let restart = RestartConfig::from_config(&config(&[
+            ("restart", "on-failure"),
+            ("restart_backoff_ms", "100"),
+            ("max_restarts", "2"),
+        ]))
+        .unwrap();
+        assert_eq!(restart.policy, RestartPolicy::OnFailure);
+        assert_eq!(restart.initial_backoff, Duration::from_millis(100));
+        assert!(restart.should_restart(&ModuleExit::Code(1)));
+        assert!(!restart.should_restart(&ModuleExit::Code(0)));
+
+        assert!(RestartConfig::from_config(&config(&[("restart", "sometimes")])).is_err());
+        assert!(RestartConfig::from_config(&config(&[("max_restarts", "many")])).is_err());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_until_the_budget_is_spent() {
+        let restart = RestartConfig {
+            policy: RestartPolicy::Always,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            max_restarts: 4,
+            window: Duration::from_secs(60),
+        };
+        let mut history = RestartHistory::new();
+        let delays: Vec<_> = std::iter::from_fn(|| history.next_delay(&restart)).collect();
+        assert_eq!(delays, [100, 200, 300, 300].map(Duration::from_millis).to_vec());
+    }
+
+    #[test]
+    fn restarts_outside_the_window_are_forgotten() {
+        let restart = RestartConfig {
+            policy: RestartPolicy::Always,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            max_restarts: 1,
+            window: Duration::from_millis(50),
+        };
+        let mut history = RestartHistory::new();
+        assert!(history.next_delay(&restart).is_some());
+        assert!(history.next_delay(&restart).is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(history.next_delay(&restart), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,35 @@
+//! Helpers shared by the unit tests
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A unique path under the system temp dir. The directory is not created; the
+/// code under test does that. Whatever ends up there is removed on drop, so a
+/// failing assertion does not leave it behind.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        TempDir(std::env::temp_dir().join(format!("kiacha-{}-{}", prefix, uuid::Uuid::new_v4())))
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
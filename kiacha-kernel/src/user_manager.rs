@@ -1,6 +1,25 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
 use dashmap::DashMap;
-use std::sync::Arc;
+use parking_lot::Mutex;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::warn;
+use crate::user_store::{Record, UserStore};
 
+/// Shortest password `set_password` accepts
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// How long a password reset code stays valid
+const RESET_CODE_TTL: Duration = Duration::from_secs(15 * 60);
+/// Session activity is written to the store at most this often per session
+const ACTIVITY_PERSIST_INTERVAL: i64 = 60_000;
+
+#[derive(Serialize, Deserialize)]
 pub struct User {
     pub id: String,
     pub username: String,
@@ -10,25 +29,110 @@ pub struct User {
     pub created_at: i64,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Session {
     pub session_id: String,
     pub user_id: String,
     pub started_at: i64,
     pub last_activity: i64,
     pub device: String,
+    /// SHA-256 of the bearer token handed out at login; the token itself is not kept
+    pub token_hash: String,
+}
+
+/// Lockout and session lifetime settings.
+#[derive(Clone, Debug)]
+pub struct AuthPolicy {
+    /// Consecutive failed logins before the account is locked
+    pub max_failures: u32,
+    pub lockout: Duration,
+    /// Sessions with no activity for this long are ended
+    pub session_idle_timeout: Duration,
+}
+
+impl Default for AuthPolicy {
+    fn default() -> Self {
+        AuthPolicy {
+            max_failures: 5,
+            lockout: Duration::from_secs(15 * 60),
+            session_idle_timeout: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    /// Unknown user, wrong password or code, or an inactive account; callers
+    /// are not told which
+    #[error("Invalid username or password")]
+    InvalidCredentials,
+    #[error("Account locked until {0}")]
+    Locked(i64),
 }
 
+/// Password state of one user
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Credential {
+    password_hash: String,
+    failed_attempts: u32,
+    /// Unix millis until which logins are refused
+    locked_until: Option<i64>,
+    /// SHA-256 of an outstanding reset code and when it expires
+    reset: Option<(String, i64)>,
+}
+
+impl Credential {
+    /// State of a user who has never set a password; it matches no password
+    fn empty() -> Self {
+        Credential {
+            password_hash: String::new(),
+            failed_attempts: 0,
+            locked_until: None,
+            reset: None,
+        }
+    }
+}
+
+/// Users, their credentials and sessions.
+///
+/// The maps are an in-memory view of a `UserStore`; every change is written
+/// to the store before it becomes visible. Changes take the store lock first,
+/// which also keeps the username index consistent.
 pub struct UserManager {
     users: DashMap<String, User>,
+    /// Lowercased username -> user id
+    usernames: DashMap<String, String>,
     sessions: DashMap<String, Session>,
+    /// User id -> ids of that user's sessions
+    user_sessions: DashMap<String, HashSet<String>>,
+    /// User id -> password credential
+    credentials: DashMap<String, Credential>,
+    /// Token hash -> session id
+    session_tokens: DashMap<String, String>,
+    /// Session id -> `last_activity` as last written to the store
+    persisted_activity: DashMap<String, i64>,
+    store: Mutex<UserStore>,
+    policy: AuthPolicy,
 }
 
 impl UserManager {
-    pub fn new() -> Self {
-        UserManager {
+    pub fn open(dir: &Path, policy: AuthPolicy) -> anyhow::Result<Self> {
+        let (store, records) = UserStore::open(dir)?;
+        let manager = UserManager {
             users: DashMap::new(),
+            usernames: DashMap::new(),
             sessions: DashMap::new(),
+            user_sessions: DashMap::new(),
+            credentials: DashMap::new(),
+            session_tokens: DashMap::new(),
+            persisted_activity: DashMap::new(),
+            store: Mutex::new(store),
+            policy,
+        };
+        for record in records {
+            manager.apply(record);
         }
+        Ok(manager)
     }
 
     pub fn list_users(&self) -> Vec<User> {
@@ -45,39 +149,490 @@ impl UserManager {
             .collect()
     }
 
-    pub fn create_user(&self, user: User) -> User {
-        self.users.insert(user.id.clone(), user.clone());
-        user
+    pub fn get_user(&self, user_id: &str) -> Option<User> {
+        self.users.get(user_id).map(|u| u.clone())
+    }
+
+    /// Usernames are matched case-insensitively
+    pub fn find_by_username(&self, username: &str) -> Option<User> {
+        let user_id = self.usernames.get(&username_key(username))?.clone();
+        self.get_user(&user_id)
     }
 
+    /// Add a user; usernames must be unique regardless of case
+    pub fn create_user(&self, user: User) -> anyhow::Result<User> {
+        let mut store = self.store.lock();
+        if self.usernames.contains_key(&username_key(&user.username)) {
+            return Err(anyhow::anyhow!("Username {} is already taken", user.username));
+        }
+        if self.users.contains_key(&user.id) {
+            return Err(anyhow::anyhow!("User {} already exists", user.id));
+        }
+        self.commit(&mut store, Record::PutUser { user: user.clone() })?;
+        Ok(user)
+    }
+
+    /// Remove a user together with their credential and sessions
     pub fn delete_user(&self, user_id: &str) -> Result<(), String> {
-        self.users.remove(user_id);
+        let mut store = self.store.lock();
+        if !self.users.contains_key(user_id) {
+            return Err(format!("User {} not found", user_id));
+        }
+        for session in self.list_sessions(user_id) {
+            self.commit(
+                &mut store,
+                Record::DeleteSession {
+                    session_id: session.session_id,
+                },
+            )
+            .map_err(|e| e.to_string())?;
+        }
+        self.commit(
+            &mut store,
+            Record::DeleteUser {
+                user_id: user_id.to_string(),
+            },
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    /// Set a user's password, replacing any previous one
+    pub fn set_password(&self, user_id: &str, password: &str) -> anyhow::Result<()> {
+        if !self.users.contains_key(user_id) {
+            return Err(anyhow::anyhow!("User {} not found", user_id));
+        }
+        validate_password(password)?;
+
+        let credential = Credential {
+            password_hash: hash_password(password)?,
+            failed_attempts: 0,
+            locked_until: None,
+            reset: None,
+        };
+        self.write(Record::PutCredential {
+            user_id: user_id.to_string(),
+            credential,
+        })
+    }
+
+    /// Check a password and open a session, returning it with its bearer token
+    ///
+    /// Failures count towards the lockout; a locked account refuses even the
+    /// right password until the lockout ends.
+    pub fn login(&self, username: &str, password: &str, device: &str) -> anyhow::Result<(Session, String)> {
+        let user = match self.find_by_username(username) {
+            Some(user) if user.active => user,
+            _ => {
+                // Spend the same time as a real check so usernames cannot be probed
+                verify_password(password, dummy_hash());
+                return Err(AuthError::InvalidCredentials.into());
+            }
+        };
+
+        self.check_secret(&user.id, |credential| verify_password(password, &credential.password_hash))?;
+        self.open_session(&user.id, device)
+    }
+
+    /// Open a session for a user whose identity `verify` establishes some other
+    /// way than a password, such as biometrics. Failures count towards the same
+    /// lockout as wrong passwords.
+    pub fn login_verified(
+        &self,
+        username: &str,
+        device: &str,
+        verify: impl FnOnce(&User) -> bool,
+    ) -> anyhow::Result<(Session, String)> {
+        let user = match self.find_by_username(username) {
+            Some(user) if user.active => user,
+            _ => return Err(AuthError::InvalidCredentials.into()),
+        };
+
+        self.check_secret(&user.id, |_| verify(&user))?;
+        self.open_session(&user.id, device)
+    }
+
+    /// Re-check a signed-in user's password before a sensitive change. Failures
+    /// count towards the lockout.
+    pub fn verify_password(&self, user_id: &str, password: &str) -> anyhow::Result<()> {
+        self.check_secret(user_id, |credential| verify_password(password, &credential.password_hash))
+    }
+
+    /// Change a user's own password. Every other session of the user is ended.
+    pub fn change_password(
+        &self,
+        user_id: &str,
+        old_password: &str,
+        new_password: &str,
+        current_session: Option<&str>,
+    ) -> anyhow::Result<()> {
+        validate_password(new_password)?;
+        self.check_secret(user_id, |credential| verify_password(old_password, &credential.password_hash))?;
+        self.set_password(user_id, new_password)?;
+        for session in self.list_sessions(user_id) {
+            if Some(session.session_id.as_str()) != current_session {
+                self.terminate_session(&session.session_id).ok();
+            }
+        }
         Ok(())
     }
 
-    pub fn create_session(&self, session: Session) -> Session {
-        self.sessions.insert(session.session_id.clone(), session.clone());
-        session
+    /// Issue a one-time code with which the user can set a new password.
+    /// Returns the code and when it expires.
+    ///
+    /// This is also how a new user, created without a password, sets their first one.
+    pub fn begin_password_reset(&self, user_id: &str) -> anyhow::Result<(String, i64)> {
+        let mut store = self.store.lock();
+        if !self.users.contains_key(user_id) {
+            return Err(anyhow::anyhow!("User {} not found", user_id));
+        }
+        let mut credential = self
+            .credentials
+            .get(user_id)
+            .map(|c| c.clone())
+            .unwrap_or_else(Credential::empty);
+
+        let code = hex::encode(rand::random::<[u8; 8]>());
+        let expires_at = now_millis() + RESET_CODE_TTL.as_millis() as i64;
+        credential.reset = Some((token_hash(&code), expires_at));
+        self.commit(
+            &mut store,
+            Record::PutCredential {
+                user_id: user_id.to_string(),
+                credential,
+            },
+        )?;
+        Ok((code, expires_at))
     }
 
-    pub fn list_sessions(&self, user_id: &str) -> Vec<Session> {
-        self.sessions
+    /// Set a new password with a code from `begin_password_reset`. Clears any
+    /// lockout and ends all of the user's sessions.
+    ///
+    /// A code is good for a single attempt: a wrong guess invalidates it.
+    pub fn complete_password_reset(&self, username: &str, code: &str, new_password: &str) -> anyhow::Result<()> {
+        validate_password(new_password)?;
+        let user = self
+            .find_by_username(username)
+            .ok_or(AuthError::InvalidCredentials)?;
+
+        let now = now_millis();
+        let code_hash = token_hash(code);
+        let valid = {
+            let mut store = self.store.lock();
+            let mut credential = self
+                .credentials
+                .get(&user.id)
+                .map(|c| c.clone())
+                .ok_or(AuthError::InvalidCredentials)?;
+            let (expected, expires_at) = match credential.reset.take() {
+                Some(reset) => reset,
+                None => return Err(AuthError::InvalidCredentials.into()),
+            };
+            self.commit(
+                &mut store,
+                Record::PutCredential {
+                    user_id: user.id.clone(),
+                    credential,
+                },
+            )?;
+            expected == code_hash && expires_at > now
+        };
+        if !valid {
+            return Err(AuthError::InvalidCredentials.into());
+        }
+
+        self.set_password(&user.id, new_password)?;
+        for session in self.list_sessions(&user.id) {
+            self.terminate_session(&session.session_id).ok();
+        }
+        Ok(())
+    }
+
+    /// Run `verify` against a user's credential, applying the lockout rules
+    ///
+    /// Verification runs without holding any lock since password hashing is slow,
+    /// so the lockout is checked again once it is done: an attempt that was in
+    /// flight when other attempts locked the account is refused even if it matched.
+    fn check_secret(&self, user_id: &str, verify: impl FnOnce(&Credential) -> bool) -> anyhow::Result<()> {
+        if !self.users.contains_key(user_id) {
+            return Err(AuthError::InvalidCredentials.into());
+        }
+        let credential = self
+            .credentials
+            .get(user_id)
+            .map(|c| c.clone())
+            .unwrap_or_else(Credential::empty);
+
+        let now = now_millis();
+        if let Some(until) = credential.locked_until.filter(|until| *until > now) {
+            return Err(AuthError::Locked(until).into());
+        }
+        let verified = verify(&credential);
+
+        let mut store = self.store.lock();
+        if !self.users.contains_key(user_id) {
+            return Err(AuthError::InvalidCredentials.into());
+        }
+        let mut credential = self
+            .credentials
+            .get(user_id)
+            .map(|c| c.clone())
+            .unwrap_or_else(Credential::empty);
+        let before = (credential.failed_attempts, credential.locked_until);
+
+        let now = now_millis();
+        if let Some(until) = credential.locked_until.filter(|until| *until > now) {
+            return Err(AuthError::Locked(until).into());
+        }
+        if credential.locked_until.map_or(false, |until| until <= now) {
+            credential.locked_until = None;
+            credential.failed_attempts = 0;
+        }
+        let result = if verified {
+            credential.failed_attempts = 0;
+            Ok(())
+        } else {
+            credential.failed_attempts += 1;
+            if credential.failed_attempts >= self.policy.max_failures {
+                let until = now + self.policy.lockout.as_millis() as i64;
+                credential.locked_until = Some(until);
+                Err(AuthError::Locked(until))
+            } else {
+                Err(AuthError::InvalidCredentials)
+            }
+        };
+
+        if (credential.failed_attempts, credential.locked_until) != before {
+            self.commit(
+                &mut store,
+                Record::PutCredential {
+                    user_id: user_id.to_string(),
+                    credential,
+                },
+            )?;
+        }
+        Ok(result?)
+    }
+
+    fn open_session(&self, user_id: &str, device: &str) -> anyhow::Result<(Session, String)> {
+        let token = hex::encode(rand::random::<[u8; 32]>());
+        let now = now_millis();
+        let session = self.create_session(Session {
+            session_id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            started_at: now,
+            last_activity: now,
+            device: device.to_string(),
+            token_hash: token_hash(&token),
+        })?;
+        Ok((session, token))
+    }
+
+    pub fn create_session(&self, session: Session) -> anyhow::Result<Session> {
+        self.write(Record::PutSession {
+            session: session.clone(),
+        })?;
+        Ok(session)
+    }
+
+    /// Resolve a bearer token to its session and user, recording the activity.
+    /// Sessions idle for longer than the policy allows are ended instead.
+    pub fn authenticate(&self, token: &str) -> Option<(Session, User)> {
+        let session_id = self.session_tokens.get(&token_hash(token))?.clone();
+        let now = now_millis();
+        let idle_limit = self.policy.session_idle_timeout.as_millis() as i64;
+
+        let session = {
+            let mut session = self.sessions.get_mut(&session_id)?;
+            if now - session.last_activity > idle_limit {
+                drop(session);
+                self.terminate_session(&session_id).ok();
+                return None;
+            }
+            session.last_activity = now;
+            session.clone()
+        };
+
+        let persisted = self.persisted_activity.get(&session_id).map_or(0, |a| *a);
+        if now - persisted > ACTIVITY_PERSIST_INTERVAL {
+            let mut store = self.store.lock();
+            // The session may have been ended meanwhile; do not bring it back
+            if self.sessions.contains_key(&session_id) {
+                if let Err(e) = self.commit(&mut store, Record::PutSession { session: session.clone() }) {
+                    warn!("Failed to persist activity of session {}: {}", session_id, e);
+                }
+            }
+        }
+
+        let user = self.get_user(&session.user_id).filter(|u| u.active)?;
+        Some((session, user))
+    }
+
+    /// End every session that has been idle past the policy limit
+    pub fn expire_idle_sessions(&self) -> Vec<Session> {
+        let cutoff = now_millis() - self.policy.session_idle_timeout.as_millis() as i64;
+        let idle: Vec<Session> = self
+            .sessions
             .iter()
-            .filter(|s| s.user_id == user_id)
-            .map(|r| Session {
-                session_id: r.session_id.clone(),
-                user_id: r.user_id.clone(),
-                started_at: r.started_at,
-                last_activity: r.last_activity,
-                device: r.device.clone(),
+            .filter(|s| s.last_activity < cutoff)
+            .map(|s| s.clone())
+            .collect();
+
+        idle.into_iter()
+            .filter(|session| match self.terminate_session(&session.session_id) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Failed to end idle session {}: {}", session.session_id, e);
+                    false
+                }
             })
             .collect()
     }
 
+    pub fn get_session(&self, session_id: &str) -> Option<Session> {
+        self.sessions.get(session_id).map(|s| s.clone())
+    }
+
+    pub fn list_sessions(&self, user_id: &str) -> Vec<Session> {
+        let session_ids: Vec<String> = match self.user_sessions.get(user_id) {
+            Some(ids) => ids.iter().cloned().collect(),
+            None => return Vec::new(),
+        };
+        session_ids
+            .iter()
+            .filter_map(|id| self.sessions.get(id).map(|s| s.clone()))
+            .collect()
+    }
+
     pub fn terminate_session(&self, session_id: &str) -> Result<(), String> {
-        self.sessions.remove(session_id);
+        let mut store = self.store.lock();
+        if !self.sessions.contains_key(session_id) {
+            return Ok(());
+        }
+        self.commit(
+            &mut store,
+            Record::DeleteSession {
+                session_id: session_id.to_string(),
+            },
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    fn write(&self, record: Record) -> anyhow::Result<()> {
+        let mut store = self.store.lock();
+        self.commit(&mut store, record)
+    }
+
+    /// Persist a change, then apply it. Callers hold the store lock.
+    fn commit(&self, store: &mut UserStore, record: Record) -> anyhow::Result<()> {
+        store.append(&record)?;
+        self.apply(record);
+
+        if store.needs_compaction() {
+            if let Err(e) = store.compact(self.records()) {
+                warn!("Failed to compact user store: {}", e);
+            }
+        }
         Ok(())
     }
+
+    /// Apply a change to the in-memory view
+    fn apply(&self, record: Record) {
+        match record {
+            Record::PutUser { user } => {
+                if let Some(previous) = self.users.get(&user.id) {
+                    self.usernames.remove(&username_key(&previous.username));
+                }
+                self.usernames.insert(username_key(&user.username), user.id.clone());
+                self.users.insert(user.id.clone(), user);
+            }
+            Record::DeleteUser { user_id } => {
+                if let Some((_, user)) = self.users.remove(&user_id) {
+                    self.usernames.remove(&username_key(&user.username));
+                }
+                self.credentials.remove(&user_id);
+            }
+            Record::PutCredential { user_id, credential } => {
+                self.credentials.insert(user_id, credential);
+            }
+            Record::PutSession { session } => {
+                self.session_tokens
+                    .insert(session.token_hash.clone(), session.session_id.clone());
+                self.user_sessions
+                    .entry(session.user_id.clone())
+                    .or_default()
+                    .insert(session.session_id.clone());
+                self.persisted_activity
+                    .insert(session.session_id.clone(), session.last_activity);
+                self.sessions.insert(session.session_id.clone(), session);
+            }
+            Record::DeleteSession { session_id } => {
+                if let Some((_, session)) = self.sessions.remove(&session_id) {
+                    self.session_tokens.remove(&session.token_hash);
+                    let now_empty = match self.user_sessions.get_mut(&session.user_id) {
+                        Some(mut ids) => {
+                            ids.remove(&session_id);
+                            ids.is_empty()
+                        }
+                        None => false,
+                    };
+                    if now_empty {
+                        self.user_sessions.remove(&session.user_id);
+                    }
+                }
+                self.persisted_activity.remove(&session_id);
+            }
+        }
+    }
+
+    /// The whole state as records, for compaction
+    fn records(&self) -> Vec<Record> {
+        let users = self.users.iter().map(|u| Record::PutUser { user: u.clone() });
+        let credentials = self.credentials.iter().map(|c| Record::PutCredential {
+            user_id: c.key().clone(),
+            credential: c.value().clone(),
+        });
+        let sessions = self.sessions.iter().map(|s| Record::PutSession { session: s.clone() });
+        users.chain(credentials).chain(sessions).collect()
+    }
+}
+
+fn username_key(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+fn validate_password(password: &str) -> anyhow::Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(anyhow::anyhow!("Password must be at least {} characters", MIN_PASSWORD_LEN));
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false)
+}
+
+/// Hash checked against when the user does not exist
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash_password("kiacha-dummy-password").unwrap_or_default())
+}
+
+fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn now_millis() -> i64 {
+    chrono::Local::now().timestamp_millis()
 }
 
 impl Clone for User {
@@ -101,6 +656,147 @@ impl Clone for Session {
             started_at: self.started_at,
             last_activity: self.last_activity,
             device: self.device.clone(),
+            token_hash: self.token_hash.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    const PASSWORD: &str = "correct horse";
+
+    fn manager(policy: AuthPolicy) -> (TempDir, UserManager) {
+        let dir = TempDir::new("users-test");
+        let manager = UserManager::open(&dir, policy).unwrap();
+        (dir, manager)
+    }
+
+    fn add_user(manager: &UserManager, username: &str) -> String {
+        let user = manager
+            .create_user(User {
+                id: uuid::Uuid::new_v4().to_string(),
+                username: username.to_string(),
+                display_name: username.to_string(),
+                role: "operator".to_string(),
+                active: true,
+                created_at: now_millis(),
+            })
+            .unwrap();
+        manager.set_password(&user.id, PASSWORD).unwrap();
+        user.id
+    }
+
+    fn is_locked(result: anyhow::Result<(Session, String)>) -> bool {
+        matches!(result.unwrap_err().downcast_ref::<AuthError>(), Some(AuthError::Locked(_)))
+    }
+
+    #[test]
+    fn repeated_failures_lock_the_account_until_the_lockout_ends() {
+        let (_dir, manager) = manager(AuthPolicy {
+            max_failures: 3,
+            // Longer than a password hash takes in a debug build
+            lockout: Duration::from_secs(3),
+            ..AuthPolicy::default()
+        });
+        add_user(&manager, "ada");
+
+        assert!(!is_locked(manager.login("ada", "wrong password", "test")));
+        assert!(!is_locked(manager.login("ada", "wrong password", "test")));
+        assert!(is_locked(manager.login("ada", "wrong password", "test")));
+        assert!(is_locked(manager.login("ada", PASSWORD, "test")));
+
+        std::thread::sleep(Duration::from_millis(3_500));
+        assert!(manager.login("ada", PASSWORD, "test").is_ok());
+        // The failure count starts over after a successful login
+        assert!(!is_locked(manager.login("ada", "wrong password", "test")));
+    }
+
+    #[test]
+    fn attempts_in_flight_when_the_account_locks_are_refused() {
+        let (_dir, manager) = manager(AuthPolicy {
+            max_failures: 2,
+            lockout: Duration::from_secs(60),
+            ..AuthPolicy::default()
+        });
+        let user_id = add_user(&manager, "ada");
+
+        // Other guesses lock the account while this attempt is being verified
+        let result = manager.check_secret(&user_id, |_| {
+            assert!(!is_locked(manager.login("ada", "wrong password", "test")));
+            assert!(is_locked(manager.login("ada", "wrong password", "test")));
+            true
+        });
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<AuthError>(),
+            Some(AuthError::Locked(_))
+        ));
+        assert!(is_locked(manager.login("ada", PASSWORD, "test")));
+    }
+
+    #[test]
+    fn reset_codes_are_single_use() {
+        let (_dir, manager) = manager(AuthPolicy::default());
+        let user_id = add_user(&manager, "ada");
+
+        let (code, _) = manager.begin_password_reset(&user_id).unwrap();
+        assert!(manager.complete_password_reset("ada", "not the code", "new password 1").is_err());
+        // A wrong guess burns the code
+        assert!(manager.complete_password_reset("ada", &code, "new password 1").is_err());
+
+        let (code, _) = manager.begin_password_reset(&user_id).unwrap();
+        let (session, _) = manager.login("ada", PASSWORD, "test").unwrap();
+        manager.complete_password_reset("ada", &code, "new password 2").unwrap();
+        assert!(manager.complete_password_reset("ada", &code, "new password 3").is_err());
+
+        assert!(manager.get_session(&session.session_id).is_none());
+        assert!(manager.login("ada", PASSWORD, "test").is_err());
+        assert!(manager.login("ada", "new password 2", "test").is_ok());
+    }
+
+    #[test]
+    fn expired_reset_codes_are_refused() {
+        let (_dir, manager) = manager(AuthPolicy::default());
+        let user_id = add_user(&manager, "ada");
+
+        let (code, _) = manager.begin_password_reset(&user_id).unwrap();
+        let mut credential = manager.credentials.get(&user_id).unwrap().clone();
+        credential.reset = credential.reset.map(|(hash, _)| (hash, now_millis() - 1));
+        manager
+            .write(Record::PutCredential {
+                user_id: user_id.clone(),
+                credential,
+            })
+            .unwrap();
+
+        assert!(manager.complete_password_reset("ada", &code, "new password 1").is_err());
+        assert!(manager.login("ada", PASSWORD, "test").is_ok());
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let (_dir, manager) = manager(AuthPolicy {
+            session_idle_timeout: Duration::from_millis(200),
+            ..AuthPolicy::default()
+        });
+        add_user(&manager, "ada");
+
+        let (_, active_token) = manager.login("ada", PASSWORD, "test").unwrap();
+        let (idle, idle_token) = manager.login("ada", PASSWORD, "test").unwrap();
+        assert!(manager.authenticate(&idle_token).is_some());
+
+        for _ in 0..3 {
+            std::thread::sleep(Duration::from_millis(100));
+            assert!(manager.authenticate(&active_token).is_some());
         }
+        let expired = manager.expire_idle_sessions();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].session_id, idle.session_id);
+        assert!(manager.authenticate(&idle_token).is_none());
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(manager.authenticate(&active_token).is_none());
     }
 }
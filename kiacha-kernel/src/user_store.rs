@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use crate::user_manager::{Credential, Session, User};
+
+/// Layout version of stored records
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Upgrades for records written by older kernels, applied in order to the raw
+/// JSON of each record: `MIGRATIONS[n]` turns a version `n + 1` record into a
+/// version `n + 2` one.
+const MIGRATIONS: &[fn(&mut serde_json::Value)] = &[];
+
+/// Journal records written after this many appends are folded into the snapshot
+const COMPACT_AFTER: usize = 1000;
+
+/// One change to the user database. Replaying records in order rebuilds it.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record {
+    PutUser { user: User },
+    DeleteUser { user_id: String },
+    PutCredential { user_id: String, credential: Credential },
+    PutSession { session: Session },
+    DeleteSession { session_id: String },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    schema_version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    schema_version: u32,
+    records: Vec<serde_json::Value>,
+}
+
+/// Durable storage behind `UserManager`.
+///
+/// State is a snapshot file plus an append-only journal of `Record`s. Both
+/// carry the schema version they were written with; older records are
+/// migrated on open and the store is rewritten at the current version. They
+/// hold password hashes and session token hashes, so only the kernel's user
+/// may read them.
+pub struct UserStore {
+    dir: PathBuf,
+    journal: File,
+    journal_records: usize,
+}
+
+impl UserStore {
+    /// Open the store, returning the records to replay in order
+    pub fn open(dir: &Path) -> anyhow::Result<(Self, Vec<Record>)> {
+        fs::create_dir_all(dir)?;
+
+        let mut records = Vec::new();
+        let mut outdated = false;
+
+        let snapshot_path = dir.join("snapshot.json");
+        if snapshot_path.exists() {
+            let snapshot: Snapshot = serde_json::from_slice(&fs::read(&snapshot_path)?)?;
+            outdated |= snapshot.schema_version < SCHEMA_VERSION;
+            records.extend(upgrade(snapshot.records, snapshot.schema_version, SCHEMA_VERSION, MIGRATIONS)?);
+        }
+
+        let journal_path = dir.join("journal.jsonl");
+        let mut journal_records = 0;
+        let mut has_header = false;
+        if journal_path.exists() {
+            if let Some((version, raw)) = read_journal(&journal_path)? {
+                has_header = true;
+                outdated |= version < SCHEMA_VERSION;
+                journal_records = raw.len();
+                records.extend(upgrade(raw, version, SCHEMA_VERSION, MIGRATIONS)?);
+            }
+        }
+
+        let journal = open_private(OpenOptions::new().create(true).append(true), &journal_path)?;
+        sync_dir(dir)?;
+        let mut store = UserStore {
+            dir: dir.to_path_buf(),
+            journal,
+            journal_records,
+        };
+        if !has_header {
+            store.write_header()?;
+        }
+        if outdated {
+            store.compact(records.clone())?;
+        }
+        Ok((store, records))
+    }
+
+    pub fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.journal.write_all(&line)?;
+        self.journal.sync_data()?;
+        self.journal_records += 1;
+        Ok(())
+    }
+
+    pub fn needs_compaction(&self) -> bool {
+        self.journal_records >= COMPACT_AFTER
+    }
+
+    /// Replace the snapshot with `records`, the full current state, and start
+    /// an empty journal.
+    ///
+    /// A crash between the two steps is harmless: replaying the old journal
+    /// over the new snapshot gives the same state.
+    pub fn compact(&mut self, records: Vec<Record>) -> anyhow::Result<()> {
+        let snapshot = Snapshot {
+            schema_version: SCHEMA_VERSION,
+            records: records
+                .iter()
+                .map(serde_json::to_value)
+                .collect::<Result<_, _>>()?,
+        };
+        let path = self.dir.join("snapshot.json");
+        let tmp = path.with_extension("tmp");
+        let mut file = open_private(OpenOptions::new().create(true).write(true).truncate(true), &tmp)?;
+        file.write_all(&serde_json::to_vec(&snapshot)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        // The rename must be durable before the journal it replaces is emptied
+        sync_dir(&self.dir)?;
+
+        self.journal = open_private(
+            OpenOptions::new().create(true).write(true).truncate(true),
+            &self.dir.join("journal.jsonl"),
+        )?;
+        self.journal_records = 0;
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&Header {
+            schema_version: SCHEMA_VERSION,
+        })?;
+        line.push(b'\n');
+        self.journal.write_all(&line)?;
+        self.journal.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Open a file readable only by its owner. Files created by older kernels
+/// with a looser mode are tightened too.
+pub(crate) fn open_private(options: &mut OpenOptions, path: &Path) -> anyhow::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        let file = options.mode(0o600).open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(options.open(path)?)
+    }
+}
+
+/// Make renames and newly created files in `dir` durable
+fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Read the journal's version header and raw records. An empty journal has
+/// no header yet.
+///
+/// A crash can only tear the last line, so an unreadable or unterminated last
+/// line is cut off so that later appends start on a line of their own. An
+/// unreadable line anywhere else is an error.
+fn read_journal(path: &Path) -> anyhow::Result<Option<(u32, Vec<serde_json::Value>)>> {
+    let data = fs::read(path)?;
+    let mut version = None;
+    let mut records: Vec<serde_json::Value> = Vec::new();
+
+    let mut start = 0;
+    while start < data.len() {
+        let newline = data[start..].iter().position(|b| *b == b'\n');
+        let end = newline.map_or(data.len(), |len| start + len + 1);
+        let parsed = match newline {
+            Some(len) => {
+                let line = &data[start..start + len];
+                let parsed: Result<(), serde_json::Error> = if version.is_none() {
+                    serde_json::from_slice::<Header>(line).map(|header| version = Some(header.schema_version))
+                } else {
+                    serde_json::from_slice(line).map(|record| records.push(record))
+                };
+                parsed.map_err(anyhow::Error::from)
+            }
+            None => Err(anyhow::anyhow!("line has no ending")),
+        };
+
+        if let Err(e) = parsed {
+            if end < data.len() {
+                return Err(anyhow::anyhow!("Unreadable user record in {:?} at byte {}: {}", path, start, e));
+            }
+            warn!("Discarding {} bytes of a torn user record in {:?}: {}", end - start, path, e);
+            let file = OpenOptions::new().write(true).open(path)?;
+            file.set_len(start as u64)?;
+            file.sync_data()?;
+            break;
+        }
+        start = end;
+    }
+    Ok(version.map(|version| (version, records)))
+}
+
+/// Bring records written at `version` up to `current`
+fn upgrade(
+    raw: Vec<serde_json::Value>,
+    version: u32,
+    current: u32,
+    migrations: &[fn(&mut serde_json::Value)],
+) -> anyhow::Result<Vec<Record>> {
+    if version == 0 || version > current {
+        return Err(anyhow::anyhow!(
+            "User store schema version {} is not supported (current {})",
+            version,
+            current
+        ));
+    }
+
+    let pending = &migrations[(version - 1) as usize..];
+    raw.into_iter()
+        .map(|mut value| {
+            for migrate in pending {
+                migrate(&mut value);
+            }
+            Ok(serde_json::from_value(value)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            username: id.to_string(),
+            display_name: String::new(),
+            role: "guest".to_string(),
+            active: true,
+            created_at: 0,
+        }
+    }
+
+    fn user_ids(records: &[Record]) -> Vec<String> {
+        records
+            .iter()
+            .filter_map(|r| match r {
+                Record::PutUser { user } => Some(user.id.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_survive_reopen_and_compaction() {
+        let dir = TempDir::new("user-store");
+        {
+            let (mut store, records) = UserStore::open(&dir).unwrap();
+            assert!(records.is_empty());
+            store.append(&Record::PutUser { user: user("a") }).unwrap();
+            store.compact(vec![Record::PutUser { user: user("a") }]).unwrap();
+            store.append(&Record::PutUser { user: user("b") }).unwrap();
+        }
+
+        let (_, records) = UserStore::open(&dir).unwrap();
+        assert_eq!(user_ids(&records), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn torn_tail_is_cut_and_later_appends_survive() {
+        let dir = TempDir::new("user-store");
+        {
+            let (mut store, _) = UserStore::open(&dir).unwrap();
+            store.append(&Record::PutUser { user: user("a") }).unwrap();
+        }
+        let mut journal = OpenOptions::new().append(true).open(dir.join("journal.jsonl")).unwrap();
+        journal.write_all(br#"{"op":"put_us"#).unwrap();
+        drop(journal);
+
+        {
+            let (mut store, records) = UserStore::open(&dir).unwrap();
+            assert_eq!(user_ids(&records), vec!["a"]);
+            store.append(&Record::PutUser { user: user("b") }).unwrap();
+        }
+        let (_, records) = UserStore::open(&dir).unwrap();
+        assert_eq!(user_ids(&records), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn unreadable_records_before_the_tail_are_an_error() {
+        let dir = TempDir::new("user-store");
+        {
+            let (mut store, _) = UserStore::open(&dir).unwrap();
+            store.append(&Record::PutUser { user: user("a") }).unwrap();
+        }
+        let mut journal = OpenOptions::new().append(true).open(dir.join("journal.jsonl")).unwrap();
+        journal.write_all(b"garbage\n").unwrap();
+        journal.write_all(&serde_json::to_vec(&Record::PutUser { user: user("b") }).unwrap()).unwrap();
+        journal.write_all(b"\n").unwrap();
+        drop(journal);
+
+        assert!(UserStore::open(&dir).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn store_files_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("user-store");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("journal.jsonl"), b"").unwrap();
+        fs::set_permissions(dir.join("journal.jsonl"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let (mut store, _) = UserStore::open(&dir).unwrap();
+        store.compact(vec![Record::PutUser { user: user("a") }]).unwrap();
+        for name in ["snapshot.json", "journal.jsonl"] {
+            let mode = fs::metadata(dir.join(name)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600, "{}", name);
+        }
+    }
+
+    #[test]
+    fn old_records_are_migrated() {
+        // A hypothetical version 2 that renamed `name` to `username`
+        fn rename_name(value: &mut serde_json::Value) {
+            if let Some(user) = value.get_mut("user").and_then(|u| u.as_object_mut()) {
+                if let Some(name) = user.remove("name") {
+                    user.insert("username".to_string(), name);
+                }
+            }
+        }
+
+        let mut old = serde_json::to_value(Record::PutUser { user: user("a") }).unwrap();
+        let name = old["user"].as_object_mut().unwrap().remove("username").unwrap();
+        old["user"]["name"] = name;
+
+        assert!(upgrade(vec![old.clone()], 2, 2, &[rename_name]).is_err());
+        let records = upgrade(vec![old], 1, 2, &[rename_name]).unwrap();
+        assert_eq!(user_ids(&records), vec!["a"]);
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        assert!(upgrade(Vec::new(), SCHEMA_VERSION + 1, SCHEMA_VERSION, MIGRATIONS).is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::Result;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use wasmtime::{Engine, Module};
+
+/// Where and how much compiled code to keep
+#[derive(Clone, Debug)]
+pub struct CacheSettings {
+    /// Compiled modules held in memory; least recently used are dropped beyond this
+    pub capacity: usize,
+    /// Directory for serialized artifacts; `None` keeps the cache in memory only
+    pub dir: Option<PathBuf>,
+    /// Most bytes of artifacts kept on disk; least recently used are deleted
+    /// beyond this. `None` for no limit.
+    pub max_disk_bytes: Option<u64>,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            capacity: 64,
+            dir: None,
+            max_disk_bytes: Some(1024 * 1024 * 1024),
+        }
+    }
+}
+
+/// Counters reported through `GetResources`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+}
+
+struct Lru {
+    modules: HashMap<String, (Module, u64)>,
+    /// Last use -> content hash, oldest first
+    order: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) -> Option<Module> {
+        self.clock += 1;
+        let (module, used) = self.modules.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.clock;
+        self.order.insert(self.clock, key.to_string());
+        Some(module.clone())
+    }
+
+    /// Returns how many modules were evicted to make room
+    fn insert(&mut self, key: String, module: Module, capacity: usize) -> u64 {
+        self.clock += 1;
+        if let Some((_, used)) = self.modules.insert(key.clone(), (module, self.clock)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.clock, key);
+
+        let mut evicted = 0;
+        while self.modules.len() > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.modules.remove(&oldest);
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+/// Compiled modules keyed by the SHA-256 of their bytes.
+///
+/// Artifacts on disk live under a directory named after the engine's
+/// compatibility hash, so a wasmtime upgrade or engine config change starts
+/// from an empty cache and the stale directories are removed. Their
+/// modification time records when they were last used.
+pub struct ModuleCache {
+    engine: Engine,
+    capacity: usize,
+    dir: Option<PathBuf>,
+    max_disk_bytes: Option<u64>,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ModuleCache {
+    pub fn new(engine: Engine, settings: CacheSettings) -> Result<Self> {
+        let dir = match settings.dir {
+            Some(root) => Some(prepare_dir(&root, &engine_fingerprint(&engine))?),
+            None => None,
+        };
+        Ok(ModuleCache {
+            engine,
+            capacity: settings.capacity.max(1),
+            dir,
+            max_disk_bytes: settings.max_disk_bytes,
+            lru: Mutex::new(Lru {
+                modules: HashMap::new(),
+                order: BTreeMap::new(),
+                clock: 0,
+            }),
+            hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        })
+    }
+
+    /// Compiled module for `wasm_data`, from memory, disk or a fresh compile
+    pub fn get(&self, wasm_data: &[u8]) -> Result<Module> {
+        let key = hex::encode(Sha256::digest(wasm_data));
+        if let Some(module) = self.lru.lock().touch(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(module);
+        }
+
+        let module = match self.load(&key) {
+            Some(module) => {
+                self.disk_hits.fetch_add(1, Ordering::Relaxed);
+                module
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                let module = Module::new(&self.engine, wasm_data)?;
+                if let Err(e) = self.store(&key, &module) {
+                    tracing::warn!("Failed to persist compiled module {}: {}", key, e);
+                }
+                module
+            }
+        };
+
+        let evicted = self.lru.lock().insert(key, module.clone(), self.capacity);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        Ok(module)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: self.lru.lock().modules.len() as u64,
+        }
+    }
+
+    fn artifact(&self, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{}.cwasm", key)))
+    }
+
+    fn load(&self, key: &str) -> Option<Module> {
+        let path = self.artifact(key)?;
+        if !path.exists() {
+            return None;
+        }
+        // SAFETY: artifacts are only written by `store`, into a directory the
+        // kernel creates with owner-only permissions, and wasmtime rejects
+        // artifacts built by an incompatible engine.
+        match unsafe { Module::deserialize_file(&self.engine, &path) } {
+            Ok(module) => {
+                let touched = File::options()
+                    .append(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(SystemTime::now()));
+                if let Err(e) = touched {
+                    tracing::warn!("Failed to mark compiled module {} as used: {}", path.display(), e);
+                }
+                Some(module)
+            }
+            Err(e) => {
+                tracing::warn!("Discarding unreadable compiled module {}: {}", path.display(), e);
+                let _ = std::fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Write an artifact under a name of its own, so concurrent compiles of
+    /// the same module cannot interleave, and make it durable before it
+    /// becomes visible
+    fn store(&self, key: &str, module: &Module) -> Result<()> {
+        let (Some(dir), Some(path)) = (&self.dir, self.artifact(key)) else { return Ok(()) };
+        let bytes = module.serialize()?;
+        let tmp = dir.join(format!("{}.{}.tmp", key, uuid::Uuid::new_v4()));
+        let written = File::create(&tmp).and_then(|mut file| {
+            file.write_all(&bytes)?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|()| std::fs::rename(&tmp, &path)) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        self.enforce_disk_limit(dir)
+    }
+
+    /// Delete the least recently used artifacts while they take up more than
+    /// `max_disk_bytes`
+    fn enforce_disk_limit(&self, dir: &Path) -> Result<()> {
+        let Some(max) = self.max_disk_bytes else { return Ok(()) };
+        let mut artifacts = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.path().extension().map_or(true, |ext| ext != "cwasm") {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            artifacts.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+
+        let mut total: u64 = artifacts.iter().map(|(_, len, _)| len).sum();
+        artifacts.sort();
+        for (_, len, path) in artifacts {
+            if total <= max {
+                break;
+            }
+            match std::fs::remove_file(&path) {
+                Ok(()) => total -= len,
+                Err(e) => tracing::warn!("Failed to remove compiled module {}: {}", path.display(), e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stable identifier of everything that makes compiled code incompatible
+fn engine_fingerprint(engine: &Engine) -> String {
+    struct Sha256Hasher(Sha256);
+
+    impl Hasher for Sha256Hasher {
+        fn write(&mut self, bytes: &[u8]) {
+            self.0.update(bytes);
+        }
+
+        fn finish(&self) -> u64 {
+            unreachable!("only the digest is used")
+        }
+    }
+
+    let mut hasher = Sha256Hasher(Sha256::new());
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hex::encode(&hasher.0.finalize()[..8])
+}
+
+/// Create `<root>/<fingerprint>` and drop artifacts of other engine versions,
+/// along with temporary files a crash left behind
+fn prepare_dir(root: &Path, fingerprint: &str) -> Result<PathBuf> {
+    std::fs::create_dir_all(root)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(root, std::fs::Permissions::from_mode(0o700))?;
+    }
+    for entry in std::fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_name() != fingerprint && entry.file_type()?.is_dir() {
+            tracing::info!("Removing stale compiled modules in {}", entry.path().display());
+            std::fs::remove_dir_all(entry.path())?;
+        }
+    }
+    let dir = root.join(fingerprint);
+    std::fs::create_dir_all(&dir)?;
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "tmp") {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    fn module(n: i32) -> Vec<u8> {
+        format!(r#"(module (func (export "run") (result i32) (i32.const {})))"#, n).into_bytes()
+    }
+
+    #[test]
+    fn least_recently_used_module_is_evicted() {
+        let settings = CacheSettings {
+            capacity: 2,
+            ..Default::default()
+        };
+        let cache = ModuleCache::new(Engine::default(), settings).unwrap();
+        cache.get(&module(1)).unwrap();
+        cache.get(&module(2)).unwrap();
+        cache.get(&module(1)).unwrap();
+        cache.get(&module(3)).unwrap();
+        cache.get(&module(1)).unwrap();
+        cache.get(&module(2)).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions, stats.entries), (2, 4, 2, 2));
+    }
+
+    #[test]
+    fn artifacts_survive_a_restart() {
+        let root = TempDir::new("wasm-cache");
+        let settings = CacheSettings {
+            capacity: 4,
+            dir: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        std::fs::create_dir_all(root.join("stale-engine")).unwrap();
+
+        ModuleCache::new(Engine::default(), settings.clone()).unwrap().get(&module(7)).unwrap();
+        let cache = ModuleCache::new(Engine::default(), settings).unwrap();
+        cache.get(&module(7)).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.disk_hits, stats.misses), (1, 0));
+        assert!(!root.join("stale-engine").exists());
+    }
+
+    #[test]
+    fn least_recently_used_artifacts_are_deleted_beyond_the_disk_limit() {
+        let root = TempDir::new("wasm-cache");
+        let unlimited = CacheSettings {
+            dir: Some(root.to_path_buf()),
+            max_disk_bytes: None,
+            ..Default::default()
+        };
+        let cache = ModuleCache::new(Engine::default(), unlimited.clone()).unwrap();
+        cache.get(&module(1)).unwrap();
+        let first = cache.artifact(&hex::encode(Sha256::digest(module(1)))).unwrap();
+        let size = std::fs::metadata(&first).unwrap().len();
+        File::options()
+            .append(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+
+        let limited = CacheSettings {
+            max_disk_bytes: Some(size * 3 / 2),
+            ..unlimited
+        };
+        let cache = ModuleCache::new(Engine::default(), limited).unwrap();
+        cache.get(&module(2)).unwrap();
+        let second = cache.artifact(&hex::encode(Sha256::digest(module(2)))).unwrap();
+        assert!(!first.exists());
+        assert!(second.exists());
+    }
+}
@@ -1,30 +1,683 @@
-use wasmtime::{Engine, Instance, Linker, Module, Store};
-use wasmtime_wasi::{WasiCtx, WasiCtxBuilder};
+use wasmtime::{Config, Engine, Instance, Linker, Module, ResourceLimiter, Store, UpdateDeadline, Val, ValType};
+use wasmtime_wasi::sync::{add_to_linker, WasiCtxBuilder};
+use wasmtime_wasi::{I32Exit, WasiCtx};
+use wasi_common::pipe::WritePipe;
+use crate::wasm_cache::{CacheSettings, CacheStats, ModuleCache};
 use anyhow::Result;
+use parking_lot::{Condvar, Mutex};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Interval at which the engine epoch advances; guests check for suspension
+/// and their time limit on every tick.
+const EPOCH_TICK: Duration = Duration::from_millis(10);
+/// Most captured stdout or stderr returned per stream
+const MAX_CAPTURED_OUTPUT: usize = 1024 * 1024;
+/// Largest table a guest may grow, in elements
+const MAX_TABLE_ELEMENTS: u32 = 100_000;
+
+/// Named groups of WASI functions that `SandboxPolicy::forbidden` can refer to
+const OPERATIONS: &[(&str, &[&str])] = &[
+    ("args", &["wasi_snapshot_preview1::args_*"]),
+    ("environment", &["wasi_snapshot_preview1::environ_*"]),
+    ("clock", &["wasi_snapshot_preview1::clock_*"]),
+    ("random", &["wasi_snapshot_preview1::random_get"]),
+    (
+        "filesystem",
+        &[
+            "wasi_snapshot_preview1::path_*",
+            "wasi_snapshot_preview1::fd_readdir",
+            "wasi_snapshot_preview1::fd_prestat_*",
+        ],
+    ),
+    ("network", &["wasi_snapshot_preview1::sock_*"]),
+    ("process", &["wasi_snapshot_preview1::proc_raise", "wasi_snapshot_preview1::sched_yield"]),
+    ("poll", &["wasi_snapshot_preview1::poll_oneoff"]),
+];
+
+/// A guest ran into its sandbox
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("Guest exceeded its memory limit of {0} bytes")]
+    MemoryLimit(u64),
+    #[error("Guest exceeded its CPU time limit of {0:?}")]
+    CpuTimeLimit(Duration),
+    #[error("Host function {0} is not allowed in the sandbox")]
+    ForbiddenImport(String),
+}
+
+/// Limits every guest runs under.
+///
+/// Host imports (`module::name`) must match an `allowed_imports` pattern and
+/// none of the `forbidden` ones; a trailing `*` matches by prefix. `forbidden`
+/// also accepts the operation names in `OPERATIONS`, such as `network`.
+#[derive(Clone, Debug)]
+pub struct SandboxPolicy {
+    /// Linear memory per guest, in bytes
+    pub max_memory: u64,
+    /// Running time of a `RunWasm` call or a call into a registered module,
+    /// host calls included and time paused excluded; supervised modules are
+    /// not time-limited
+    pub max_cpu_time: Duration,
+    pub allowed_imports: Vec<String>,
+    pub forbidden: Vec<String>,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy {
+            max_memory: 256 * 1024 * 1024,
+            max_cpu_time: Duration::from_secs(30),
+            allowed_imports: vec!["wasi_snapshot_preview1::*".to_string()],
+            forbidden: vec!["network".to_string()],
+        }
+    }
+}
+
+impl SandboxPolicy {
+    /// Refuse the first import the policy does not allow
+    pub fn check_imports<'a>(&self, imports: impl IntoIterator<Item = &'a str>) -> Result<(), SandboxError> {
+        let forbidden: Vec<&str> = self
+            .forbidden
+            .iter()
+            .flat_map(|entry| match OPERATIONS.iter().find(|(name, _)| name == entry) {
+                Some((_, patterns)) => patterns.to_vec(),
+                None => vec![entry.as_str()],
+            })
+            .collect();
+
+        for import in imports {
+            let allowed = self.allowed_imports.iter().any(|p| pattern_matches(p, import));
+            if !allowed || forbidden.iter().any(|p| pattern_matches(p, import)) {
+                return Err(SandboxError::ForbiddenImport(import.to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Host imports a module needs, as `module::name`
+pub fn imports(module: &Module) -> Vec<String> {
+    module
+        .imports()
+        .map(|import| format!("{}::{}", import.module(), import.name()))
+        .collect()
+}
+
+fn pattern_matches(pattern: &str, import: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => import.starts_with(prefix),
+        None => pattern == import,
+    }
+}
+
+/// Store data of a running guest
+struct Guest {
+    wasi: WasiCtx,
+    max_memory: u64,
+    /// Longest the current call may run
+    max_cpu_time: Option<Duration>,
+    /// When the current call started, and how long it has been paused since
+    started: Instant,
+    paused: Duration,
+}
+
+impl Guest {
+    fn start_call(&mut self) {
+        self.started = Instant::now();
+        self.paused = Duration::ZERO;
+    }
+
+    /// Time the current call has run, including time blocked in host calls
+    fn running_time(&self) -> Duration {
+        self.started.elapsed().saturating_sub(self.paused)
+    }
+}
+
+impl ResourceLimiter for Guest {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> Result<bool> {
+        if desired as u64 > self.max_memory {
+            return Err(SandboxError::MemoryLimit(self.max_memory).into());
+        }
+        Ok(true)
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> Result<bool> {
+        Ok(desired <= MAX_TABLE_ELEMENTS)
+    }
+}
+
+/// Suspension switch for a running guest, checked on every epoch deadline.
+pub struct WasmControl {
+    paused: Mutex<bool>,
+    resumed: Condvar,
+}
+
+impl WasmControl {
+    pub fn new() -> Self {
+        WasmControl {
+            paused: Mutex::new(false),
+            resumed: Condvar::new(),
+        }
+    }
+
+    pub fn pause(&self) {
+        *self.paused.lock() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock() = false;
+        self.resumed.notify_all();
+    }
+
+    /// Block the executing thread for as long as the guest is paused, and
+    /// return how long that was
+    fn wait_while_paused(&self) -> Duration {
+        let mut paused = self.paused.lock();
+        let since = Instant::now();
+        while *paused {
+            self.resumed.wait(&mut paused);
+        }
+        since.elapsed()
+    }
+}
 
 pub struct WasmRuntime {
-    engine: Engine,
+    modules: ModuleCache,
+    policy: SandboxPolicy,
 }
 
 impl WasmRuntime {
-    pub fn new() -> Result<Self> {
-        let engine = Engine::default();
-        Ok(WasmRuntime { engine })
+    pub fn new(policy: SandboxPolicy, cache: CacheSettings) -> Result<Self> {
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+
+        let ticker = engine.clone();
+        std::thread::Builder::new()
+            .name("wasm-epoch".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(EPOCH_TICK);
+                ticker.increment_epoch();
+            })?;
+
+        Ok(WasmRuntime {
+            modules: ModuleCache::new(engine, cache)?,
+            policy,
+        })
     }
 
-    pub async fn execute(&self, wasm_data: &[u8], _args: Vec<String>) -> Result<String> {
-        let module = Module::new(&self.engine, wasm_data)?;
-        let mut store = Store::new(&self.engine, ());
+    pub fn policy(&self) -> &SandboxPolicy {
+        &self.policy
+    }
 
-        let linker = Linker::new(&self.engine);
-        let instance = linker.instantiate(&mut store, &module)?;
+    pub fn cache_stats(&self) -> CacheStats {
+        self.modules.stats()
+    }
 
-        // Call the "run" export if it exists
-        if let Ok(run) = instance.get_typed_func::<(), i32>(&mut store, "run") {
-            let result = run.call(&mut store, ())?;
-            return Ok(format!("WASM result: {}", result));
+    /// Compiled module for `wasm_data`, from the cache when possible. Compile
+    /// once per request and pass the module on, so the cache counts one use.
+    pub fn compile(&self, wasm_data: &[u8]) -> Result<Module> {
+        self.modules.get(wasm_data)
+    }
+
+    /// Instantiate a guest that keeps its state between calls.
+    ///
+    /// `_initialize` runs first when the guest exports it (a WASI reactor);
+    /// `_start` is never called. Output goes to the kernel's own stdout and
+    /// stderr, and `cpu_limit` applies to each call separately.
+    pub fn instantiate_persistent(
+        &self,
+        module: &Module,
+        invocation: WasmInvocation,
+        control: Arc<WasmControl>,
+    ) -> Result<WasmInstance> {
+        let (mut store, instance) = self.instantiate(module, &invocation, Some(control), None)?;
+        if let Ok(init) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            init.call(&mut store, ()).map_err(limit_error)?;
+        }
+        Ok(WasmInstance { store, instance })
+    }
+
+    /// Check a module against the sandbox policy and link it with WASI.
+    /// Output goes into `capture` when the invocation asks for it.
+    fn instantiate(
+        &self,
+        module: &Module,
+        invocation: &WasmInvocation,
+        control: Option<Arc<WasmControl>>,
+        capture: Option<(&OutputPipe, &OutputPipe)>,
+    ) -> Result<(Store<Guest>, Instance)> {
+        self.policy.check_imports(imports(module).iter().map(String::as_str))?;
+
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.arg(&invocation.program)?
+            .args(&invocation.args)?
+            .envs(&invocation.env)?;
+        match capture {
+            Some((stdout, stderr)) if invocation.capture_output => {
+                wasi.stdout(Box::new(stdout.clone()))
+                    .stderr(Box::new(stderr.clone()));
+            }
+            _ => {
+                wasi.inherit_stdout().inherit_stderr();
+            }
         }
 
-        Ok("WASM executed without explicit result".to_string())
+        // Running time is measured on the clock, so time spent blocked in host
+        // calls counts; it is checked on the next tick after such a call returns
+        let mut store = Store::new(
+            module.engine(),
+            Guest {
+                wasi: wasi.build(),
+                max_memory: self.policy.max_memory,
+                max_cpu_time: invocation.cpu_limit.then_some(self.policy.max_cpu_time),
+                started: Instant::now(),
+                paused: Duration::ZERO,
+            },
+        );
+        store.limiter(|guest| guest);
+
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(move |mut store| {
+            let guest = store.data_mut();
+            if let Some(max) = guest.max_cpu_time.filter(|max| guest.running_time() > *max) {
+                return Err(SandboxError::CpuTimeLimit(max).into());
+            }
+            if let Some(control) = &control {
+                guest.paused += control.wait_while_paused();
+            }
+            Ok(UpdateDeadline::Continue(1))
+        });
+
+        let mut linker: Linker<Guest> = Linker::new(module.engine());
+        add_to_linker(&mut linker, |guest| &mut guest.wasi)?;
+        let instance = linker.instantiate(&mut store, module).map_err(limit_error)?;
+        Ok((store, instance))
+    }
+
+    /// Run a guest on the blocking thread pool (see `execute_blocking`)
+    pub async fn execute(self: &Arc<Self>, module: Module, invocation: WasmInvocation) -> Result<WasmOutput> {
+        let runtime = self.clone();
+        tokio::task::spawn_blocking(move || runtime.execute_blocking(&module, invocation, None)).await?
+    }
+
+    /// Run a guest on the current thread. When `control` is set, the guest can be
+    /// suspended between epoch ticks, so callers must run this on a blocking thread.
+    ///
+    /// A WASI command's `_start` is preferred; otherwise an exported
+    /// `run: () -> i32` is called. Guests that break the sandbox policy fail
+    /// with a `SandboxError`.
+    pub fn execute_blocking(
+        &self,
+        module: &Module,
+        invocation: WasmInvocation,
+        control: Option<Arc<WasmControl>>,
+    ) -> Result<WasmOutput> {
+        let stdout = WritePipe::new(CappedBuffer::default());
+        let stderr = WritePipe::new(CappedBuffer::default());
+        let (mut store, instance) =
+            self.instantiate(module, &invocation, control, Some((&stdout, &stderr)))?;
+
+        let (result, exit_code) = if let Ok(start) = instance.get_typed_func::<(), ()>(&mut store, "_start") {
+            match start.call(&mut store, ()) {
+                Ok(()) => (String::new(), 0),
+                Err(e) => match e.downcast_ref::<I32Exit>() {
+                    Some(exit) => (String::new(), exit.0),
+                    None => return Err(limit_error(e)),
+                },
+            }
+        } else if let Ok(run) = instance.get_typed_func::<(), i32>(&mut store, "run") {
+            let result = run.call(&mut store, ()).map_err(limit_error)?;
+            (format!("WASM result: {}", result), 0)
+        } else {
+            ("WASM executed without explicit result".to_string(), 0)
+        };
+
+        // The pipes are shared with the store until it is gone
+        drop(store);
+        Ok(WasmOutput {
+            result,
+            exit_code,
+            stdout: captured(stdout),
+            stderr: captured(stderr),
+        })
+    }
+}
+
+/// Number passed to or returned from a guest export
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WasmValue {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl WasmValue {
+    fn to_val(self, ty: &ValType) -> Result<Val> {
+        match (self, ty) {
+            (WasmValue::I32(v), ValType::I32) => Ok(Val::I32(v)),
+            (WasmValue::I64(v), ValType::I64) => Ok(Val::I64(v)),
+            (WasmValue::F32(v), ValType::F32) => Ok(Val::F32(v.to_bits())),
+            (WasmValue::F64(v), ValType::F64) => Ok(Val::F64(v.to_bits())),
+            (value, ty) => Err(anyhow::anyhow!("Expected {}, got {:?}", ty, value)),
+        }
+    }
+
+    fn from_val(val: &Val) -> Result<Self> {
+        match val {
+            Val::I32(v) => Ok(WasmValue::I32(*v)),
+            Val::I64(v) => Ok(WasmValue::I64(*v)),
+            Val::F32(bits) => Ok(WasmValue::F32(f32::from_bits(*bits))),
+            Val::F64(bits) => Ok(WasmValue::F64(f64::from_bits(*bits))),
+            other => Err(anyhow::anyhow!("Unsupported result type {}", other.ty())),
+        }
+    }
+}
+
+/// A guest kept alive between calls, with its memory and globals intact.
+///
+/// Calls block the current thread and are subject to the `WasmControl` the
+/// instance was created with.
+pub struct WasmInstance {
+    store: Store<Guest>,
+    instance: Instance,
+}
+
+impl WasmInstance {
+    /// Call an export taking and returning numbers
+    pub fn call(&mut self, export: &str, args: &[WasmValue]) -> Result<Vec<WasmValue>> {
+        let func = self
+            .instance
+            .get_func(&mut self.store, export)
+            .ok_or_else(|| anyhow::anyhow!("Module has no exported function {}", export))?;
+        let ty = func.ty(&self.store);
+        if ty.params().len() != args.len() {
+            return Err(anyhow::anyhow!(
+                "{} takes {} arguments, got {}",
+                export,
+                ty.params().len(),
+                args.len()
+            ));
+        }
+        let params = args
+            .iter()
+            .zip(ty.params())
+            .enumerate()
+            .map(|(i, (arg, param))| {
+                arg.to_val(&param)
+                    .map_err(|e| anyhow::anyhow!("Argument {} of {}: {}", i + 1, export, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut results = vec![Val::I32(0); ty.results().len()];
+
+        self.store.data_mut().start_call();
+        func.call(&mut self.store, &params, &mut results).map_err(limit_error)?;
+        results.iter().map(WasmValue::from_val).collect()
+    }
+
+    /// Whether the guest can receive IPC messages through `handle_message`
+    pub fn handles_messages(&mut self) -> bool {
+        self.instance.get_func(&mut self.store, "kiacha_alloc").is_some()
+            && self.instance.get_func(&mut self.store, "kiacha_on_message").is_some()
+    }
+
+    /// Pass a message body to the guest and return its reply, if any.
+    ///
+    /// The body is copied into a buffer from `kiacha_alloc(len: i32) -> i32`,
+    /// then `kiacha_on_message(ptr: i32, len: i32) -> i64` is called. It returns
+    /// the reply's pointer in the high and its length in the low 32 bits, or 0
+    /// for no reply.
+    pub fn handle_message(&mut self, body: &[u8]) -> Result<Option<Vec<u8>>> {
+        let alloc = self
+            .instance
+            .get_typed_func::<i32, i32>(&mut self.store, "kiacha_alloc")?;
+        let on_message = self
+            .instance
+            .get_typed_func::<(i32, i32), i64>(&mut self.store, "kiacha_on_message")?;
+        let memory = self
+            .instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("Module does not export its memory"))?;
+
+        self.store.data_mut().start_call();
+        let len = i32::try_from(body.len())?;
+        let ptr = alloc.call(&mut self.store, len).map_err(limit_error)?;
+        memory.write(&mut self.store, ptr as u32 as usize, body)?;
+        let reply = on_message.call(&mut self.store, (ptr, len)).map_err(limit_error)?;
+        if reply == 0 {
+            return Ok(None);
+        }
+
+        let (ptr, len) = ((reply as u64 >> 32) as usize, (reply as u64 & 0xffff_ffff) as usize);
+        // The guest picks the length; only allocate what its memory can hold
+        if ptr.checked_add(len).map_or(true, |end| end > memory.data_size(&self.store)) {
+            return Err(anyhow::anyhow!(
+                "Reply of {} bytes at {} lies outside the module's memory",
+                len,
+                ptr
+            ));
+        }
+        let mut bytes = vec![0; len];
+        memory.read(&self.store, ptr, &mut bytes)?;
+        Ok(Some(bytes))
+    }
+}
+
+/// How to start a guest
+#[derive(Clone, Debug, Default)]
+pub struct WasmInvocation {
+    /// `argv[0]` as the guest sees it
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    /// Collect stdout and stderr into the `WasmOutput` instead of writing them
+    /// to the kernel's own
+    pub capture_output: bool,
+    /// Stop the guest once it has run for the policy's `max_cpu_time`
+    pub cpu_limit: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WasmOutput {
+    /// Value returned by `run`, when that was the entry point
+    pub result: String,
+    /// Code passed to `proc_exit`, or 0 when the guest returned normally
+    pub exit_code: i32,
+    /// Captured output, cut off at `MAX_CAPTURED_OUTPUT` bytes
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Report a sandbox limit as itself rather than behind the trap's backtrace
+fn limit_error(e: anyhow::Error) -> anyhow::Error {
+    match e.downcast::<SandboxError>() {
+        Ok(limit) => limit.into(),
+        Err(e) => e,
+    }
+}
+
+/// Captured guest output that keeps the first `MAX_CAPTURED_OUTPUT` bytes.
+/// Later writes still succeed, so the guest is not told its output is lost.
+#[derive(Default)]
+struct CappedBuffer(Vec<u8>);
+
+impl Write for CappedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let room = MAX_CAPTURED_OUTPUT.saturating_sub(self.0.len());
+        self.0.extend_from_slice(&buf[..buf.len().min(room)]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+type OutputPipe = WritePipe<CappedBuffer>;
+
+fn captured(pipe: OutputPipe) -> Vec<u8> {
+    pipe.try_into_inner().map(|buffer| buffer.0).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wasi_command_output_and_exit_code_are_captured() {
+        let wasm = br#"(module
+            (import "wasi_snapshot_preview1" "fd_write" (func $fd_write (param i32 i32 i32 i32) (result i32)))
+            (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 8) "hi\n")
+            (func (export "_start")
+                (i32.store (i32.const 0) (i32.const 8))
+                (i32.store (i32.const 4) (i32.const 3))
+                (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 20)))
+                (call $proc_exit (i32.const 3))))"#;
+
+        let runtime = WasmRuntime::new(SandboxPolicy::default(), CacheSettings::default()).unwrap();
+        let invocation = WasmInvocation {
+            program: "test".to_string(),
+            capture_output: true,
+            ..Default::default()
+        };
+        let module = runtime.compile(wasm).unwrap();
+        let output = runtime.execute_blocking(&module, invocation, None).unwrap();
+        assert_eq!(output.stdout, b"hi\n");
+        assert_eq!(output.exit_code, 3);
+    }
+
+    #[test]
+    fn captured_output_stops_at_the_cap() {
+        let mut buffer = CappedBuffer::default();
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..(MAX_CAPTURED_OUTPUT / chunk.len() + 4) {
+            assert_eq!(buffer.write(&chunk).unwrap(), chunk.len());
+        }
+        assert_eq!(buffer.0.len(), MAX_CAPTURED_OUTPUT);
+    }
+
+    #[test]
+    fn run_export_is_still_supported() {
+        let wasm = br#"(module (func (export "run") (result i32) (i32.const 42)))"#;
+        let runtime = WasmRuntime::new(SandboxPolicy::default(), CacheSettings::default()).unwrap();
+        let output = runtime
+            .execute_blocking(&runtime.compile(wasm).unwrap(), WasmInvocation::default(), None)
+            .unwrap();
+        assert_eq!((output.result.as_str(), output.exit_code), ("WASM result: 42", 0));
+    }
+
+    #[test]
+    fn memory_beyond_the_policy_is_refused() {
+        let wasm = br#"(module
+            (memory 1)
+            (func (export "run") (result i32) (memory.grow (i32.const 16))))"#;
+        let policy = SandboxPolicy {
+            max_memory: 4 * 65536,
+            ..Default::default()
+        };
+        let runtime = WasmRuntime::new(policy, CacheSettings::default()).unwrap();
+        let err = runtime
+            .execute_blocking(&runtime.compile(wasm).unwrap(), WasmInvocation::default(), None)
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SandboxError::MemoryLimit(_))));
+    }
+
+    fn time_limited(max_cpu_time: Duration) -> (WasmRuntime, WasmInvocation) {
+        let policy = SandboxPolicy {
+            max_cpu_time,
+            ..Default::default()
+        };
+        let invocation = WasmInvocation {
+            cpu_limit: true,
+            ..Default::default()
+        };
+        (WasmRuntime::new(policy, CacheSettings::default()).unwrap(), invocation)
+    }
+
+    #[test]
+    fn guests_are_stopped_at_the_cpu_time_limit() {
+        let wasm = br#"(module (func (export "run") (result i32) (loop $spin (br $spin)) (i32.const 0)))"#;
+        let (runtime, invocation) = time_limited(Duration::from_millis(100));
+        let started = Instant::now();
+        let module = runtime.compile(wasm).unwrap();
+        let err = runtime.execute_blocking(&module, invocation, None).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SandboxError::CpuTimeLimit(_))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn time_blocked_in_host_calls_counts() {
+        // Sleeps 300ms in poll_oneoff on the monotonic clock, then makes one call
+        let wasm = br#"(module
+            (import "wasi_snapshot_preview1" "poll_oneoff" (func $poll (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func $after)
+            (func (export "run") (result i32)
+                (i32.store (i32.const 16) (i32.const 1))
+                (i64.store (i32.const 24) (i64.const 300000000))
+                (drop (call $poll (i32.const 0) (i32.const 64) (i32.const 1) (i32.const 128)))
+                (call $after)
+                (i32.const 0)))"#;
+        let (runtime, invocation) = time_limited(Duration::from_millis(100));
+        let module = runtime.compile(wasm).unwrap();
+        let err = runtime.execute_blocking(&module, invocation, None).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(SandboxError::CpuTimeLimit(_))));
+    }
+
+    #[test]
+    fn forbidden_operations_reject_their_imports() {
+        let policy = SandboxPolicy {
+            forbidden: vec!["filesystem".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.check_imports(["wasi_snapshot_preview1::fd_write"]).is_ok());
+        assert!(policy.check_imports(["wasi_snapshot_preview1::path_open"]).is_err());
+        assert!(policy.check_imports(["env::host_exec"]).is_err());
+    }
+
+    #[test]
+    fn persistent_instances_keep_state_between_calls() {
+        let wasm = br#"(module
+            (memory (export "memory") 1)
+            (global $total (mut i64) (i64.const 0))
+            (func (export "add") (param i64) (result i64)
+                (global.set $total (i64.add (global.get $total) (local.get 0)))
+                (global.get $total))
+            (func (export "kiacha_alloc") (param i32) (result i32) (i32.const 64))
+            (func (export "kiacha_on_message") (param i32 i32) (result i64)
+                (i64.or (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32))
+                        (i64.extend_i32_u (local.get 1)))))"#;
+
+        let runtime = WasmRuntime::new(SandboxPolicy::default(), CacheSettings::default()).unwrap();
+        let module = runtime.compile(wasm).unwrap();
+        let mut instance = runtime
+            .instantiate_persistent(&module, WasmInvocation::default(), Arc::new(WasmControl::new()))
+            .unwrap();
+        instance.call("add", &[WasmValue::I64(2)]).unwrap();
+        assert_eq!(instance.call("add", &[WasmValue::I64(3)]).unwrap(), vec![WasmValue::I64(5)]);
+        assert!(instance.call("add", &[WasmValue::I32(1)]).is_err());
+
+        assert!(instance.handles_messages());
+        assert_eq!(instance.handle_message(b"ping").unwrap().as_deref(), Some(&b"ping"[..]));
+    }
+
+    #[test]
+    fn replies_outside_guest_memory_are_refused() {
+        let wasm = br#"(module
+            (memory (export "memory") 1)
+            (func (export "kiacha_alloc") (param i32) (result i32) (i32.const 64))
+            (func (export "kiacha_on_message") (param i32 i32) (result i64) (i64.const 0xffffffff)))"#;
+
+        let runtime = WasmRuntime::new(SandboxPolicy::default(), CacheSettings::default()).unwrap();
+        let module = runtime.compile(wasm).unwrap();
+        let mut instance = runtime
+            .instantiate_persistent(&module, WasmInvocation::default(), Arc::new(WasmControl::new()))
+            .unwrap();
+        assert!(instance.handle_message(b"ping").is_err());
     }
 }